@@ -0,0 +1,33 @@
+// Compares the vm with and without its optimizations on the classic Lox benchmarks, which spend
+// most of their time calling functions and methods and accessing fields:
+//
+//   cargo bench --bench optimize
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_lox::{Backend, Lox};
+
+// the programs leave their result in a global rather than printing it
+const PROGRAMS: [(&str, &str); 4] = [
+    ("fib", include_str!("lox/fib.lox")),
+    ("binary_trees", include_str!("lox/binary_trees.lox")),
+    ("method_call", include_str!("lox/method_call.lox")),
+    ("zoo", include_str!("lox/zoo.lox")),
+];
+
+fn optimize(c: &mut Criterion) {
+    for (name, source) in PROGRAMS {
+        let mut group = c.benchmark_group(format!("optimize/{}", name));
+        for (id, optimize) in [("unoptimized", false), ("optimized", true)] {
+            group.bench_function(id, |b| {
+                b.iter(|| {
+                    let mut lox = Lox::with_backend(Backend::Vm);
+                    lox.set_optimize(optimize);
+                    assert!(lox.run(black_box(source)).is_ok());
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, optimize);
+criterion_main!(benches);
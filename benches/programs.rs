@@ -0,0 +1,69 @@
+// Times the stages of running the standard Lox benchmark programs, so a change that slows the
+// interpreter down shows up. Save a baseline before the change and compare against it after:
+//
+//   cargo bench --bench programs -- --save-baseline before
+//   cargo bench --bench programs -- --baseline before
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rust_lox::{Backend, Lox, Parser, Scanner};
+
+// the programs leave their result in a global rather than printing it
+const PROGRAMS: [(&str, &str); 6] = [
+    ("fib", include_str!("lox/fib.lox")),
+    ("binary_trees", include_str!("lox/binary_trees.lox")),
+    ("equality", include_str!("lox/equality.lox")),
+    ("instantiation", include_str!("lox/instantiation.lox")),
+    ("string_equality", include_str!("lox/string_equality.lox")),
+    ("trees", include_str!("lox/trees.lox")),
+];
+
+fn scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    for (name, source) in PROGRAMS {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut scanner = Scanner::new(black_box(source));
+                scanner.scan_tokens();
+                scanner
+            })
+        });
+    }
+    group.finish();
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, source) in PROGRAMS {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let tokens = scanner.tokens().to_vec();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || tokens.clone(),
+                |tokens| Parser::new(tokens).parse().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// run times Lox::run from source to result on both backends
+fn run(c: &mut Criterion) {
+    for (name, source) in PROGRAMS {
+        let mut group = c.benchmark_group(format!("run/{}", name));
+        // the slower programs take long enough that fewer samples still give stable numbers
+        group.sample_size(20);
+        for (id, backend) in [("tree-walker", Backend::TreeWalker), ("vm", Backend::Vm)] {
+            group.bench_function(id, |b| {
+                b.iter(|| {
+                    let mut lox = Lox::with_backend(backend);
+                    assert!(lox.run(black_box(source)).is_ok());
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, scan, parse, run);
+criterion_main!(benches);
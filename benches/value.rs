@@ -0,0 +1,54 @@
+// Compares the two representations of Value. Run it once without and once with NaN-boxing, and
+// criterion reports the difference:
+//
+//   cargo bench --bench value -- --save-baseline enum
+//   cargo bench --bench value --features nan-boxing -- --baseline enum
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_lox::{Backend, Lox, Value};
+
+fn values(c: &mut Criterion) {
+    let values: Vec<Value> = (0..4096)
+        .map(|i| match i % 4 {
+            0 => Value::NIL,
+            1 => Value::from(i % 3 == 0),
+            _ => Value::from(i as f64),
+        })
+        .collect();
+
+    c.bench_function("value/sum_numbers", |b| {
+        b.iter(|| {
+            let values = black_box(&values);
+            values
+                .iter()
+                .filter_map(|value| value.as_number())
+                .sum::<f64>()
+        })
+    });
+    c.bench_function("value/count_truthy", |b| {
+        b.iter(|| {
+            let values = black_box(&values);
+            values.iter().filter(|value| value.is_truthy()).count()
+        })
+    });
+    c.bench_function("value/equal_neighbours", |b| {
+        b.iter(|| {
+            let values = black_box(&values);
+            values.windows(2).filter(|pair| pair[0] == pair[1]).count()
+        })
+    });
+    c.bench_function("value/copy", |b| b.iter(|| black_box(&values).clone()));
+}
+
+// vm runs arithmetic on the vm, where values are pushed, popped and type checked all the time
+fn vm(c: &mut Criterion) {
+    let source = "(1 + 2) * 3 - 4 / 5 < 6 == !nil;\n".repeat(1000);
+    c.bench_function("value/vm_arithmetic", |b| {
+        b.iter(|| {
+            let mut lox = Lox::with_backend(Backend::Vm);
+            assert!(lox.run(black_box(&source)).is_ok());
+        })
+    });
+}
+
+criterion_group!(benches, values, vm);
+criterion_main!(benches);
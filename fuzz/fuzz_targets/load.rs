@@ -0,0 +1,36 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_lox::Lox;
+
+const STEP_LIMIT: usize = 100_000;
+
+// The input is the body of a .loxc file, after a byte whose lowest bit says whether the file
+// has line tables. It gets a valid header and checksum, so the fuzzer gets past those to the
+// code the loader validates, and whatever passes runs on the vm.
+fuzz_target!(|data: &[u8]| {
+    let Some((flags, body)) = data.split_first() else {
+        return;
+    };
+    let mut lox = Lox::new();
+    lox.set_step_limit(Some(STEP_LIMIT));
+    // the magic number and the format version
+    let mut file = lox.compile("", false).unwrap()[..6].to_vec();
+    file.extend_from_slice(&u16::from(flags & 1).to_le_bytes());
+    file.extend_from_slice(&crc32(body).to_le_bytes());
+    file.extend_from_slice(body);
+    let _ = lox.run_compiled(&file);
+});
+
+// crc32 is the CRC-32 the loader checks, the one used by zip and png
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_lox::{Parser, Scanner};
+
+fuzz_target!(|source: &str| {
+    let mut scanner = Scanner::new(source);
+    scanner.scan_tokens();
+    let mut parser = Parser::new(scanner.tokens().to_vec());
+    let _ = parser.parse();
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_lox::{Backend, Lox};
+
+// generous enough for any sensible program in the corpus, but keeps endless loops from
+// being reported as timeouts
+const STEP_LIMIT: usize = 100_000;
+
+// every program runs on both backends, after the optimizer has been over it like it is by default
+fuzz_target!(|source: &str| {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::with_backend(backend);
+        lox.set_step_limit(Some(STEP_LIMIT));
+        let _ = lox.run(source);
+    }
+});
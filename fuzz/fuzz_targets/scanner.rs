@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_lox::Scanner;
+
+fuzz_target!(|source: &str| {
+    let mut scanner = Scanner::new(source);
+    scanner.scan_tokens();
+});
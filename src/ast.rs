@@ -1,11 +1,25 @@
 use crate::token::Token;
-use crate::tokentype;
-use crate::tokentype::{Literal, TokenType};
-use std::fmt::{write, Display, Formatter};
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// ExprId identifies an expression that refers to a variable, or to this or super, so the interpreter can remember
+// where the resolver found the variable. Ids are unique across parses, since functions declared
+// on one line of the REPL may run on the next.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ExprId(u64);
+
+impl ExprId {
+    pub(crate) fn next() -> ExprId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ExprId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
 #[derive(Debug)]
-pub(crate) enum Expression {
+pub enum Expression {
     Assign {
+        id: ExprId,
         name: Token,
         expr: Box<Expression>,
     },
@@ -40,10 +54,12 @@ pub(crate) enum Expression {
         value: Box<Expression>,
     },
     Super {
+        id: ExprId,
         keyword: Token,
         method: Token,
     },
     This {
+        id: ExprId,
         keyword: Token,
     },
     Unary {
@@ -51,30 +67,245 @@ pub(crate) enum Expression {
         right: Box<Expression>,
     },
     Variable {
+        id: ExprId,
+        name: Token,
+    },
+}
+
+impl Expression {
+    // height is how deep the expression nests, counting the operands chained to it, which is how
+    // deep dropping it recurses
+    pub(crate) fn height(&self) -> usize {
+        let children = match self {
+            Expression::Assign { expr, .. }
+            | Expression::Get { expr, .. }
+            | Expression::Grouping { expr }
+            | Expression::Unary { right: expr, .. } => expr.height(),
+            Expression::Binary { left, right, .. }
+            | Expression::Logical { left, right, .. }
+            | Expression::Set {
+                object: left,
+                value: right,
+                ..
+            } => left.height().max(right.height()),
+            Expression::Call {
+                callee, arguments, ..
+            } => arguments
+                .iter()
+                .map(Expression::height)
+                .fold(callee.height(), usize::max),
+            Expression::Literal { .. }
+            | Expression::Super { .. }
+            | Expression::This { .. }
+            | Expression::Variable { .. } => 0,
+        };
+        children + 1
+    }
+
+    // left is the operand a chain like `1 + 2 + 3` or `a.b().c` builds on: the left operand of a
+    // binary or logical operator, the callee of a call or the object of a property access. Chains
+    // are as long as programs make them, so the passes after the parser walk them with a loop
+    // instead of recursing into every left operand.
+    pub(crate) fn left(&self) -> Option<&Expression> {
+        match self {
+            Expression::Binary { left, .. } | Expression::Logical { left, .. } => Some(left),
+            Expression::Call { callee, .. } => Some(callee),
+            Expression::Get { expr, .. } => Some(expr),
+            _ => None,
+        }
+    }
+
+    // chain gives the innermost operand of the chain the expression ends, which is not part of a
+    // chain itself, and the links built on it from the inside out, ending with the expression
+    pub(crate) fn chain(&self) -> (&Expression, Vec<&Expression>) {
+        let mut links = Vec::new();
+        let mut innermost = self;
+        while let Some(left) = innermost.left() {
+            links.push(innermost);
+            innermost = left;
+        }
+        links.reverse();
+        (innermost, links)
+    }
+
+    // token is the token runtime errors of the expression point at
+    pub(crate) fn token(&self) -> &Token {
+        match self {
+            Expression::Grouping { expr } => expr.token(),
+            Expression::Assign { name, .. }
+            | Expression::Get { name, .. }
+            | Expression::Set { name, .. }
+            | Expression::Variable { name, .. } => name,
+            Expression::Binary { operator, .. }
+            | Expression::Logical { operator, .. }
+            | Expression::Unary { operator, .. } => operator,
+            Expression::Call { paren, .. } => paren,
+            Expression::Literal { value } => value,
+            Expression::Super { keyword, .. } | Expression::This { keyword, .. } => keyword,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Statement {
+    Block {
+        statements: Vec<Statement>,
+    },
+    Class {
+        name: Token,
+        // the variable naming the superclass
+        superclass: Option<Expression>,
+        methods: Vec<Rc<Function>>,
+    },
+    Expression {
+        expr: Expression,
+    },
+    Function(Rc<Function>),
+    If {
+        condition: Expression,
+        then_branch: Box<Statement>,
+        else_branch: Option<Box<Statement>>,
+    },
+    Print {
+        expr: Expression,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expression>,
+    },
+    Var {
         name: Token,
+        initializer: Option<Expression>,
+    },
+    While {
+        condition: Expression,
+        body: Box<Statement>,
     },
 }
 
+// Function is a function declaration. It is shared, since the functions the tree-walker creates
+// from it outlive the program they were declared in.
+#[derive(Debug)]
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Statement>,
+}
+
 impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // a chain is written from the outside in up to its innermost operand, and then what every
+        // link adds after its left operand from the inside out
+        let (innermost, links) = self.chain();
+        for link in links.iter().rev() {
+            match link {
+                Expression::Binary { operator, .. } => write!(f, "({} ", operator)?,
+                Expression::Logical { operator, .. } => write!(f, "({} ", operator.lexeme)?,
+                Expression::Call { .. } => write!(f, "(call ")?,
+                Expression::Get { .. } => write!(f, "(. ")?,
+                _ => unreachable!("chains only hold links"),
+            }
+        }
+        match innermost {
+            Expression::Assign { name, expr, .. } => write!(f, "(= {} {})", name.lexeme, expr)?,
+            Expression::Grouping { expr } => write!(f, "(group {})", expr)?,
+            Expression::Literal { value } => write!(f, "{}", value)?,
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "(= (. {} {}) {})", object, name.lexeme, value)?,
+            Expression::Super { method, .. } => write!(f, "(super {})", method.lexeme)?,
+            Expression::This { .. } => write!(f, "this")?,
+            Expression::Unary { operator, right } => write!(f, "({}, {})", operator.lexeme, right)?,
+            Expression::Variable { name, .. } => write!(f, "{}", name.lexeme)?,
+            Expression::Binary { .. }
+            | Expression::Call { .. }
+            | Expression::Get { .. }
+            | Expression::Logical { .. } => unreachable!("the innermost operand is never a link"),
+        }
+        for link in links {
+            match link {
+                Expression::Binary { right, .. } | Expression::Logical { right, .. } => {
+                    write!(f, " {})", right)?
+                }
+                Expression::Call { arguments, .. } => {
+                    for argument in arguments {
+                        write!(f, " {}", argument)?;
+                    }
+                    write!(f, ")")?
+                }
+                Expression::Get { name, .. } => write!(f, " {})", name.lexeme)?,
+                _ => unreachable!("chains only hold links"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Statement {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expression::Assign { name, expr } => todo!(),
-            Expression::Binary {
-                left,
-                operator,
-                right,
-            } => write!(f, "({} {} {})", operator, left, right),
-            Expression::Call { .. } => todo!(),
-            Expression::Get { .. } => todo!(),
-            Expression::Grouping { expr } => write!(f, "(group {})", expr),
-            Expression::Literal { value } => write!(f, "{}", value.literal.clone().unwrap()),
-            Expression::Logical { .. } => todo!(),
-            Expression::Set { .. } => todo!(),
-            Expression::Super { .. } => todo!(),
-            Expression::This { .. } => todo!(),
-            Expression::Unary { operator, right } => write!(f, "({}, {})", operator.lexeme, right),
-            Expression::Variable { .. } => todo!(),
+            Statement::Block { statements } => {
+                write!(f, "(block")?;
+                for statement in statements {
+                    write!(f, " {}", statement)?;
+                }
+                write!(f, ")")
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                write!(f, "(class {}", name.lexeme)?;
+                if let Some(superclass) = superclass {
+                    write!(f, " < {}", superclass)?;
+                }
+                for method in methods {
+                    write!(f, " {}", method)?;
+                }
+                write!(f, ")")
+            }
+            Statement::Expression { expr } => write!(f, "(; {})", expr),
+            Statement::Function(function) => write!(f, "{}", function),
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                write!(f, "(if {} {}", condition, then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    write!(f, " {}", else_branch)?;
+                }
+                write!(f, ")")
+            }
+            Statement::Print { expr } => write!(f, "(print {})", expr),
+            Statement::Return { value: None, .. } => write!(f, "(return)"),
+            Statement::Return {
+                value: Some(value), ..
+            } => write!(f, "(return {})", value),
+            Statement::Var {
+                name,
+                initializer: None,
+            } => write!(f, "(var {})", name.lexeme),
+            Statement::Var {
+                name,
+                initializer: Some(initializer),
+            } => write!(f, "(var {} {})", name.lexeme, initializer),
+            Statement::While { condition, body } => write!(f, "(while {} {})", condition, body),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let params: Vec<&str> = self.params.iter().map(|p| &*p.lexeme).collect();
+        write!(f, "(fun {} ({})", self.name.lexeme, params.join(" "))?;
+        for statement in &self.body {
+            write!(f, " {}", statement)?;
         }
+        write!(f, ")")
     }
 }
 
@@ -86,6 +317,8 @@ impl Visitor<String> for Expression {
     }
 }
 
+// the visitor is only exercised by the tests for now
+#[allow(dead_code)]
 pub(crate) trait Visitor<T> {
     fn visit(&self) -> T;
 }
@@ -93,38 +326,24 @@ pub(crate) trait Visitor<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tokentype::{Literal, TokenType};
 
     #[test]
     fn test_visitor_string() {
         let test = Expression::Binary {
             left: Box::new(Expression::Binary {
                 left: Box::new(Expression::Literal {
-                    value: Token::new(
-                        TokenType::Number,
-                        "123".to_string(),
-                        Some(Literal::Number(123 as f64)),
-                        0,
-                    ),
+                    value: Token::new(TokenType::Number, "123", Some(Literal::Number(123.0)), 0, 0),
                 }),
-                operator: Token::new(TokenType::Plus, "+".to_string(), None, 0),
+                operator: Token::new(TokenType::Plus, "+", None, 0, 0),
                 right: Box::new(Expression::Literal {
-                    value: Token::new(
-                        TokenType::Number,
-                        "321".to_string(),
-                        Some(Literal::Number(321 as f64)),
-                        0,
-                    ),
+                    value: Token::new(TokenType::Number, "321", Some(Literal::Number(321.0)), 0, 0),
                 }),
             }),
-            operator: Token::new(TokenType::Star, "*".to_string(), None, 0),
+            operator: Token::new(TokenType::Star, "*", None, 0, 0),
             right: Box::new(Expression::Grouping {
                 expr: Box::new(Expression::Literal {
-                    value: Token::new(
-                        TokenType::Number,
-                        "234".to_string(),
-                        Some(Literal::Number(234 as f64)),
-                        0,
-                    ),
+                    value: Token::new(TokenType::Number, "234", Some(Literal::Number(234.0)), 0, 0),
                 }),
             }),
         };
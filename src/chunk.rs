@@ -0,0 +1,235 @@
+use crate::value::Value;
+
+// the largest constant index that fits in the three byte operand of ConstantLong
+pub(crate) const MAX_CONSTANTS: usize = 1 << 24;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum OpCode {
+    // Constant is followed by a one byte index into the constant pool, ConstantLong by a three
+    // byte little endian index for chunks with more than 256 constants
+    Constant,
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Pop,
+    // the local variable and upvalue instructions are followed by a one byte slot, the global
+    // ones by the two byte index of the constant holding the name of the variable
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    // the property instructions are followed by the two byte index of the constant holding the
+    // name of the property, then the two byte index of their inline cache. GetSuper has no cache,
+    // as the method it finds never changes.
+    GetProperty,
+    SetProperty,
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    // the jumps are followed by a two byte distance from the end of the instruction, forwards
+    // for Jump, JumpIfFalse and PopJumpIfFalse and backwards for Loop. PopJumpIfFalse pops the
+    // condition whether it jumps or not.
+    Jump,
+    JumpIfFalse,
+    PopJumpIfFalse,
+    Loop,
+    // Call is followed by the number of arguments. Invoke calls a method on an instance without
+    // binding it first and is followed by the name of the method, the number of arguments and
+    // the inline cache; SuperInvoke calls a method of the superclass and has no cache.
+    Call,
+    Invoke,
+    SuperInvoke,
+    // Closure is followed by the two byte index of the function constant, then for each of its
+    // upvalues a byte that is 1 when it captures a local of the enclosing function rather than
+    // one of its upvalues, and the slot of that local or upvalue
+    Closure,
+    CloseUpvalue,
+    Return,
+    // Class and Method are followed by the two byte index of the constant holding the name of
+    // the class or method
+    Class,
+    Inherit,
+    Method,
+    // superinstructions, which do the work of two common instructions at once: GetLocalProperty
+    // is GetLocal followed by GetProperty and has both their operands, AddLocal is GetLocal
+    // followed by Add
+    GetLocalProperty,
+    AddLocal,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 43] = [
+        OpCode::Constant,
+        OpCode::ConstantLong,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::PopJumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::GetLocalProperty,
+        OpCode::AddLocal,
+    ];
+
+    pub(crate) fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+
+    // operand_width is the number of bytes of operands following the opcode. Closure has two more
+    // for every upvalue of its function.
+    pub(crate) fn operand_width(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::AddLocal => 1,
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::PopJumpIfFalse
+            | OpCode::Loop
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Method => 2,
+            OpCode::ConstantLong | OpCode::SuperInvoke => 3,
+            OpCode::GetProperty | OpCode::SetProperty => 4,
+            OpCode::Invoke | OpCode::GetLocalProperty => 5,
+            _ => 0,
+        }
+    }
+}
+
+// Chunk is a compiled sequence of instructions together with the constants they refer to
+#[derive(Clone, PartialEq, Debug, Default)]
+pub(crate) struct Chunk {
+    pub(crate) code: Vec<u8>,
+    pub(crate) constants: Vec<Value>,
+    // the source lines of the code, run length encoded as (line, number of bytes) since
+    // consecutive instructions mostly come from the same line
+    pub(crate) lines: Vec<(usize, usize)>,
+}
+
+impl Chunk {
+    pub(crate) fn new() -> Chunk {
+        Chunk::default()
+    }
+
+    pub(crate) fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+        match self.lines.last_mut() {
+            Some((last, count)) if *last == line => *count += 1,
+            _ => self.lines.push((line, 1)),
+        }
+    }
+
+    pub(crate) fn write_op(&mut self, op: OpCode, line: usize) {
+        self.write(op as u8, line);
+    }
+
+    // add_constant adds value to the constant pool and returns its index, or None when the pool
+    // is full
+    pub(crate) fn add_constant(&mut self, value: Value) -> Option<usize> {
+        if self.constants.len() >= MAX_CONSTANTS {
+            return None;
+        }
+        self.constants.push(value);
+        Some(self.constants.len() - 1)
+    }
+
+    // read_u16 returns the little endian two byte operand at offset
+    pub(crate) fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    // line returns the source line of the byte at offset
+    pub(crate) fn line(&self, offset: usize) -> usize {
+        let mut start = 0;
+        for (line, count) in &self.lines {
+            start += count;
+            if offset < start {
+                return *line;
+            }
+        }
+        self.lines.last().map_or(0, |(line, _)| *line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip_through_bytes() {
+        for op in OpCode::ALL {
+            assert_eq!(OpCode::from_byte(op as u8), Some(op));
+        }
+        assert_eq!(OpCode::from_byte(OpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn chunk_tracks_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Nil, 1);
+        chunk.write_op(OpCode::Pop, 1);
+        chunk.write_op(OpCode::True, 3);
+        chunk.write_op(OpCode::Return, 4);
+
+        assert_eq!(chunk.lines.len(), 3);
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|i| chunk.line(i)).collect();
+        assert_eq!(lines, [1, 1, 3, 4]);
+    }
+}
@@ -0,0 +1,1203 @@
+use crate::ast::{self, Expression, Statement};
+use crate::chunk::{Chunk, OpCode};
+use crate::heap::{Heap, ObjRef};
+use crate::object::{Function, Object};
+use crate::symbol::Symbol;
+use crate::token::Token;
+use crate::tokentype::{Literal, TokenType};
+use crate::value::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// locals and upvalues are addressed by a byte, which limits how many a function can have. The
+// first local slot holds the function being called, or this in a method.
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+// inline caches are addressed by two bytes
+const MAX_CACHES: usize = 1 << 16;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CompileError {
+    pub(crate) token: Token,
+    pub(crate) message: String,
+}
+
+impl CompileError {
+    pub(crate) fn new(token: &Token, message: &str) -> CompileError {
+        CompileError {
+            token: token.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+// ClassState is what the compiler tracks for a class whose methods it is compiling
+struct ClassState {
+    has_superclass: bool,
+}
+
+struct Local {
+    name: Symbol,
+    // the number of blocks the local is declared in
+    depth: usize,
+    // false until the initializer of the local has been compiled
+    initialized: bool,
+    // whether a closure captures the local, which moves it off the stack when it goes out of scope
+    captured: bool,
+}
+
+// UpvalueRef says where a closure finds a variable it captures when it is created: in a local
+// slot of the enclosing function, or in one of the upvalues of the enclosing function
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+// Access is how a variable is read and written
+#[derive(Clone, Copy)]
+enum Access {
+    Local(u8),
+    Upvalue(u8),
+    Global(u16),
+}
+
+// FunctionState is what the compiler tracks for a function it is in the middle of compiling
+struct FunctionState {
+    kind: FunctionKind,
+    name: Option<Rc<str>>,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    // the constants holding the names of globals and properties, so every name is added to the
+    // pool once
+    names: HashMap<Symbol, u16>,
+    // the number of inline caches the code refers to
+    caches: usize,
+    // where the last instruction starts, and the last place a jump lands, which superinstructions
+    // need to know
+    last_instruction: usize,
+    jump_target: Option<usize>,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: Option<Rc<str>>) -> FunctionState {
+        // the first slot holds the callee, which methods call this and other functions can't name
+        let callee = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => Symbol::THIS,
+            FunctionKind::Script | FunctionKind::Function => Symbol::NONE,
+        };
+        let callee = Local {
+            name: callee,
+            depth: 0,
+            initialized: true,
+            captured: false,
+        };
+        FunctionState {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![callee],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            names: HashMap::new(),
+            caches: 0,
+            last_instruction: 0,
+            jump_target: None,
+        }
+    }
+}
+
+// Compiler turns the tree produced by the parser into bytecode for the vm, in one function for the
+// top level code of the script and one for every function declared in it. Like the functions,
+// string constants are allocated on the heap of the vm that runs the script.
+//
+// Local variables live on the stack of the vm, in the slots of the function that declares them.
+// A function that refers to a local of a function around it captures it as an upvalue instead.
+//
+// With superinstructions set, common sequences of instructions are emitted as a single one that
+// does the work of all of them.
+pub(crate) struct Compiler<'a> {
+    heap: &'a mut Heap,
+    superinstructions: bool,
+    // the functions being compiled, innermost last. The first one is the script.
+    functions: Vec<FunctionState>,
+    // the classes being compiled, innermost last
+    classes: Vec<ClassState>,
+    errors: Vec<CompileError>,
+    // the token we are compiling, instructions are attributed to its line
+    token: Token,
+}
+
+impl Compiler<'_> {
+    // compile returns the function running the top level code of a program, or every error in it
+    pub(crate) fn compile(
+        statements: &[Statement],
+        heap: &mut Heap,
+        superinstructions: bool,
+    ) -> Result<ObjRef, Vec<CompileError>> {
+        let mut compiler = Compiler {
+            heap,
+            superinstructions,
+            functions: vec![FunctionState::new(FunctionKind::Script, None)],
+            classes: Vec::new(),
+            errors: Vec::new(),
+            token: Token::new(TokenType::Eof, "", None, 1, 0),
+        };
+        // the script returns the value of its last statement if that is an expression statement
+        let (last, statements) = match statements.split_last() {
+            Some((Statement::Expression { expr }, rest)) => (Some(expr), rest),
+            _ => (None, statements),
+        };
+        for statement in statements {
+            compiler.statement(statement);
+        }
+        if let Some(expr) = last {
+            compiler.expression(expr);
+            compiler.emit(OpCode::Return);
+        }
+        let (script, _) = compiler.end_function();
+        if compiler.errors.is_empty() {
+            Ok(script)
+        } else {
+            Err(compiler.errors)
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Block { statements } => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            Statement::Expression { expr } => {
+                self.expression(expr);
+                self.emit(OpCode::Pop);
+            }
+            Statement::Function(function) => {
+                self.at(&function.name);
+                let global = self.declare_variable(&function.name);
+                // functions may refer to themselves, so they are initialized right away
+                self.mark_initialized();
+                self.function(function, FunctionKind::Function);
+                self.define_variable(global);
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_condition_jump();
+                self.statement(then_branch);
+                if else_branch.is_none() && self.superinstructions {
+                    // the condition is popped already, so there is nothing to jump over
+                    self.patch_jump(then_jump);
+                    return;
+                }
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.pop_condition();
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Statement::Print { expr } => {
+                self.expression(expr);
+                self.emit(OpCode::Print);
+            }
+            Statement::Return { keyword, value } => {
+                self.at(keyword);
+                let kind = self.current().kind;
+                if kind == FunctionKind::Script {
+                    self.error("Can't return from top-level code.");
+                }
+                match value {
+                    Some(value) => {
+                        if kind == FunctionKind::Initializer {
+                            self.error("Can't return a value from an initializer.");
+                        }
+                        self.expression(value);
+                        self.emit(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Statement::Var { name, initializer } => {
+                self.at(name);
+                let global = self.declare_variable(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(OpCode::Nil),
+                }
+                self.at(name);
+                self.define_variable(global);
+            }
+            Statement::While { condition, body } => {
+                let loop_start = self.current().chunk.code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_condition_jump();
+                self.statement(body);
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.pop_condition();
+            }
+        }
+    }
+
+    // class compiles a class declaration. The class is created without methods, and then gets the
+    // methods of its superclass and its own one by one. Methods of a class with a superclass are
+    // declared in a scope with a local holding the superclass, which they capture to find super.
+    fn class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expression>,
+        methods: &[Rc<ast::Function>],
+    ) {
+        self.at(name);
+        let name_constant = self.identifier_constant(name);
+        let global = self.declare_variable(name);
+        self.emit(OpCode::Class);
+        self.emit_u16(name_constant);
+        self.define_variable(global);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        // the parser only lets a name through as the superclass
+        if let Some(Expression::Variable {
+            name: superclass, ..
+        }) = superclass
+        {
+            if superclass.symbol == name.symbol {
+                self.at(superclass);
+                self.error("A class can't inherit from itself.");
+            }
+            self.named_variable(superclass);
+            self.begin_scope();
+            let token = Token::new(TokenType::Super, "super", None, name.line, name.column);
+            self.declare_variable(&token);
+            self.mark_initialized();
+            self.named_variable(name);
+            // an error inheriting is reported where the superclass is named
+            self.at(superclass);
+            self.emit(OpCode::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        self.named_variable(name);
+        for method in methods {
+            self.at(&method.name);
+            let constant = self.identifier_constant(&method.name);
+            let kind = if method.name.symbol == Symbol::INIT {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+            self.at(&method.name);
+            self.emit(OpCode::Method);
+            self.emit_u16(constant);
+        }
+        self.emit(OpCode::Pop);
+        if superclass.is_some() {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    // function compiles the body of a function or method declaration into a function of its own,
+    // and emits the code creating a closure of it
+    fn function(&mut self, declaration: &ast::Function, kind: FunctionKind) {
+        let name = Some(declaration.name.lexeme.clone());
+        self.functions.push(FunctionState::new(kind, name));
+        // the parameters are the first locals of the function. Its scope is never ended, returning
+        // discards all of its locals at once.
+        self.begin_scope();
+        for param in &declaration.params {
+            self.at(param);
+            self.current_mut().arity += 1;
+            self.declare_variable(param);
+            self.mark_initialized();
+        }
+        for statement in &declaration.body {
+            self.statement(statement);
+        }
+        let (function, upvalues) = self.end_function();
+
+        self.at(&declaration.name);
+        let index = self.make_constant(Value::object(function));
+        self.emit(OpCode::Closure);
+        self.emit_u16(index);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    // end_function finishes the innermost function with an implicit return and puts it on the
+    // heap. It returns the function and the variables it captures.
+    fn end_function(&mut self) -> (ObjRef, Vec<UpvalueRef>) {
+        self.emit_return();
+        let state = self
+            .functions
+            .pop()
+            .expect("the script is never finished twice");
+        let function = Function {
+            name: state.name,
+            arity: state.arity,
+            upvalue_count: state.upvalues.len(),
+            chunk: Rc::new(state.chunk),
+            caches: (0..state.caches).map(|_| Cell::new(None)).collect(),
+        };
+        (self.heap.alloc(Object::Function(function)), state.upvalues)
+    }
+
+    // emit_return returns from the current function without a value, which for an initializer
+    // means returning this
+    fn emit_return(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit_with_byte(OpCode::GetLocal, 0);
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_mut().scope_depth += 1;
+    }
+
+    // end_scope pops the locals of the scope, moving the captured ones into their upvalues
+    fn end_scope(&mut self) {
+        let state = self.current_mut();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self.current().locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            let op = if local.captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.emit(op);
+            self.current_mut().locals.pop();
+        }
+    }
+
+    // declare_variable declares name in the current scope. Globals are late bound, which needs the
+    // constant holding their name, locals live in the next stack slot.
+    fn declare_variable(&mut self, name: &Token) -> Option<u16> {
+        let state = self.current();
+        let depth = state.scope_depth;
+        if depth == 0 {
+            return Some(self.identifier_constant(name));
+        }
+        let redeclared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == depth)
+            .any(|local| local.name == name.symbol);
+        let full = state.locals.len() >= MAX_LOCALS;
+        if redeclared {
+            self.error("Already a variable with this name in this scope.");
+        }
+        if full {
+            self.error("Too many local variables in function.");
+            return None;
+        }
+        self.current_mut().locals.push(Local {
+            name: name.symbol,
+            depth,
+            initialized: false,
+            captured: false,
+        });
+        None
+    }
+
+    fn define_variable(&mut self, global: Option<u16>) {
+        match global {
+            Some(index) => {
+                self.emit(OpCode::DefineGlobal);
+                self.emit_u16(index);
+            }
+            None => self.mark_initialized(),
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.current_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.initialized = true;
+        }
+    }
+
+    // expression compiles expr. Chains are compiled from their innermost operand out, see
+    // Expression::left.
+    fn expression(&mut self, expr: &Expression) {
+        let mut links = Vec::new();
+        let mut innermost = expr;
+        while let Some(left) = left(innermost) {
+            links.push(innermost);
+            innermost = left;
+        }
+        self.operand(innermost);
+        for link in links.into_iter().rev() {
+            self.link(link);
+        }
+    }
+
+    // operand compiles an expression that is not a link of a chain
+    fn operand(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Literal { value } => self.literal(value),
+            Expression::Grouping { expr } => self.expression(expr),
+            Expression::Unary { operator, right } => {
+                self.expression(right);
+                self.at(operator);
+                match operator.kind {
+                    TokenType::Minus => self.emit(OpCode::Negate),
+                    TokenType::Bang => self.emit(OpCode::Not),
+                    _ => self.error("Invalid unary operator."),
+                }
+            }
+            Expression::Variable { name, .. } => self.named_variable(name),
+            Expression::Assign { name, expr, .. } => {
+                self.at(name);
+                let access = self.resolve(name);
+                self.expression(expr);
+                self.at(name);
+                match access {
+                    Access::Local(slot) => self.emit_with_byte(OpCode::SetLocal, slot),
+                    Access::Upvalue(index) => self.emit_with_byte(OpCode::SetUpvalue, index),
+                    Access::Global(index) => {
+                        self.emit(OpCode::SetGlobal);
+                        self.emit_u16(index);
+                    }
+                }
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                self.at(name);
+                let name = self.identifier_constant(name);
+                let cache = self.cache();
+                self.emit(OpCode::SetProperty);
+                self.emit_u16(name);
+                self.emit_u16(cache);
+            }
+            Expression::Super {
+                keyword, method, ..
+            } => {
+                self.this(keyword);
+                self.superclass(keyword);
+                let name = self.identifier_constant(method);
+                self.at(method);
+                self.emit(OpCode::GetSuper);
+                self.emit_u16(name);
+            }
+            Expression::This { keyword, .. } => self.this(keyword),
+            // a call of a super method starts a chain, see left
+            Expression::Call { callee, .. } => match callee.as_ref() {
+                Expression::Super { keyword, .. } => {
+                    self.this(keyword);
+                    self.link(expr);
+                }
+                _ => unreachable!("other calls are links"),
+            },
+            Expression::Binary { .. } | Expression::Get { .. } | Expression::Logical { .. } => {
+                unreachable!("links are compiled by expression")
+            }
+        }
+    }
+
+    // link compiles a link of a chain, whose left operand is on the stack already
+    fn link(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Binary {
+                operator, right, ..
+            } => {
+                self.expression(right);
+                self.at(operator);
+                match operator.kind {
+                    TokenType::EqualEqual => self.emit(OpCode::Equal),
+                    TokenType::BangEqual => {
+                        self.emit(OpCode::Equal);
+                        self.emit(OpCode::Not);
+                    }
+                    TokenType::Greater => self.emit(OpCode::Greater),
+                    TokenType::GreaterEqual => self.emit(OpCode::GreaterEqual),
+                    TokenType::Less => self.emit(OpCode::Less),
+                    TokenType::LessEqual => self.emit(OpCode::LessEqual),
+                    TokenType::Plus => {
+                        if !self.fuse(OpCode::AddLocal) {
+                            self.emit(OpCode::Add);
+                        }
+                    }
+                    TokenType::Minus => self.emit(OpCode::Subtract),
+                    TokenType::Star => self.emit(OpCode::Multiply),
+                    TokenType::Slash => self.emit(OpCode::Divide),
+                    _ => self.error("Invalid binary operator."),
+                }
+            }
+            Expression::Logical {
+                operator, right, ..
+            } => {
+                self.at(operator);
+                if operator.kind == TokenType::Or {
+                    // skip over the right operand if the left one is truthy
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                // methods are called without binding them to the instance first, so the left
+                // operand of the call is the instance, see left
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.at(paren);
+                // the parser does not let calls with more arguments through
+                let count = arguments.len() as u8;
+                match callee.as_ref() {
+                    Expression::Get { name, .. } => {
+                        let name = self.identifier_constant(name);
+                        let cache = self.cache();
+                        self.emit(OpCode::Invoke);
+                        self.emit_u16(name);
+                        self.emit_byte(count);
+                        self.emit_u16(cache);
+                    }
+                    Expression::Super {
+                        keyword, method, ..
+                    } => {
+                        self.superclass(keyword);
+                        let name = self.identifier_constant(method);
+                        self.at(paren);
+                        self.emit(OpCode::SuperInvoke);
+                        self.emit_u16(name);
+                        self.emit_byte(count);
+                    }
+                    _ => self.emit_with_byte(OpCode::Call, count),
+                }
+            }
+            Expression::Get { name, .. } => {
+                self.at(name);
+                let name = self.identifier_constant(name);
+                let cache = self.cache();
+                // the operands of GetLocalProperty follow the slot of the local
+                if !self.fuse(OpCode::GetLocalProperty) {
+                    self.emit(OpCode::GetProperty);
+                }
+                self.emit_u16(name);
+                self.emit_u16(cache);
+            }
+            _ => unreachable!("chains only hold links"),
+        }
+    }
+
+    fn named_variable(&mut self, name: &Token) {
+        self.at(name);
+        match self.resolve(name) {
+            Access::Local(slot) => self.emit_with_byte(OpCode::GetLocal, slot),
+            Access::Upvalue(index) => self.emit_with_byte(OpCode::GetUpvalue, index),
+            Access::Global(index) => {
+                self.emit(OpCode::GetGlobal);
+                self.emit_u16(index);
+            }
+        }
+    }
+
+    // this loads this, which methods hold in their first slot, at keyword
+    fn this(&mut self, keyword: &Token) {
+        self.at(keyword);
+        if self.classes.is_empty() {
+            let message = format!("Can't use '{}' outside of a class.", keyword.lexeme);
+            return self.error(&message);
+        }
+        let this = Token::new(TokenType::This, "this", None, keyword.line, keyword.column);
+        self.named_variable(&this);
+    }
+
+    // superclass loads the superclass of the class we are in, for a use of super at keyword
+    fn superclass(&mut self, keyword: &Token) {
+        self.at(keyword);
+        match self.classes.last() {
+            // this has reported the error already
+            None => {}
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => self.named_variable(keyword),
+        }
+    }
+
+    // resolve finds the variable name refers to: a local of the current function, a local or
+    // upvalue of a function around it, which is captured, or else a global
+    fn resolve(&mut self, name: &Token) -> Access {
+        let current = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            return Access::Local(slot);
+        }
+        if let Some(index) = self.resolve_upvalue(current, name) {
+            return Access::Upvalue(index);
+        }
+        Access::Global(self.identifier_constant(name))
+    }
+
+    fn resolve_local(&mut self, function: usize, name: &Token) -> Option<u8> {
+        let locals = &self.functions[function].locals;
+        let (slot, local) = locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.symbol)?;
+        if !local.initialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        // there are never more than MAX_LOCALS locals
+        Some(slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, function: usize, name: &Token) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        let enclosing = function - 1;
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].locals[slot as usize].captured = true;
+            return Some(self.add_upvalue(function, slot, true));
+        }
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(function, index, false))
+    }
+
+    // add_upvalue returns the upvalue of function capturing a variable, adding it if the function
+    // does not capture the variable yet. Closures capturing the same variable share it.
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|other| *other == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() >= MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        let upvalues = &mut self.functions[function].upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn literal(&mut self, token: &Token) {
+        self.at(token);
+        match (token.kind, &token.literal) {
+            (TokenType::Nil, _) => self.emit(OpCode::Nil),
+            (TokenType::True, _) => self.emit(OpCode::True),
+            (TokenType::False, _) => self.emit(OpCode::False),
+            (_, Some(Literal::Number(val))) => self.constant(Value::from(*val)),
+            (_, Some(Literal::String(val))) => {
+                let value = self.heap.alloc_string(val);
+                self.constant(value)
+            }
+            _ => self.error("Invalid literal."),
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        let index = match self.current_mut().chunk.add_constant(value) {
+            Some(index) => index,
+            None => return self.error("Too many constants in one chunk."),
+        };
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_with_byte(OpCode::Constant, index);
+        } else {
+            self.emit(OpCode::ConstantLong);
+            for byte in &index.to_le_bytes()[..3] {
+                self.emit_byte(*byte);
+            }
+        }
+    }
+
+    // identifier_constant returns the constant holding the name of a global
+    fn identifier_constant(&mut self, name: &Token) -> u16 {
+        if let Some(index) = self.current().names.get(&name.symbol) {
+            return *index;
+        }
+        let value = self.heap.alloc_string(&name.lexeme);
+        let index = self.make_constant(value);
+        self.current_mut().names.insert(name.symbol, index);
+        index
+    }
+
+    // make_constant adds a constant referred to by a two byte operand
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let index = self.current_mut().chunk.add_constant(value);
+        match index.and_then(|index| u16::try_from(index).ok()) {
+            Some(index) => index,
+            None => {
+                self.error("Too many constants in one chunk.");
+                0
+            }
+        }
+    }
+
+    // cache returns the index of a new inline cache
+    fn cache(&mut self) -> u16 {
+        let state = self.current_mut();
+        state.caches += 1;
+        if state.caches > MAX_CACHES {
+            self.error("Too many property accesses in function.");
+            return 0;
+        }
+        (state.caches - 1) as u16
+    }
+
+    // fuse turns the GetLocal just emitted into the superinstruction op, which does the work of
+    // both it and the instruction about to be emitted, and returns whether it did. It does not
+    // when a jump lands between the two instructions, or when they are on different lines and an
+    // error would be reported at the wrong one.
+    fn fuse(&mut self, op: OpCode) -> bool {
+        let line = self.token.line;
+        let superinstructions = self.superinstructions;
+        let state = self.current_mut();
+        let end = state.chunk.code.len();
+        let fusable = superinstructions
+            && state.last_instruction + 2 == end
+            && state.chunk.code[state.last_instruction] == OpCode::GetLocal as u8
+            && state.jump_target != Some(end)
+            && state.chunk.lines.last().map(|(last, _)| *last) == Some(line);
+        if fusable {
+            state.chunk.code[state.last_instruction] = op as u8;
+        }
+        fusable
+    }
+
+    // emit_condition_jump emits a jump over the code that follows for when the condition on the
+    // stack is false. The condition is popped on the way, or by pop_condition where the jump lands
+    // without superinstructions.
+    fn emit_condition_jump(&mut self) -> usize {
+        if self.superinstructions {
+            return self.emit_jump(OpCode::PopJumpIfFalse);
+        }
+        let jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        jump
+    }
+
+    fn pop_condition(&mut self) {
+        if !self.superinstructions {
+            self.emit(OpCode::Pop);
+        }
+    }
+
+    // emit_jump emits a jump with a placeholder distance, which patch_jump fills in once we know
+    // where it goes
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit(op);
+        self.emit_u16(u16::MAX);
+        self.current().chunk.code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let state = self.current_mut();
+        state.jump_target = Some(state.chunk.code.len());
+        let code = &mut state.chunk.code;
+        let distance = code.len() - offset - 2;
+        match u16::try_from(distance) {
+            Ok(distance) => code[offset..offset + 2].copy_from_slice(&distance.to_le_bytes()),
+            Err(_) => self.error("Too much code to jump over."),
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit(OpCode::Loop);
+        let distance = self.current().chunk.code.len() - loop_start + 2;
+        match u16::try_from(distance) {
+            Ok(distance) => self.emit_u16(distance),
+            Err(_) => {
+                self.error("Loop body too large.");
+                self.emit_u16(0);
+            }
+        }
+    }
+
+    fn current(&self) -> &FunctionState {
+        self.functions
+            .last()
+            .expect("the script is always being compiled")
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("the script is always being compiled")
+    }
+
+    // at makes token the one instructions and errors are attributed to
+    fn at(&mut self, token: &Token) {
+        self.token = token.clone();
+    }
+
+    fn error(&mut self, message: &str) {
+        let error = CompileError::new(&self.token, message);
+        self.errors.push(error);
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        let line = self.token.line;
+        let state = self.current_mut();
+        state.last_instruction = state.chunk.code.len();
+        state.chunk.write_op(op, line);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.token.line;
+        self.current_mut().chunk.write(byte, line);
+    }
+
+    fn emit_with_byte(&mut self, op: OpCode, byte: u8) {
+        self.emit(op);
+        self.emit_byte(byte);
+    }
+
+    fn emit_u16(&mut self, val: u16) {
+        for byte in val.to_le_bytes() {
+            self.emit_byte(byte);
+        }
+    }
+}
+
+// left is the left operand of expr if it is a link of a chain, like Expression::left, except that
+// calling a method invokes it on the instance without getting it first, and calling a method of
+// super starts a chain
+fn left(expr: &Expression) -> Option<&Expression> {
+    match expr {
+        Expression::Call { callee, .. } => match callee.as_ref() {
+            Expression::Get { expr, .. } => Some(expr),
+            Expression::Super { .. } => None,
+            callee => Some(callee),
+        },
+        expr => expr.left(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Statement> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        Parser::new(scanner.into_parts().0).parse().unwrap()
+    }
+
+    fn function(heap: &Heap, obj: ObjRef) -> &Function {
+        match heap.get(obj) {
+            Object::Function(function) => function,
+            object => panic!("{:?} is not a function", object),
+        }
+    }
+
+    // compile returns the chunk of the script
+    fn compile(source: &str) -> Rc<Chunk> {
+        compile_with(source, true)
+    }
+
+    fn compile_with(source: &str, superinstructions: bool) -> Rc<Chunk> {
+        let mut heap = Heap::new();
+        let script = Compiler::compile(&parse(source), &mut heap, superinstructions).unwrap();
+        Rc::clone(&function(&heap, script).chunk)
+    }
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        let errors = Compiler::compile(&parse(source), &mut Heap::new(), true).unwrap_err();
+        errors.into_iter().map(|error| error.message).collect()
+    }
+
+    #[test]
+    fn compiler_emits_postfix_code() {
+        let chunk = compile("print 1 + 2 * 3;\n!true;");
+        let expected = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::Constant as u8,
+            1,
+            OpCode::Constant as u8,
+            2,
+            OpCode::Multiply as u8,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::True as u8,
+            OpCode::Not as u8,
+            // the script returns the value of the last expression statement
+            OpCode::Return as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(chunk.code, expected);
+        assert_eq!(chunk.constants, [1.0, 2.0, 3.0].map(Value::from));
+        assert_eq!(chunk.line(9), 2);
+    }
+
+    #[test]
+    fn compiler_uses_long_constants_when_the_pool_grows() {
+        let source = "1 + ".repeat(300) + "1;";
+        let chunk = compile(&source);
+        assert_eq!(chunk.constants.len(), 301);
+        // the first constant takes two bytes, every next one is followed by an Add. The 257th
+        // constant is the first one that does not fit in a single byte.
+        let long = 2 + 255 * 3;
+        assert_eq!(chunk.code[long], OpCode::ConstantLong as u8);
+        assert_eq!(chunk.code[long + 1..long + 4], [0, 1, 0]);
+    }
+
+    #[test]
+    fn compiler_keeps_locals_on_the_stack() {
+        // slot 0 holds the script itself
+        let chunk = compile("{ var a = 1; print a; }");
+        let expected = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::Print as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(chunk.code, expected);
+
+        // globals are looked up by name, which is only added to the pool once
+        let chunk = compile("var a; a = a;");
+        assert_eq!(chunk.constants.len(), 1);
+        assert_eq!(chunk.code[4], OpCode::GetGlobal as u8);
+    }
+
+    #[test]
+    fn compiler_captures_variables_in_upvalues() {
+        let mut heap = Heap::new();
+        let source = "{ var x = 1; fun get() { return x; } fun set() { x = 2; } }";
+        let script = Compiler::compile(&parse(source), &mut heap, true).unwrap();
+        let chunk = &function(&heap, script).chunk;
+        let expected = [
+            OpCode::Constant as u8,
+            0,
+            // both closures capture local 1 of the script
+            OpCode::Closure as u8,
+            1,
+            0,
+            1,
+            1,
+            OpCode::Closure as u8,
+            2,
+            0,
+            1,
+            1,
+            OpCode::Pop as u8,
+            OpCode::Pop as u8,
+            // x is captured, so it moves into the upvalue the closures share
+            OpCode::CloseUpvalue as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(chunk.code, expected);
+
+        let get = function(&heap, chunk.constants[1].as_object().unwrap());
+        assert_eq!(get.upvalue_count, 1);
+        assert_eq!(get.chunk.code[..2], [OpCode::GetUpvalue as u8, 0]);
+    }
+
+    #[test]
+    fn compiler_emits_superinstructions() {
+        let source = "{ var a = 1; print 2 + a; if (a) print a.x; }";
+        let expected = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::Constant as u8,
+            1,
+            OpCode::AddLocal as u8,
+            1,
+            OpCode::Print as u8,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::PopJumpIfFalse as u8,
+            7,
+            0,
+            OpCode::GetLocalProperty as u8,
+            1,
+            2,
+            0,
+            0,
+            0,
+            OpCode::Print as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(compile_with(source, true).code, expected);
+
+        let expected = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::Constant as u8,
+            1,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::JumpIfFalse as u8,
+            12,
+            0,
+            OpCode::Pop as u8,
+            OpCode::GetLocal as u8,
+            1,
+            OpCode::GetProperty as u8,
+            2,
+            0,
+            0,
+            0,
+            OpCode::Print as u8,
+            OpCode::Jump as u8,
+            1,
+            0,
+            OpCode::Pop as u8,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(compile_with(source, false).code, expected);
+
+        // nothing is fused where a jump lands in between, or across lines
+        for source in ["{ var a; 1 + (a and a); }", "{ var a; 1 +\na; }"] {
+            let code = &compile(source).code;
+            assert!(code.contains(&(OpCode::Add as u8)), "{}", source);
+            assert!(!code.contains(&(OpCode::AddLocal as u8)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn compiler_compiles_classes() {
+        let mut heap = Heap::new();
+        let source = "class A { init() {} m() { return this.m(); } }";
+        let script = Compiler::compile(&parse(source), &mut heap, true).unwrap();
+        let chunk = &function(&heap, script).chunk;
+        let expected = [
+            OpCode::Class as u8,
+            0,
+            0,
+            OpCode::DefineGlobal as u8,
+            0,
+            0,
+            OpCode::GetGlobal as u8,
+            0,
+            0,
+            OpCode::Closure as u8,
+            2,
+            0,
+            OpCode::Method as u8,
+            1,
+            0,
+            OpCode::Closure as u8,
+            4,
+            0,
+            OpCode::Method as u8,
+            3,
+            0,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Return as u8,
+        ];
+        assert_eq!(chunk.code, expected);
+
+        // initializers return this, which methods keep in slot 0
+        let init = function(&heap, chunk.constants[2].as_object().unwrap());
+        assert_eq!(
+            init.chunk.code,
+            [OpCode::GetLocal as u8, 0, OpCode::Return as u8]
+        );
+        // calling a method is a single instruction with a cache of its own
+        let method = function(&heap, chunk.constants[4].as_object().unwrap());
+        assert_eq!(method.chunk.code[2], OpCode::Invoke as u8);
+        assert_eq!(method.caches.len(), 1);
+
+        assert_eq!(
+            compile_errors(
+                "class A < A {}\nprint this;\nclass B { init() { return 1; } f() { super.f(); } }"
+            ),
+            [
+                "A class can't inherit from itself.",
+                "Can't use 'this' outside of a class.",
+                "Can't return a value from an initializer.",
+                "Can't use 'super' in a class with no superclass.",
+            ]
+        );
+    }
+
+    #[test]
+    fn compiler_reports_scoping_errors() {
+        assert_eq!(
+            compile_errors("{ var a = 1; var a = 2; }\n{ var b = b; }\nreturn 1;"),
+            [
+                "Already a variable with this name in this scope.",
+                "Can't read local variable in its own initializer.",
+                "Can't return from top-level code.",
+            ]
+        );
+        let locals: String = (0..256).map(|i| format!("var a{};", i)).collect();
+        assert_eq!(
+            compile_errors(&format!("{{ {} }}", locals)),
+            ["Too many local variables in function."]
+        );
+    }
+}
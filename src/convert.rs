@@ -0,0 +1,756 @@
+use crate::heap::{Heap, ObjRef};
+use crate::lox::Backend;
+use crate::object::{Class, Instance, LoxClass, LoxInstance, Object, Shape};
+use crate::value::Value;
+use serde::de::value::{StrDeserializer, StringDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, Unexpected,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Impossible, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+// Values convert to and from Rust types the way they would to and from JSON: nil, booleans,
+// numbers and strings are themselves, and instances are maps from the names of their fields to
+// their values. Structs and maps become instances of a class without methods named after the
+// struct, or Map. Unit variants of enums are strings, and the others are instances with a single
+// field named after the variant. Lox has no lists, so sequences and tuples do not convert.
+
+// ConvertError is why a value could not be converted
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ConvertError(String);
+
+impl Display for ConvertError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl de::Error for ConvertError {
+    fn custom<T: Display>(message: T) -> Self {
+        ConvertError(message.to_string())
+    }
+}
+
+impl ser::Error for ConvertError {
+    fn custom<T: Display>(message: T) -> Self {
+        ConvertError(message.to_string())
+    }
+}
+
+// from_value makes a T out of value
+pub(crate) fn from_value<T: DeserializeOwned>(
+    heap: &Heap,
+    value: Value,
+) -> Result<T, ConvertError> {
+    T::deserialize(Deserializer { heap, value })
+}
+
+// to_value makes value into a Lox value for programs of backend. It is put on the heap without
+// collecting garbage, so the values it is made of stay alive until it is done.
+pub(crate) fn to_value<T: Serialize + ?Sized>(
+    heap: &mut Heap,
+    backend: Backend,
+    value: &T,
+) -> Result<Value, ConvertError> {
+    let mut serializer = Serializer {
+        heap,
+        backend,
+        classes: HashMap::new(),
+    };
+    value.serialize(&mut serializer)
+}
+
+fn unsupported(what: &str) -> ConvertError {
+    ConvertError(format!("Lox has no lists, so {} can't be converted.", what))
+}
+
+struct Deserializer<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl<'a> Deserializer<'a> {
+    fn integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConvertError> {
+        match self.value.as_number() {
+            Some(n) if n.fract() == 0.0 && n < 0.0 => visitor.visit_i64(n as i64),
+            Some(n) if n.fract() == 0.0 => visitor.visit_u64(n as u64),
+            Some(n) => Err(de::Error::invalid_type(Unexpected::Float(n), &visitor)),
+            None => de::Deserializer::deserialize_any(self, visitor),
+        }
+    }
+
+    fn unexpected(&self, visitor: &dyn de::Expected) -> ConvertError {
+        let found = match self.heap.type_name(self.value) {
+            "native function" => Unexpected::Other("a native function"),
+            "function" => Unexpected::Other("a function"),
+            "class" => Unexpected::Other("a class"),
+            _ => Unexpected::Other("an instance of a native class"),
+        };
+        de::Error::invalid_type(found, visitor)
+    }
+}
+
+macro_rules! deserialize_integers {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConvertError> {
+                self.integer(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = ConvertError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConvertError> {
+        if self.value.is_nil() {
+            return visitor.visit_unit();
+        }
+        if let Some(b) = self.value.as_bool() {
+            return visitor.visit_bool(b);
+        }
+        if let Some(n) = self.value.as_number() {
+            return visitor.visit_f64(n);
+        }
+        if let Some(s) = self.heap.as_str(self.value) {
+            return visitor.visit_str(s);
+        }
+        match self.heap.fields(self.value) {
+            Some(fields) => visitor.visit_map(Fields {
+                heap: self.heap,
+                fields: fields.into_iter(),
+                value: Value::NIL,
+            }),
+            None => Err(self.unexpected(&visitor)),
+        }
+    }
+
+    deserialize_integers! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConvertError> {
+        if self.value.is_nil() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ConvertError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConvertError> {
+        if let Some(variant) = self.heap.as_str(self.value) {
+            let variant: StrDeserializer<ConvertError> = variant.into_deserializer();
+            return visitor.visit_enum(variant);
+        }
+        match self.heap.fields(self.value).as_deref() {
+            Some(&[(variant, value)]) => visitor.visit_enum(Variant {
+                heap: self.heap,
+                variant: variant.to_string(),
+                value,
+            }),
+            _ => Err(de::Error::invalid_type(
+                Unexpected::Other("a value that is not a string or an instance with one field"),
+                &visitor,
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map
+        struct identifier ignored_any
+    }
+}
+
+// Fields gives the fields of an instance to a map or a struct
+struct Fields<'a> {
+    heap: &'a Heap,
+    fields: std::vec::IntoIter<(&'a str, Value)>,
+    // the value of the field whose name was given last
+    value: Value,
+}
+
+impl<'de, 'a> MapAccess<'de> for Fields<'a> {
+    type Error = ConvertError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ConvertError> {
+        let Some((name, value)) = self.fields.next() else {
+            return Ok(None);
+        };
+        self.value = value;
+        let name: StrDeserializer<ConvertError> = name.into_deserializer();
+        seed.deserialize(name).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ConvertError> {
+        seed.deserialize(Deserializer {
+            heap: self.heap,
+            value: self.value,
+        })
+    }
+}
+
+// Variant is an enum variant that is not a unit variant: an instance with a single field named
+// after the variant
+struct Variant<'a> {
+    heap: &'a Heap,
+    variant: String,
+    value: Value,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a> {
+    type Error = ConvertError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), ConvertError> {
+        let variant: StringDeserializer<ConvertError> = self.variant.clone().into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Variant<'a> {
+    type Error = ConvertError;
+
+    fn unit_variant(self) -> Result<(), ConvertError> {
+        de::Deserialize::deserialize(Deserializer {
+            heap: self.heap,
+            value: self.value,
+        })
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, ConvertError> {
+        seed.deserialize(Deserializer {
+            heap: self.heap,
+            value: self.value,
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, ConvertError> {
+        Err(unsupported("tuple variants"))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConvertError> {
+        de::Deserializer::deserialize_any(
+            Deserializer {
+                heap: self.heap,
+                value: self.value,
+            },
+            visitor,
+        )
+    }
+}
+
+struct Serializer<'a> {
+    heap: &'a mut Heap,
+    backend: Backend,
+    // the classes made so far by name, so instances of the same struct share one, and their shape
+    classes: HashMap<String, ObjRef>,
+}
+
+impl<'a> Serializer<'a> {
+    fn string(&mut self, s: &str) -> Value {
+        self.heap.alloc_string(s)
+    }
+
+    // instance makes an instance of the class called class with fields
+    fn instance(&mut self, class: &str, fields: Vec<(String, Value)>) -> Value {
+        let class = match self.classes.get(class) {
+            Some(class) => *class,
+            None => {
+                let obj = match self.backend {
+                    Backend::TreeWalker => self.heap.alloc(Object::LoxClass(LoxClass {
+                        name: class.into(),
+                        methods: HashMap::new(),
+                        constructor: None,
+                    })),
+                    Backend::Vm => {
+                        let name = self.heap.alloc_string(class).as_object().unwrap();
+                        let shape = self.heap.alloc(Object::Shape(Shape::default()));
+                        self.heap.alloc(Object::Class(Class {
+                            name,
+                            methods: HashMap::new(),
+                            shape,
+                            constructor: None,
+                        }))
+                    }
+                };
+                self.classes.insert(class.to_string(), obj);
+                obj
+            }
+        };
+        let instance = match self.backend {
+            Backend::TreeWalker => Object::LoxInstance(LoxInstance {
+                class,
+                fields: fields
+                    .into_iter()
+                    .map(|(name, value)| (self.heap.symbols.intern(&name), value))
+                    .collect(),
+            }),
+            Backend::Vm => {
+                let mut shape = match self.heap.get(class) {
+                    Object::Class(class) => class.shape,
+                    object => panic!("{:?} is not a class", object),
+                };
+                let mut values = Vec::with_capacity(fields.len());
+                for (name, value) in fields {
+                    let name = self.heap.alloc_string(&name).as_object().unwrap();
+                    shape = self.transition(shape, name);
+                    values.push(value);
+                }
+                Object::Instance(Instance {
+                    class,
+                    shape,
+                    fields: values,
+                })
+            }
+        };
+        Value::object(self.heap.alloc(instance))
+    }
+
+    // transition gives the shape that instances of shape move on to when they get the field name,
+    // like the vm does when a program sets a field
+    fn transition(&mut self, shape: ObjRef, name: ObjRef) -> ObjRef {
+        let Object::Shape(current) = self.heap.get(shape) else {
+            panic!("{:?} is not a shape", shape);
+        };
+        if let Some(next) = current.transitions.get(&name) {
+            return *next;
+        }
+        let mut fields = current.fields.clone();
+        fields.insert(name, fields.len());
+        let next = self.heap.alloc(Object::Shape(Shape {
+            fields,
+            transitions: HashMap::new(),
+        }));
+        if let Object::Shape(current) = self.heap.get_mut(shape) {
+            current.transitions.insert(name, next);
+        }
+        next
+    }
+}
+
+impl<'s, 'a> ser::Serializer for &'s mut Serializer<'a> {
+    type Ok = Value;
+    type Error = ConvertError;
+    type SerializeSeq = Impossible<Value, ConvertError>;
+    type SerializeTuple = Impossible<Value, ConvertError>;
+    type SerializeTupleStruct = Impossible<Value, ConvertError>;
+    type SerializeTupleVariant = Impossible<Value, ConvertError>;
+    type SerializeMap = Builder<'s, 'a>;
+    type SerializeStruct = Builder<'s, 'a>;
+    type SerializeStructVariant = Builder<'s, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, ConvertError> {
+        Ok(Value::from(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, ConvertError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, ConvertError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, ConvertError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, ConvertError> {
+        Ok(Value::from(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, ConvertError> {
+        Ok(self.string(v.encode_utf8(&mut [0; 4])))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, ConvertError> {
+        Ok(self.string(v))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Value, ConvertError> {
+        Err(unsupported("bytes"))
+    }
+
+    fn serialize_none(self) -> Result<Value, ConvertError> {
+        Ok(Value::NIL)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, ConvertError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, ConvertError> {
+        Ok(Value::NIL)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, ConvertError> {
+        Ok(Value::NIL)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, ConvertError> {
+        Ok(self.string(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, ConvertError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, ConvertError> {
+        let value = value.serialize(&mut *self)?;
+        Ok(self.instance(name, vec![(variant.to_string(), value)]))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, ConvertError> {
+        Err(unsupported("sequences"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, ConvertError> {
+        Err(unsupported("tuples"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, ConvertError> {
+        Err(unsupported("tuple structs"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, ConvertError> {
+        Err(unsupported("tuple variants"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, ConvertError> {
+        Ok(Builder::new(self, "Map", None))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, ConvertError> {
+        Ok(Builder::new(self, name, None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, ConvertError> {
+        Ok(Builder::new(self, variant, Some(name)))
+    }
+}
+
+// Builder collects the fields of an instance made from a map or a struct
+struct Builder<'s, 'a> {
+    serializer: &'s mut Serializer<'a>,
+    class: &'static str,
+    // the enum of a struct variant, which the instance is the only field of an instance of
+    variant_of: Option<&'static str>,
+    fields: Vec<(String, Value)>,
+    // the name of the field whose value comes next in a map
+    key: String,
+}
+
+impl<'s, 'a> Builder<'s, 'a> {
+    fn new(
+        serializer: &'s mut Serializer<'a>,
+        class: &'static str,
+        variant_of: Option<&'static str>,
+    ) -> Self {
+        Builder {
+            serializer,
+            class,
+            variant_of,
+            fields: Vec::new(),
+            key: String::new(),
+        }
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), ConvertError> {
+        let value = value.serialize(&mut *self.serializer)?;
+        self.fields.push((name.to_string(), value));
+        Ok(())
+    }
+
+    fn build(self) -> Result<Value, ConvertError> {
+        let instance = self.serializer.instance(self.class, self.fields);
+        Ok(match self.variant_of {
+            Some(name) => self
+                .serializer
+                .instance(name, vec![(self.class.to_string(), instance)]),
+            None => instance,
+        })
+    }
+}
+
+impl<'s, 'a> ser::SerializeMap for Builder<'s, 'a> {
+    type Ok = Value;
+    type Error = ConvertError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ConvertError> {
+        let key = key.serialize(&mut *self.serializer)?;
+        match self.serializer.heap.as_str(key) {
+            Some(key) => {
+                self.key = key.to_string();
+                Ok(())
+            }
+            None => Err(ConvertError(
+                "Map keys must be strings to become fields.".to_string(),
+            )),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConvertError> {
+        let key = std::mem::take(&mut self.key);
+        self.field(&key, value)
+    }
+
+    fn end(self) -> Result<Value, ConvertError> {
+        self.build()
+    }
+}
+
+impl<'s, 'a> ser::SerializeStruct for Builder<'s, 'a> {
+    type Ok = Value;
+    type Error = ConvertError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ConvertError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, ConvertError> {
+        self.build()
+    }
+}
+
+impl<'s, 'a> ser::SerializeStructVariant for Builder<'s, 'a> {
+    type Ok = Value;
+    type Error = ConvertError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ConvertError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, ConvertError> {
+        self.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lox::{Backend, Lox};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Config {
+        name: String,
+        port: u16,
+        debug: bool,
+        ratio: f64,
+        proxy: Option<String>,
+        level: Level,
+        server: Server,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Server {
+        host: String,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Level {
+        Quiet,
+        Verbose(u8),
+        Custom { prefix: String },
+    }
+
+    #[test]
+    fn programs_give_configurations() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            let value = lox
+                .run(
+                    "class Config {} class Server {}
+                     var c = Config();
+                     c.name = \"app\"; c.port = 8080; c.debug = false; c.ratio = 0.5;
+                     c.proxy = nil; c.level = \"Quiet\";
+                     c.server = Server(); c.server.host = \"localhost\";
+                     c;",
+                )
+                .unwrap();
+            let config: Config = lox.deserialize(value).unwrap();
+            assert_eq!(
+                config,
+                Config {
+                    name: "app".to_string(),
+                    port: 8080,
+                    debug: false,
+                    ratio: 0.5,
+                    proxy: None,
+                    level: Level::Quiet,
+                    server: Server {
+                        host: "localhost".to_string()
+                    },
+                },
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn rust_values_become_instances() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.run("fun describe(c) { return c.name + \" on \" + c.server.host; }")
+                .unwrap();
+            let config = Config {
+                name: "app".to_string(),
+                port: 80,
+                debug: true,
+                ratio: 1.5,
+                proxy: Some("cache".to_string()),
+                level: Level::Custom {
+                    prefix: ">".to_string(),
+                },
+                server: Server {
+                    host: "example.com".to_string(),
+                },
+            };
+            let value = lox.serialize(&config).unwrap();
+            assert_eq!(lox.display(value), "Config instance");
+            let describe = lox.global("describe").unwrap();
+            let description = lox.call(describe, &[value]).unwrap();
+            assert_eq!(lox.display(description), "app on example.com");
+            assert_eq!(lox.deserialize::<Config>(value).unwrap(), config);
+
+            let level = lox.serialize(&Level::Verbose(2)).unwrap();
+            assert_eq!(lox.deserialize::<Level>(level), Ok(Level::Verbose(2)));
+            let map = HashMap::from([("a".to_string(), 1.0), ("b".to_string(), 2.0)]);
+            let value = lox.serialize(&map).unwrap();
+            assert_eq!(lox.display(value), "Map instance");
+            assert_eq!(lox.deserialize::<HashMap<String, f64>>(value), Ok(map));
+        }
+    }
+
+    #[test]
+    fn values_that_do_not_fit_are_errors() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            assert_eq!(
+                lox.serialize(&vec![1, 2]).unwrap_err().to_string(),
+                "Lox has no lists, so sequences can't be converted."
+            );
+            let half = lox.run("0.5;").unwrap();
+            assert!(lox.deserialize::<u16>(half).is_err());
+            let big = lox.run("70000;").unwrap();
+            assert!(lox.deserialize::<u16>(big).is_err());
+            let function = lox.run("fun f() {} f;").unwrap();
+            assert_eq!(
+                lox.deserialize::<String>(function).unwrap_err().to_string(),
+                "invalid type: a function, expected a string"
+            );
+            let value = lox.run("class Server {} Server();").unwrap();
+            assert_eq!(
+                lox.deserialize::<Server>(value).unwrap_err().to_string(),
+                "missing field `host`"
+            );
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::heap::{Heap, ObjRef};
+use crate::object::Object;
+use crate::value::Value;
+use std::fmt::Write;
+
+// disassemble_function lists the code of a compiled function, followed by the functions declared in
+// it
+pub(crate) fn disassemble_function(function: ObjRef, heap: &Heap) -> String {
+    let (chunk, name) = match heap.get(function) {
+        Object::Function(function) => (&function.chunk, &function.name),
+        _ => return String::new(),
+    };
+    let name = name.as_deref().unwrap_or("<script>");
+    let mut listing = disassemble(chunk, heap, name);
+    for constant in &chunk.constants {
+        if let Some(obj) = constant.as_object() {
+            if let Object::Function(_) = heap.get(obj) {
+                listing.push_str(&disassemble_function(obj, heap));
+            }
+        }
+    }
+    listing
+}
+
+// disassemble lists every instruction in chunk with its offset, source line, operands and the
+// constants they refer to. Instructions on the same line as the previous one show a '|':
+//
+// == script ==
+// 0000    1 Constant            0 '1'
+// 0002    | Print
+pub(crate) fn disassemble(chunk: &Chunk, heap: &Heap, name: &str) -> String {
+    let mut listing = format!("== {} ==\n", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (instruction, next) = disassemble_instruction(chunk, heap, offset);
+        listing.push_str(&instruction);
+        listing.push('\n');
+        offset = next;
+    }
+    listing
+}
+
+// disassemble_instruction describes the instruction at offset and returns the offset of the next
+// instruction. Chunks loaded from disk may be corrupt, so unknown opcodes and missing operands are
+// described rather than trusted.
+pub(crate) fn disassemble_instruction(
+    chunk: &Chunk,
+    heap: &Heap,
+    offset: usize,
+) -> (String, usize) {
+    let mut text = format!("{:04} ", offset);
+    let line = chunk.line(offset);
+    if offset > 0 && line == chunk.line(offset - 1) {
+        text.push_str("   | ");
+    } else {
+        let _ = write!(text, "{:4} ", line);
+    }
+
+    let byte = chunk.code[offset];
+    let op = match OpCode::from_byte(byte) {
+        Some(op) => op,
+        None => {
+            let _ = write!(text, "Unknown opcode {}", byte);
+            return (text, offset + 1);
+        }
+    };
+    let width = op.operand_width();
+    let next = offset + 1 + width;
+    let operand = match chunk.code.get(offset + 1..next) {
+        Some(operand) => operand
+            .iter()
+            .rev()
+            .fold(0, |operand, byte| operand << 8 | *byte as usize),
+        None => {
+            let _ = write!(text, "{:?} <missing operand>", op);
+            return (text, chunk.code.len());
+        }
+    };
+    let name = format!("{:?}", op);
+    match op {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            constant_instruction(chunk, heap, &name, operand, &mut text);
+            (text, next)
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::AddLocal => {
+            let _ = write!(text, "{:<16} {:4}", name, operand);
+            (text, next)
+        }
+        // the instructions with several operands show the name of the property first
+        OpCode::GetProperty | OpCode::SetProperty => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            constant_instruction(chunk, heap, &name, index, &mut text);
+            let _ = write!(text, " cache {}", chunk.read_u16(offset + 3));
+            (text, next)
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let index = chunk.read_u16(offset + 1) as usize;
+            constant_instruction(chunk, heap, &name, index, &mut text);
+            let _ = write!(text, " ({} args)", chunk.code[offset + 3]);
+            if op == OpCode::Invoke {
+                let _ = write!(text, " cache {}", chunk.read_u16(offset + 4));
+            }
+            (text, next)
+        }
+        OpCode::GetLocalProperty => {
+            let index = chunk.read_u16(offset + 2) as usize;
+            constant_instruction(chunk, heap, &name, index, &mut text);
+            let slot = chunk.code[offset + 1];
+            let _ = write!(text, " local {} cache {}", slot, chunk.read_u16(offset + 4));
+            (text, next)
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::PopJumpIfFalse => {
+            let _ = write!(text, "{:<16} {:4} -> {}", name, offset, next + operand);
+            (text, next)
+        }
+        OpCode::Loop => {
+            let target = next as isize - operand as isize;
+            let _ = write!(text, "{:<16} {:4} -> {}", name, offset, target);
+            (text, next)
+        }
+        OpCode::Closure => closure_instruction(chunk, heap, offset, operand, text),
+        _ => {
+            let _ = write!(text, "{}", name);
+            (text, next)
+        }
+    }
+}
+
+// closure_instruction describes a Closure instruction, which lists the variables the closure
+// captures after its function, one per line:
+//
+// 0000    1 Closure             0 '<fn f>'
+// 0003    |                     local 1
+fn closure_instruction(
+    chunk: &Chunk,
+    heap: &Heap,
+    offset: usize,
+    index: usize,
+    mut text: String,
+) -> (String, usize) {
+    constant_instruction(chunk, heap, "Closure", index, &mut text);
+    let function = chunk
+        .constants
+        .get(index)
+        .and_then(|value| value.as_object());
+    let upvalues = match function.map(|function| heap.get(function)) {
+        Some(Object::Function(function)) => function.upvalue_count,
+        _ => 0,
+    };
+    let mut next = offset + 3;
+    for _ in 0..upvalues {
+        let (is_local, index) = match chunk.code.get(next..next + 2) {
+            Some(upvalue) => (upvalue[0] == 1, upvalue[1]),
+            None => {
+                text.push_str("\n<missing upvalue>");
+                return (text, chunk.code.len());
+            }
+        };
+        let kind = if is_local { "local" } else { "upvalue" };
+        let _ = write!(
+            text,
+            "\n{:04}    |                     {} {}",
+            next, kind, index
+        );
+        next += 2;
+    }
+    (text, next)
+}
+
+// constant_instruction describes an instruction whose operand is the index of a constant
+fn constant_instruction(chunk: &Chunk, heap: &Heap, name: &str, index: usize, text: &mut String) {
+    match chunk.constants.get(index) {
+        Some(value) => {
+            let _ = write!(text, "{:<16} {:4} '{}'", name, index, heap.display(*value));
+        }
+        None => {
+            let _ = write!(text, "{:<16} {:4} <missing constant>", name, index);
+        }
+    }
+}
+
+// stack shows the values on the vm stack from bottom to top, like `[ 1 ][ "a" ]`
+pub(crate) fn stack(values: &[Value], heap: &Heap) -> String {
+    let mut text = String::from("          ");
+    for value in values {
+        match heap.as_str(*value) {
+            Some(val) => {
+                let _ = write!(text, "[ \"{}\" ]", val);
+            }
+            None => {
+                let _ = write!(text, "[ {} ]", heap.display(*value));
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassemble_lists_instructions() {
+        let mut heap = Heap::new();
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::from(1.5));
+        chunk.add_constant(heap.alloc_string("a"));
+        chunk.write_op(OpCode::Constant, 1);
+        chunk.write(0, 1);
+        chunk.write_op(OpCode::Negate, 1);
+        chunk.write_op(OpCode::Print, 1);
+        chunk.write_op(OpCode::ConstantLong, 2);
+        chunk.write(1, 2);
+        chunk.write(0, 2);
+        chunk.write(0, 2);
+        chunk.write_op(OpCode::Pop, 2);
+        chunk.write_op(OpCode::Return, 3);
+
+        let expected = "\
+== test ==
+0000    1 Constant            0 '1.5'
+0002    | Negate
+0003    | Print
+0004    2 ConstantLong        1 'a'
+0008    | Pop
+0009    3 Return
+";
+        assert_eq!(disassemble(&chunk, &heap, "test"), expected);
+    }
+
+    #[test]
+    fn disassemble_describes_corrupt_code() {
+        let mut chunk = Chunk::new();
+        chunk.write(255, 1);
+        chunk.write_op(OpCode::Constant, 1);
+        chunk.write(7, 1);
+        chunk.write_op(OpCode::ConstantLong, 1);
+
+        let expected = "\
+== corrupt ==
+0000    1 Unknown opcode 255
+0001    | Constant            7 <missing constant>
+0003    | ConstantLong <missing operand>
+";
+        assert_eq!(disassemble(&chunk, &Heap::new(), "corrupt"), expected);
+    }
+
+    #[test]
+    fn stack_shows_values_bottom_to_top() {
+        let mut heap = Heap::new();
+        let values = [Value::from(1.0), heap.alloc_string("a"), Value::NIL];
+        assert_eq!(stack(&values, &heap), "          [ 1 ][ \"a\" ][ nil ]");
+    }
+}
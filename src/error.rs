@@ -0,0 +1,95 @@
+use crate::interpreter::RuntimeError;
+use crate::loxc::LoadError;
+use crate::token::Span;
+use std::fmt::{Display, Formatter};
+
+// Error is why Lox could not run a program to the end
+#[derive(Clone, PartialEq, Debug)]
+pub enum Error {
+    // the source has errors, found by the scanner, the parser, the resolver or the compiler, and
+    // nothing ran
+    Compile(Vec<Diagnostic>),
+    // the program failed while it ran
+    Runtime(RuntimeError),
+    // a compiled program is corrupt or was written by another version, and nothing ran
+    Load(LoadError),
+    // a value given to Lox was collected, or came from another Lox or backend (which is usually but
+    // not always caught)
+    InvalidValue,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Compile(diagnostics) => {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Load(error) => write!(f, "{}", error),
+            Error::InvalidValue => {
+                write!(f, "The value is no longer alive or is not from this Lox.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Diagnostic is an error in the source of a program. It shows the offending source, similar to
+// rustc:
+//
+// [line 1] Error: unexpected character '#'
+//   |
+// 1 | var a = #;
+//   |         ^
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    // how many characters of the line the error points at
+    pub length: usize,
+    // the line of source the error is on, if there is one
+    pub code: Option<String>,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(source: &str, span: Span, message: &str) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            line: span.line,
+            column: span.column,
+            length: span.length,
+            code: source
+                .lines()
+                .nth(span.line.saturating_sub(1))
+                .map(String::from),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] Error: {}", self.line, self.message)?;
+        if let Some(code) = &self.code {
+            let gutter = " ".repeat(self.line.to_string().len());
+            writeln!(f)?;
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", self.line, code)?;
+            write!(
+                f,
+                "{} | {}{}",
+                gutter,
+                " ".repeat(self.column.saturating_sub(1)),
+                "^".repeat(self.length)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+use crate::object::Object;
+use crate::symbol::Interner;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// the heap is collected once it holds this many bytes, and after a collection once it has grown
+// to GROW_FACTOR times what survived
+const FIRST_COLLECTION: usize = 1024 * 1024;
+const GROW_FACTOR: usize = 2;
+
+// ObjRef is a handle to an object on a Heap. Values hold handles rather than pointers, so a
+// handle that outlives its object is a bug that panics instead of reading freed memory. The
+// handle carries the generation of its slot, which changes whenever the slot is reused, so an
+// old handle does not turn into a handle to the object that took its place. A slot is retired
+// before its generation would wrap around, so this holds however often slots are reused.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ObjRef {
+    pub(crate) index: u32,
+    pub(crate) generation: u32,
+}
+
+// generations wrap around at this many bits, which is what a NaN-boxed value has room for
+pub(crate) const GENERATION_BITS: u32 = 18;
+const GENERATION_MASK: u32 = (1 << GENERATION_BITS) - 1;
+
+// every heap starts its slots at a different generation, so that a handle is unlikely to be valid
+// on a heap other than its own. Unlikely is all it is: with this few bits, a handle from another
+// heap whose generations happen to overlap is taken for one of this heap.
+static HEAPS: AtomicU32 = AtomicU32::new(0);
+
+// GcStats describes the work done by the garbage collector of a heap so far
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct GcStats {
+    pub collections: usize,
+    // the objects on the heap and their size in bytes
+    pub objects: usize,
+    pub bytes: usize,
+    // everything collections have freed so far
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    // the size the heap may grow to before it is collected again
+    pub next_collection: usize,
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} collections freed {} objects ({} bytes), {} objects ({} bytes) live",
+            self.collections, self.objects_freed, self.bytes_freed, self.objects, self.bytes
+        )
+    }
+}
+
+struct Slot {
+    object: Option<Object>,
+    generation: u32,
+    marked: bool,
+    // what the object counted towards the size of the heap when it was allocated
+    size: usize,
+}
+
+// Heap owns the objects of a running program and frees them with a mark and sweep collector once
+// nothing refers to them anymore. The heap does not know what refers to its objects, so it never
+// collects by itself: at points where every live value is within reach, the owner checks
+// should_collect, marks its roots and calls collect.
+pub(crate) struct Heap {
+    slots: Vec<Slot>,
+    // the slots of freed objects, which are reused before the heap grows
+    free: Vec<u32>,
+    // the generation of new slots
+    first_generation: u32,
+    // the string objects by their contents. The table does not keep strings alive, collect
+    // removes the ones it frees.
+    strings: HashMap<Rc<str>, ObjRef>,
+    // the symbols of the names in the programs that run on the heap. Programs are scanned with
+    // them, so the names in their syntax trees key the variables and properties the runtime keeps.
+    pub(crate) symbols: Interner,
+    // marked objects whose references have not been traced yet
+    gray: Vec<ObjRef>,
+    // when set, should_collect always holds so that every allocation collects, which shakes out
+    // values that are not marked as roots
+    stress: bool,
+    // the size the heap may not grow beyond, and whether a collection found more than that still
+    // alive since the owner last looked
+    limit: Option<usize>,
+    exhausted: bool,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Heap {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            // Fibonacci hashing spreads the generations of heaps created one after another
+            first_generation: HEAPS
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_mul(0x9E37_79B9)
+                >> (32 - GENERATION_BITS),
+            strings: HashMap::new(),
+            symbols: Interner::default(),
+            gray: Vec::new(),
+            stress: false,
+            limit: None,
+            exhausted: false,
+            stats: GcStats {
+                next_collection: FIRST_COLLECTION,
+                ..GcStats::default()
+            },
+        }
+    }
+
+    pub(crate) fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub(crate) fn is_stressed(&self) -> bool {
+        self.stress
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub(crate) fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    // take_exhausted tells whether the objects still alive outgrew the limit at a collection since
+    // it was last asked. The owner turns that into an error, as the heap can not fail allocations.
+    pub(crate) fn take_exhausted(&mut self) -> bool {
+        mem::take(&mut self.exhausted)
+    }
+
+    pub(crate) fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub(crate) fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = mem::size_of::<Slot>() + object.size();
+        self.stats.objects += 1;
+        self.stats.bytes += size;
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    object: None,
+                    // the first allocation moves on to the first generation
+                    generation: self.first_generation.wrapping_sub(1) & GENERATION_MASK,
+                    marked: false,
+                    size: 0,
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.object = Some(object);
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+        slot.size = size;
+        ObjRef {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    pub(crate) fn get(&self, obj: ObjRef) -> &Object {
+        match self.slot(obj).and_then(|slot| slot.object.as_ref()) {
+            Some(object) => object,
+            None => panic!("{:?} was used after it was collected", obj),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        let slot = self.slots.get_mut(obj.index as usize);
+        let slot = slot.filter(|slot| slot.generation == obj.generation);
+        match slot.and_then(|slot| slot.object.as_mut()) {
+            Some(object) => object,
+            None => panic!("{:?} was used after it was collected", obj),
+        }
+    }
+
+    // contains tells whether value is alive on this heap. Values that are not objects always
+    // are; handles to collected objects, or to objects of another heap, are not.
+    pub(crate) fn contains(&self, value: Value) -> bool {
+        match value.as_object() {
+            Some(obj) => self.slot(obj).is_some_and(|slot| slot.object.is_some()),
+            None => true,
+        }
+    }
+
+    fn slot(&self, obj: ObjRef) -> Option<&Slot> {
+        let slot = self.slots.get(obj.index as usize)?;
+        (slot.generation == obj.generation).then_some(slot)
+    }
+
+    // alloc_string returns the string with contents val, which is only allocated if there is no
+    // such string yet
+    pub(crate) fn alloc_string(&mut self, val: &str) -> Value {
+        if let Some(obj) = self.strings.get(val) {
+            return Value::object(*obj);
+        }
+        let val: Rc<str> = val.into();
+        let obj = self.alloc(Object::String(Rc::clone(&val)));
+        self.strings.insert(val, obj);
+        Value::object(obj)
+    }
+
+    // interned gives the string with the contents val, if there is one
+    pub(crate) fn interned(&self, val: &str) -> Option<ObjRef> {
+        self.strings.get(val).copied()
+    }
+
+    // as_str returns the contents of value if it is a string
+    pub(crate) fn as_str(&self, value: Value) -> Option<&str> {
+        match self.get(value.as_object()?) {
+            Object::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    // fields gives the names and values of the fields of value, sorted by name, if it is an
+    // instance of a class of either backend
+    #[cfg(feature = "serde")]
+    pub(crate) fn fields(&self, value: Value) -> Option<Vec<(&str, Value)>> {
+        let mut fields: Vec<_> = match self.get(value.as_object()?) {
+            Object::LoxInstance(instance) => instance
+                .fields
+                .iter()
+                .map(|(name, value)| (&**self.symbols.text(*name), *value))
+                .collect(),
+            Object::Instance(instance) => match self.get(instance.shape) {
+                Object::Shape(shape) => shape
+                    .fields
+                    .iter()
+                    .map(|(name, index)| {
+                        let name = self.as_str(Value::object(*name)).unwrap_or_default();
+                        (name, instance.fields[*index])
+                    })
+                    .collect(),
+                object => panic!("{:?} is not a shape", object),
+            },
+            _ => return None,
+        };
+        fields.sort_by_key(|(name, _)| *name);
+        Some(fields)
+    }
+
+    pub(crate) fn display(&self, value: Value) -> Displayed<'_> {
+        Displayed { heap: self, value }
+    }
+
+    // type_name names the type of a value the way a Lox programmer thinks of it
+    pub(crate) fn type_name(&self, value: Value) -> &'static str {
+        if value.as_bool().is_some() {
+            return "boolean";
+        }
+        if value.as_number().is_some() {
+            return "number";
+        }
+        let Some(obj) = value.as_object() else {
+            return "nil";
+        };
+        match self.get(obj) {
+            Object::String(_) => "string",
+            Object::Native(_) | Object::NativeMethod(_) => "native function",
+            Object::LoxFunction(_)
+            | Object::Closure(_)
+            | Object::Function(_)
+            | Object::BoundMethod(_) => "function",
+            Object::LoxClass(_) | Object::Class(_) => "class",
+            Object::LoxInstance(_) | Object::Instance(_) | Object::Userdata(_) => "instance",
+            Object::Environment(_) => "environment",
+            Object::Upvalue(_) => "upvalue",
+            Object::Shape(_) => "shape",
+        }
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        self.stress || self.stats.bytes > self.stats.next_collection || self.over_limit()
+    }
+
+    pub(crate) fn mark_value(&mut self, value: Value) {
+        if let Some(obj) = value.as_object() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, obj: ObjRef) {
+        let slot = self.slots.get_mut(obj.index as usize);
+        let Some(slot) = slot.filter(|slot| slot.generation == obj.generation) else {
+            panic!("{:?} was used after it was collected", obj);
+        };
+        if !slot.marked {
+            slot.marked = true;
+            self.gray.push(obj);
+        }
+    }
+
+    // collect frees every object that is not reachable from the marked roots
+    pub(crate) fn collect(&mut self) {
+        let mut children = Vec::new();
+        while let Some(obj) = self.gray.pop() {
+            self.get(obj).trace(&mut children);
+            for child in children.drain(..) {
+                self.mark_object(child);
+            }
+        }
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.marked {
+                slot.marked = false;
+            } else if let Some(object) = slot.object.take() {
+                if let Object::String(val) = &object {
+                    self.strings.remove(val);
+                }
+                self.stats.objects -= 1;
+                self.stats.bytes -= slot.size;
+                self.stats.objects_freed += 1;
+                self.stats.bytes_freed += slot.size;
+                // a slot whose next generation would be its first again is never reused
+                if (slot.generation + 1) & GENERATION_MASK != self.first_generation {
+                    self.free.push(index as u32);
+                }
+            }
+        }
+        self.stats.collections += 1;
+        self.stats.next_collection = (self.stats.bytes * GROW_FACTOR).max(FIRST_COLLECTION);
+        self.exhausted |= self.over_limit();
+    }
+
+    fn over_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.stats.bytes > limit)
+    }
+}
+
+// Displayed shows a value the way print does, which for objects needs the heap they live on
+pub(crate) struct Displayed<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl Display for Displayed<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(val) = self.value.as_bool() {
+            write!(f, "{}", val)
+        } else if let Some(val) = self.value.as_number() {
+            write!(f, "{}", val)
+        } else if let Some(obj) = self.value.as_object() {
+            match self.heap.get(obj) {
+                Object::String(val) => write!(f, "{}", val),
+                Object::Native(_) | Object::NativeMethod(_) => write!(f, "<native fn>"),
+                Object::Userdata(userdata) => {
+                    let class = self.heap.display(Value::object(userdata.class));
+                    write!(f, "{} instance", class)
+                }
+                Object::LoxFunction(function) => {
+                    write!(f, "<fn {}>", function.declaration.name.lexeme)
+                }
+                Object::Function(function) => match &function.name {
+                    Some(name) => write!(f, "<fn {}>", name),
+                    None => write!(f, "<script>"),
+                },
+                Object::Closure(closure) => {
+                    write!(f, "{}", self.heap.display(Value::object(closure.function)))
+                }
+                Object::LoxClass(class) => write!(f, "{}", class.name),
+                Object::LoxInstance(instance) => {
+                    let class = self.heap.display(Value::object(instance.class));
+                    write!(f, "{} instance", class)
+                }
+                Object::Class(class) => {
+                    write!(f, "{}", self.heap.display(Value::object(class.name)))
+                }
+                Object::Instance(instance) => {
+                    let class = self.heap.display(Value::object(instance.class));
+                    write!(f, "{} instance", class)
+                }
+                Object::BoundMethod(bound) => {
+                    write!(f, "{}", self.heap.display(Value::object(bound.method)))
+                }
+                // never seen by programs, but the disassembler may show them
+                Object::Environment(_) => write!(f, "<environment>"),
+                Object::Upvalue(_) => write!(f, "<upvalue>"),
+                Object::Shape(_) => write!(f, "<shape>"),
+            }
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_frees_unmarked_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.alloc_string("kept");
+        heap.alloc_string("garbage");
+        heap.mark_value(kept);
+        heap.collect();
+
+        let stats = heap.stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.objects, 1);
+        assert_eq!(stats.objects_freed, 1);
+        assert_eq!(heap.as_str(kept), Some("kept"));
+
+        // marks only last for one collection
+        heap.collect();
+        assert_eq!(heap.stats().objects, 0);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("a");
+        heap.collect();
+        let b = heap.alloc_string("b");
+        assert_eq!(b.as_object().map(|obj| obj.index), Some(0));
+        assert_eq!(heap.display(b).to_string(), "b");
+
+        // but the handles to the objects that had them do not refer to the new ones
+        assert_ne!(a, b);
+        assert!(!heap.contains(a));
+        assert!(heap.contains(b));
+    }
+
+    #[test]
+    fn slots_are_retired_before_their_generation_wraps() {
+        let mut heap = Heap::new();
+        let first = heap.alloc_string("a");
+        heap.collect();
+        for _ in 1..1 << GENERATION_BITS {
+            assert_eq!(heap.alloc_string("a").as_object().unwrap().index, 0);
+            heap.collect();
+        }
+        let next = heap.alloc_string("a");
+        assert_eq!(next.as_object().unwrap().index, 1);
+        assert!(!heap.contains(first));
+    }
+
+    #[test]
+    #[should_panic(expected = "used after it was collected")]
+    fn collected_objects_can_not_be_marked() {
+        let mut heap = Heap::new();
+        let value = heap.alloc_string("a");
+        heap.collect();
+        heap.alloc_string("b");
+        heap.mark_value(value);
+    }
+
+    #[test]
+    fn handles_only_belong_to_their_heap() {
+        let mut heap = Heap::new();
+        let mut other = Heap::new();
+        let a = heap.alloc_string("a");
+        assert!(!other.contains(a));
+        other.alloc_string("b");
+        assert!(!other.contains(a));
+        assert!(other.contains(Value::from(1.0)));
+    }
+
+    #[test]
+    fn strings_are_interned() {
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("a");
+        assert_eq!(heap.alloc_string("a"), a);
+        assert_ne!(heap.alloc_string("b"), a);
+        assert_eq!(heap.stats().objects, 2);
+
+        // collected strings leave the intern table
+        heap.collect();
+        let a = heap.alloc_string("a");
+        assert_eq!(heap.as_str(a), Some("a"));
+        assert_eq!(heap.stats().objects, 1);
+    }
+
+    #[test]
+    fn stress_collects_on_every_allocation() {
+        let mut heap = Heap::new();
+        assert!(!heap.should_collect());
+        heap.set_stress(true);
+        assert!(heap.should_collect());
+    }
+
+    #[test]
+    fn heap_is_exhausted_when_live_objects_outgrow_the_limit() {
+        let mut heap = Heap::new();
+        heap.set_limit(Some(100));
+        heap.alloc_string(&"a".repeat(100));
+        assert!(heap.should_collect());
+        heap.collect();
+        assert!(!heap.take_exhausted());
+
+        let kept = heap.alloc_string(&"b".repeat(100));
+        heap.mark_value(kept);
+        heap.collect();
+        assert!(heap.take_exhausted());
+        assert!(!heap.take_exhausted());
+    }
+
+    #[test]
+    #[should_panic(expected = "used after it was collected")]
+    fn collected_objects_can_not_be_used() {
+        let mut heap = Heap::new();
+        let value = heap.alloc_string("a");
+        heap.collect();
+        heap.alloc_string("b");
+        heap.as_str(value);
+    }
+}
@@ -1,271 +1,1158 @@
-use crate::ast::Expression;
+use crate::ast::{ExprId, Expression, Function, Statement};
+use crate::compiler::CompileError;
+use crate::heap::{GcStats, Heap, ObjRef};
+use crate::native::{self, Args};
+use crate::object::{
+    Constructor, Environment, LoxClass, LoxFunction, LoxInstance, Native, Object, Userdata,
+};
+use crate::resolver::Resolver;
+use crate::symbol::Symbol;
 use crate::token::Token;
-use crate::tokentype::TokenType;
+use crate::tokentype::{Literal, TokenType};
+use crate::value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
-pub(crate) enum Error {
-    MissingToken(TokenType),
-    UnexpectedToken(Token),
+// RuntimeError is an error raised while running a program, by either the interpreter or the vm
+#[derive(Clone, PartialEq, Debug)]
+pub struct RuntimeError {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+    // the calls that were running, the innermost first
+    pub(crate) trace: Vec<StackFrame>,
+    // the limit the program ran into, if that is what stopped it
+    pub(crate) limit: Option<Limit>,
 }
 
-type Errors = Vec<Error>;
+// Limit is a resource limit that stops programs which exceed it, so untrusted programs can be run
+// without them running forever, crashing with a real stack overflow or eating all memory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Limit {
+    // the program took more steps than it was allowed
+    Steps,
+    // calls nested deeper than they were allowed, or calls and expressions nested deeper than
+    // the tree walker's stack allows
+    CallDepth,
+    // the objects the program kept alive outgrew the heap
+    Memory,
+}
+
+impl Limit {
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Limit::Steps => "Step limit exceeded.",
+            Limit::CallDepth => "Stack overflow.",
+            Limit::Memory => "Out of memory.",
+        }
+    }
+}
+
+// StackFrame is a call that was running when a runtime error happened
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StackFrame {
+    // the name of the function, None for the top level code of the script
+    pub function: Option<String>,
+    // the line the call had got to
+    pub line: usize,
+}
+
+impl RuntimeError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn trace(&self) -> &[StackFrame] {
+        &self.trace
+    }
+
+    // limit tells which limit stopped the program, if it did not fail by itself
+    pub fn limit(&self) -> Option<Limit> {
+        self.limit
+    }
+
+    fn new(token: &Token, message: &str) -> RuntimeError {
+        RuntimeError::at_line(token.line, message)
+    }
+
+    pub(crate) fn at_line(line: usize, message: &str) -> RuntimeError {
+        RuntimeError {
+            line,
+            message: message.to_string(),
+            trace: Vec::new(),
+            limit: None,
+        }
+    }
+
+    pub(crate) fn exceeded(line: usize, limit: Limit) -> RuntimeError {
+        RuntimeError {
+            limit: Some(limit),
+            ..RuntimeError::at_line(line, limit.message())
+        }
+    }
+}
 
-pub struct Interpreter {
-    tokens: Vec<Token>,
-    current: usize,
+fn undefined(name: &Token) -> RuntimeError {
+    RuntimeError::new(name, &format!("Undefined variable '{}'.", name.lexeme))
+}
+
+fn undefined_property(name: &Token) -> RuntimeError {
+    RuntimeError::new(name, &format!("Undefined property '{}'.", name.lexeme))
+}
+
+// stack_address is where the Rust stack of the current thread is at
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+fn check_arity(paren: &Token, arity: usize, arguments: usize) -> Result<(), RuntimeError> {
+    if arity == arguments {
+        return Ok(());
+    }
+    let message = format!("Expected {} arguments but got {}.", arity, arguments);
+    Err(RuntimeError::new(paren, &message))
+}
+
+// Output is where print writes to, shared by both backends of a Lox
+pub(crate) type Output = Rc<RefCell<dyn Write>>;
+
+// stdout is the output programs print to unless the embedder sets another
+pub(crate) fn stdout() -> Output {
+    Rc::new(RefCell::new(io::stdout()))
+}
+
+// natives are the functions built into both backends
+pub(crate) fn natives() -> Vec<Native> {
+    vec![Native {
+        name: "clock".into(),
+        arity: 0,
+        function: Rc::new(clock),
+    }]
+}
+
+// clock returns the number of seconds since the epoch, for timing programs
+fn clock(_: &mut Args) -> Result<Value, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    Ok(Value::from(now.map_or(0.0, |now| now.as_secs_f64())))
+}
+
+// print_error is the message of a print that could not write to the output
+pub(crate) fn print_error(error: io::Error) -> String {
+    format!("Could not print: {}.", error)
+}
+
+// a runtime error shows the calls that were running like clox does:
+//
+// Operand must be a number.
+// [line 2] in f()
+// [line 4] in script
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if self.trace.is_empty() {
+            return write!(f, "\n[line {}]", self.line);
+        }
+        for frame in &self.trace {
+            match &frame.function {
+                Some(function) => write!(f, "\n[line {}] in {}()", frame.line, function)?,
+                None => write!(f, "\n[line {}] in script", frame.line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// the deepest calls may nest by default before a program is stopped with a stack overflow
+pub(crate) const MAX_CALL_DEPTH: usize = 64;
+
+// how many bytes of the Rust stack a program may take. The tree-walker recurses for every call and
+// every nested expression, so calls inside deeply nested expressions could overflow the stack long
+// before they nest as deep as they may. Threads get 2 MiB of stack unless they ask for more, this
+// leaves half of that to whatever runs the interpreter.
+const STACK_BUDGET: usize = 1024 * 1024;
+
+// how many links of a chain evaluate recurses into before it walks the rest with a loop, which
+// saves short chains like `a.b()` from collecting their links first
+const CHAIN_RECURSION: usize = 8;
+
+// Interrupt is why execution of a statement stopped early: a return statement unwinding to its
+// call, or a runtime error unwinding all the way out
+enum Interrupt {
+    Return(Value),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Interrupt {
+    fn from(error: RuntimeError) -> Self {
+        Interrupt::Error(error)
+    }
+}
+
+// Interpreter runs programs by walking the tree produced by the parser. Variables live in
+// environments on the heap, one for every block and call, so closures can hold on to them.
+pub(crate) struct Interpreter {
+    // the number of steps taken by the current call to interpret, and how many it may take before
+    // we give up on it. This keeps untrusted input from running forever.
+    steps: usize,
+    step_limit: Option<usize>,
+    max_call_depth: usize,
+    pub(crate) heap: Heap,
+    // the environment of the globals, and of the scope we are running in
+    globals: ObjRef,
+    environment: ObjRef,
+    // how many environments out the resolver found each local variable
+    locals: HashMap<ExprId, usize>,
+    // the functions we are in and the lines they were called at, the innermost last
+    calls: Vec<(Rc<str>, usize)>,
+    // values that are only held by Rust locals while evaluating something else, which the
+    // garbage collector must not free
+    temps: Vec<Value>,
+    // where the Rust stack was when the current call to interpret started, see STACK_BUDGET
+    stack_base: usize,
+    output: Output,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
 }
 
 impl Interpreter {
-    pub(crate) fn new(tokens: Vec<Token>) -> Interpreter {
-        Interpreter { tokens, current: 0 }
+    pub(crate) fn new() -> Interpreter {
+        let mut heap = Heap::new();
+        let globals = heap.alloc(Object::Environment(Environment::default()));
+        let mut interpreter = Interpreter {
+            steps: 0,
+            step_limit: None,
+            max_call_depth: MAX_CALL_DEPTH,
+            heap,
+            globals,
+            environment: globals,
+            locals: HashMap::new(),
+            calls: Vec::new(),
+            temps: Vec::new(),
+            stack_base: 0,
+            output: stdout(),
+        };
+        for native in natives() {
+            interpreter.define_native(native);
+        }
+        interpreter
     }
 
-    pub(crate) fn parse(&mut self) -> Result<Expression, Errors> {
-        self.expression()
+    // define_class makes a class whose instances constructor makes a global
+    pub(crate) fn define_class(&mut self, name: Rc<str>, constructor: Constructor) {
+        let symbol = self.heap.symbols.intern(&name);
+        let class = self.alloc(Object::LoxClass(LoxClass {
+            name,
+            methods: HashMap::new(),
+            constructor: Some(constructor),
+        }));
+        self.environment_mut(self.globals)
+            .values
+            .insert(symbol, Value::object(class));
     }
 
-    fn expression(&mut self) -> Result<Expression, Errors> {
-        self.equality()
+    // define_native makes native a global
+    pub(crate) fn define_native(&mut self, native: Native) {
+        let name = self.heap.symbols.intern(&native.name);
+        let native = self.alloc(Object::Native(native));
+        self.environment_mut(self.globals)
+            .values
+            .insert(name, Value::object(native));
     }
 
-    fn equality(&mut self) -> Result<Expression, Errors> {
-        // if our 'left' side is an error, parse the right and try to find errors there
-        let mut expr = self.comparison();
-        //TODO move the is_err part to a separate function
-        if expr.is_err() {
-            let mut errors = expr.unwrap_err();
-            // move to the next good spot to start parsing
-            self.synchronize();
-            // parse the next part
-            let right = self.expression();
-            // add any errors from the right part to the current errors
-            if right.is_err() {
-                for error in right.unwrap_err() {
-                    errors.push(error)
-                }
+    pub(crate) fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // globals gives the names and values of the global variables
+    pub(crate) fn globals(&self) -> Vec<(String, Value)> {
+        match self.heap.get(self.globals) {
+            Object::Environment(globals) => globals
+                .values
+                .iter()
+                .map(|(name, value)| (self.heap.symbols.text(*name).to_string(), *value))
+                .collect(),
+            object => panic!("{:?} is not an environment", object),
+        }
+    }
+
+    // reset forgets every global and everything programs allocated, but keeps the settings
+    pub(crate) fn reset(&mut self) {
+        let mut fresh = Interpreter::new();
+        fresh.step_limit = self.step_limit;
+        fresh.max_call_depth = self.max_call_depth;
+        fresh.heap.set_stress(self.heap.is_stressed());
+        fresh.heap.set_limit(self.heap.limit());
+        fresh.output = Rc::clone(&self.output);
+        *self = fresh;
+    }
+
+    // set_output makes print write to output
+    pub(crate) fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    // set_gc_stress makes every allocation collect garbage, for testing the collector
+    pub(crate) fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    // set_step_limit limits how many statements and expressions a single call to interpret may
+    // evaluate, None means there is no limit
+    pub(crate) fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    // set_max_call_depth sets how deep calls may nest. Every call recurses on the Rust stack, so
+    // deep calls can also run out of STACK_BUDGET first, which stops them with the same limit.
+    pub(crate) fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    // set_heap_limit limits how many bytes the objects programs keep alive may take, None means
+    // there is no limit
+    pub(crate) fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap.set_limit(limit);
+    }
+
+    // resolve runs the resolver over a program before it is interpreted
+    pub(crate) fn resolve(&mut self, statements: &[Statement]) -> Result<(), Vec<CompileError>> {
+        Resolver::resolve(statements, &mut self.locals)
+    }
+
+    // interpret runs a program and gives the value of its last statement if that is an expression
+    // statement, or nil
+    pub(crate) fn interpret(&mut self, statements: &[Statement]) -> Result<Value, RuntimeError> {
+        self.steps = 0;
+        self.stack_base = stack_address();
+        self.temps.clear();
+        self.calls.clear();
+        self.environment = self.globals;
+        let (last, statements) = match statements.split_last() {
+            Some((Statement::Expression { expr }, rest)) => (Some(expr), rest),
+            _ => (None, statements),
+        };
+        for statement in statements {
+            match self.execute(statement) {
+                Ok(()) => {}
+                // the resolver does not let return statements outside of functions through
+                Err(Interrupt::Return(_)) => return Ok(Value::NIL),
+                Err(Interrupt::Error(error)) => return Err(self.traced(error)),
             }
-            return Err(errors);
         }
+        match last {
+            Some(expr) => self.evaluate(expr).map_err(|error| self.traced(error)),
+            None => Ok(Value::NIL),
+        }
+    }
 
-        let mut res = expr.unwrap();
+    // call_function calls a function or class from Rust, with arguments the caller keeps rooted
+    // until it returns. The stack trace of an error leaves out the caller.
+    pub(crate) fn call_function(
+        &mut self,
+        callee: Value,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        self.steps = 0;
+        self.stack_base = stack_address();
+        self.temps.clear();
+        self.calls.clear();
+        self.environment = self.globals;
+        self.temps.push(callee);
+        self.temps.extend_from_slice(arguments);
+        let paren = Token::new(TokenType::RightParen, ")", None, 0, 0);
+        let result = self.call(callee, &paren, arguments);
+        self.temps.clear();
+        result.map_err(|error| {
+            let mut error = self.traced(error);
+            error.trace.pop();
+            error
+        })
+    }
 
-        while self.check_and_consume(&[TokenType::BangEqual, TokenType::EqualEqual]) {
-            let operator = self.previous().clone();
-            // since left is known to be error free, we can parse right and return the error if we find one
-            let right = self.comparison()?;
-            res = Expression::Binary {
-                left: Box::new(res),
-                operator,
-                right: Box::new(right),
-            };
+    // global gives the value of a global variable
+    pub(crate) fn global(&self, name: &str) -> Option<Value> {
+        let globals = self.environment(self.globals);
+        globals.values.get(&self.heap.symbols.get(name)?).copied()
+    }
+
+    // traced gives error the calls that are running, unless a call it unwound out of already did
+    fn traced(&self, mut error: RuntimeError) -> RuntimeError {
+        if !error.trace.is_empty() {
+            return error;
         }
-        Ok(res)
+        let mut line = error.line;
+        for (function, call) in self.calls.iter().rev() {
+            error.trace.push(StackFrame {
+                function: Some(function.to_string()),
+                line,
+            });
+            line = *call;
+        }
+        error.trace.push(StackFrame {
+            function: None,
+            line,
+        });
+        error
     }
 
-    fn comparison(&mut self) -> Result<Expression, Errors> {
-        let mut expr = self.term();
-        if expr.is_err() {
-            let mut errors = expr.unwrap_err();
-            self.synchronize();
-            let right = self.expression();
-            if right.is_err() {
-                for error in right.unwrap_err() {
-                    errors.push(error)
+    fn execute(&mut self, statement: &Statement) -> Result<(), Interrupt> {
+        match statement {
+            Statement::Block { statements } => {
+                let environment = Environment {
+                    enclosing: Some(self.environment),
+                    ..Environment::default()
+                };
+                let environment = self.alloc(Object::Environment(environment));
+                self.execute_block(statements, environment)?;
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods)?,
+            Statement::Expression { expr } => {
+                self.evaluate(expr)?;
+            }
+            Statement::Function(declaration) => {
+                let function = LoxFunction {
+                    declaration: Rc::clone(declaration),
+                    closure: self.environment,
+                    is_initializer: false,
+                };
+                let function = self.alloc(Object::LoxFunction(function));
+                self.define(declaration.name.symbol, Value::object(function));
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.execute(then_branch)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)?;
+                }
+            }
+            Statement::Print { expr } => {
+                let value = self.evaluate(expr)?;
+                let printed = writeln!(self.output.borrow_mut(), "{}", self.heap.display(value));
+                printed.map_err(|error| RuntimeError::new(expr.token(), &print_error(error)))?;
+            }
+            Statement::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::NIL,
+                };
+                return Err(Interrupt::Return(value));
+            }
+            Statement::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::NIL,
+                };
+                self.define(name.symbol, value);
+            }
+            Statement::While { condition, body } => {
+                while self.evaluate(condition)?.is_truthy() {
+                    self.execute(body)?;
                 }
             }
-            return Err(errors);
         }
+        Ok(())
+    }
 
-        let mut res = expr.unwrap();
-
-        while self.check_and_consume(&[
-            TokenType::Greater,
-            TokenType::GreaterEqual,
-            TokenType::Less,
-            TokenType::LessEqual,
-        ]) {
-            let operator = self.previous().clone();
-            let right = self.term()?;
-            res = Expression::Binary {
-                left: Box::new(res),
-                operator,
-                right: Box::new(right),
+    // class declares a class. Its methods close over an environment declaring super if it has a
+    // superclass.
+    fn class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expression>,
+        declarations: &[Rc<Function>],
+    ) -> Result<(), RuntimeError> {
+        // the superclass and the methods are rooted until the class holds them
+        let temps = self.temps.len();
+        let mut methods = HashMap::new();
+        let mut constructor = None;
+        if let Some(superclass) = superclass {
+            let value = self.evaluate(superclass)?;
+            match value.as_object().map(|obj| self.heap.get(obj)) {
+                Some(Object::LoxClass(class)) => {
+                    methods = class.methods.clone();
+                    constructor = class.constructor.clone();
+                }
+                _ => {
+                    let token = match superclass {
+                        Expression::Variable { name, .. } => name,
+                        _ => name,
+                    };
+                    return Err(RuntimeError::new(token, "Superclass must be a class."));
+                }
             }
+            self.temps.push(value);
+            let mut environment = Environment {
+                enclosing: Some(self.environment),
+                ..Environment::default()
+            };
+            environment.values.insert(Symbol::SUPER, value);
+            let environment = self.alloc(Object::Environment(environment));
+            self.temps.push(Value::object(self.environment));
+            self.environment = environment;
         }
-        return Ok(res);
+        for declaration in declarations {
+            let method = LoxFunction {
+                declaration: Rc::clone(declaration),
+                closure: self.environment,
+                is_initializer: declaration.name.symbol == Symbol::INIT,
+            };
+            let method = self.alloc(Object::LoxFunction(method));
+            self.temps.push(Value::object(method));
+            methods.insert(declaration.name.symbol, method);
+        }
+        let class = self.alloc(Object::LoxClass(LoxClass {
+            name: name.lexeme.clone(),
+            methods,
+            constructor,
+        }));
+        if superclass.is_some() {
+            self.environment = self
+                .environment(self.environment)
+                .enclosing
+                .unwrap_or(self.globals);
+        }
+        self.temps.truncate(temps);
+        self.define(name.symbol, Value::object(class));
+        Ok(())
+    }
+
+    // execute_block runs statements in environment, and returns to the current environment
+    // afterwards however they finish
+    fn execute_block(
+        &mut self,
+        statements: &[Statement],
+        environment: ObjRef,
+    ) -> Result<(), Interrupt> {
+        let previous = self.environment;
+        self.temps.push(Value::object(previous));
+        self.environment = environment;
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+        self.environment = previous;
+        self.temps.pop();
+        result
     }
 
-    fn term(&mut self) -> Result<Expression, Errors> {
-        let mut expr = self.factor();
-        if expr.is_err() {
-            let mut errors = expr.unwrap_err();
-            self.synchronize();
-            let right = self.expression();
-            if right.is_err() {
-                for error in right.unwrap_err() {
-                    errors.push(error)
+    fn evaluate(&mut self, expr: &Expression) -> Result<Value, RuntimeError> {
+        // every call evaluates an expression, so this bounds calls and expressions together
+        if stack_address().abs_diff(self.stack_base) > STACK_BUDGET {
+            return Err(RuntimeError::exceeded(expr.token().line, Limit::CallDepth));
+        }
+        self.chain(expr, CHAIN_RECURSION)
+    }
+
+    // chain evaluates an expression that may be a link of a chain, see Expression::left. The first
+    // few links recurse into their left operand, the rest of a longer chain is walked with a loop.
+    // Either way every link counts a step before its left operand is evaluated.
+    fn chain(&mut self, expr: &Expression, recursion: usize) -> Result<Value, RuntimeError> {
+        match expr.left() {
+            None => self.operand(expr),
+            Some(left) if recursion > 0 => {
+                self.step(expr.token())?;
+                let left = self.chain(left, recursion - 1)?;
+                self.link(expr, left)
+            }
+            Some(_) => {
+                let (innermost, links) = expr.chain();
+                for link in links.iter().rev() {
+                    self.step(link.token())?;
+                }
+                let mut value = self.operand(innermost)?;
+                for link in links {
+                    value = self.link(link, value)?;
                 }
+                Ok(value)
             }
-            return Err(errors);
         }
+    }
 
-        let mut res = expr.unwrap();
+    // link evaluates a link of a chain, given the value of its left operand
+    fn link(&mut self, expr: &Expression, left: Value) -> Result<Value, RuntimeError> {
+        match expr {
+            Expression::Binary {
+                operator, right, ..
+            } => {
+                let right = self.with_root(left, |this| this.evaluate(right))?;
+                self.binary(left, operator, right)
+            }
+            Expression::Logical {
+                operator, right, ..
+            } => {
+                let short_circuits = match operator.kind {
+                    TokenType::Or => left.is_truthy(),
+                    _ => !left.is_truthy(),
+                };
+                if short_circuits {
+                    return Ok(left);
+                }
+                self.evaluate(right)
+            }
+            Expression::Call {
+                paren, arguments, ..
+            } => {
+                let callee = left;
+                // the callee and the arguments evaluated so far are rooted until the call is done
+                let temps = self.temps.len();
+                self.temps.push(callee);
+                let mut values = Vec::with_capacity(arguments.len());
+                for argument in arguments {
+                    match self.evaluate(argument) {
+                        Ok(value) => {
+                            self.temps.push(value);
+                            values.push(value);
+                        }
+                        Err(error) => {
+                            self.temps.truncate(temps);
+                            return Err(error);
+                        }
+                    }
+                }
+                let result = self.call(callee, paren, &values);
+                self.temps.truncate(temps);
+                result
+            }
+            Expression::Get { name, .. } => self.with_root(left, |this| this.get(left, name)),
+            _ => unreachable!("chains only hold links"),
+        }
+    }
 
-        while self.check_and_consume(&[TokenType::Minus, TokenType::Plus]) {
-            let operator = self.previous().clone();
-            let right = self.factor()?;
-            res = Expression::Binary {
-                left: Box::new(res),
-                operator,
-                right: Box::new(right),
+    // operand evaluates an expression that is not a link of a chain
+    fn operand(&mut self, expr: &Expression) -> Result<Value, RuntimeError> {
+        match expr {
+            Expression::Literal { value } => {
+                self.step(value)?;
+                self.literal(value)
+            }
+            Expression::Grouping { expr } => self.evaluate(expr),
+            Expression::Unary { operator, right } => {
+                self.step(operator)?;
+                let right = self.evaluate(right)?;
+                self.unary(operator, right)
+            }
+            Expression::Variable { id, name } => {
+                self.step(name)?;
+                self.look_up(*id, name)
             }
+            Expression::Assign { id, name, expr } => {
+                self.step(name)?;
+                let value = self.evaluate(expr)?;
+                self.assign(*id, name, value)?;
+                Ok(value)
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.step(name)?;
+                let object = self.evaluate(object)?;
+                let value = self.with_root(object, |this| this.evaluate(value))?;
+                if let Some(userdata) = native::userdata(&self.heap, object) {
+                    return native::set_property(&mut self.heap, &userdata, &name.lexeme, value)
+                        .map(|()| value)
+                        .map_err(|message| RuntimeError::new(name, &message));
+                }
+                match object.as_object().map(|obj| self.heap.get_mut(obj)) {
+                    Some(Object::LoxInstance(instance)) => {
+                        instance.fields.insert(name.symbol, value);
+                        Ok(value)
+                    }
+                    _ => Err(RuntimeError::new(name, "Only instances have fields.")),
+                }
+            }
+            Expression::Super {
+                id,
+                keyword,
+                method,
+            } => {
+                self.step(keyword)?;
+                // this is declared in the environment right inside the one declaring super
+                let depth = self.locals.get(id).copied().unwrap_or_default();
+                let superclass = self.environment(self.ancestor(depth)).values[&keyword.symbol];
+                let this = self.ancestor(depth.saturating_sub(1));
+                let this = self.environment(this).values[&Symbol::THIS];
+                let found = match superclass.as_object().map(|obj| self.heap.get(obj)) {
+                    Some(Object::LoxClass(class)) => class.methods.get(&method.symbol).copied(),
+                    _ => None,
+                };
+                match found {
+                    Some(found) => Ok(self.bind(found, this)),
+                    None => Err(undefined_property(method)),
+                }
+            }
+            Expression::This { id, keyword } => {
+                self.step(keyword)?;
+                self.look_up(*id, keyword)
+            }
+            Expression::Binary { .. }
+            | Expression::Call { .. }
+            | Expression::Get { .. }
+            | Expression::Logical { .. } => unreachable!("links are evaluated by chain"),
         }
-        return Ok(res);
     }
 
-    fn factor(&mut self) -> Result<Expression, Errors> {
-        let expr = self.unary();
-        if expr.is_err() {
-            let mut errors = expr.unwrap_err();
-            self.synchronize();
-            let right = self.expression();
-            if right.is_err() {
-                for error in right.unwrap_err() {
-                    errors.push(error)
+    // get looks a property up on an instance: a field, or else a method bound to the instance. The
+    // instances of native classes have the properties of their Rust object instead of fields.
+    fn get(&mut self, object: Value, name: &Token) -> Result<Value, RuntimeError> {
+        let class = match object.as_object().map(|obj| self.heap.get(obj)) {
+            Some(Object::LoxInstance(instance)) => {
+                if let Some(value) = instance.fields.get(&name.symbol) {
+                    return Ok(*value);
+                }
+                instance.class
+            }
+            Some(Object::Userdata(userdata)) => {
+                let (class, userdata) = (userdata.class, Rc::clone(&userdata.object));
+                let property =
+                    native::get_property(&mut self.heap, object, &userdata, &name.lexeme);
+                if let Some(value) = property {
+                    return Ok(value);
                 }
+                class
             }
-            return Err(errors);
+            _ => return Err(RuntimeError::new(name, "Only instances have properties.")),
+        };
+        let method = match self.heap.get(class) {
+            Object::LoxClass(class) => class.methods.get(&name.symbol).copied(),
+            object => panic!("{:?} is not a class", object),
+        };
+        match method {
+            Some(method) => Ok(self.bind(method, object)),
+            None => Err(undefined_property(name)),
         }
+    }
 
-        let mut res = expr.unwrap();
+    // bind returns a copy of method whose environment declares this as instance. Both must be
+    // rooted.
+    fn bind(&mut self, method: ObjRef, instance: Value) -> Value {
+        let (declaration, closure, is_initializer) = match self.heap.get(method) {
+            Object::LoxFunction(function) => (
+                Rc::clone(&function.declaration),
+                function.closure,
+                function.is_initializer,
+            ),
+            object => panic!("{:?} is not a function", object),
+        };
+        let mut environment = Environment {
+            enclosing: Some(closure),
+            ..Environment::default()
+        };
+        environment.values.insert(Symbol::THIS, instance);
+        let environment = self.alloc(Object::Environment(environment));
+        let function = self.with_root(Value::object(environment), |this| {
+            this.alloc(Object::LoxFunction(LoxFunction {
+                declaration,
+                closure: environment,
+                is_initializer,
+            }))
+        });
+        Value::object(function)
+    }
 
-        while self.check_and_consume(&[TokenType::Slash, TokenType::Star]) {
-            let operator = self.previous().clone();
-            let right = self.unary()?;
-            res = Expression::Binary {
-                left: Box::new(res),
-                operator,
-                right: Box::new(right),
+    fn call(
+        &mut self,
+        callee: Value,
+        paren: &Token,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        let object = callee.as_object().map(|obj| self.heap.get(obj));
+        let (declaration, closure, is_initializer) = match object {
+            Some(Object::LoxFunction(function)) => (
+                Rc::clone(&function.declaration),
+                function.closure,
+                function.is_initializer,
+            ),
+            Some(Object::Native(native)) => {
+                check_arity(paren, native.arity, arguments.len())?;
+                let function = Rc::clone(&native.function);
+                return function(&mut Args::new(&mut self.heap, arguments))
+                    .map_err(|message| RuntimeError::new(paren, &message));
+            }
+            Some(Object::NativeMethod(_)) => {
+                let method = callee.as_object().unwrap();
+                return native::call_method(&mut self.heap, method, arguments)
+                    .map_err(|message| RuntimeError::new(paren, &message));
+            }
+            Some(Object::LoxClass(class)) => {
+                let initializer = class.methods.get(&Symbol::INIT).copied();
+                let constructor = class.constructor.clone();
+                let class = callee.as_object().unwrap();
+                // the classes the embedder defines make their instances with the Rust
+                // constructor, which gets the arguments an initializer of a subclass gets too
+                let instance = match constructor {
+                    Some(constructor) => {
+                        check_arity(paren, constructor.arity, arguments.len())?;
+                        let object =
+                            (constructor.function)(&mut Args::new(&mut self.heap, arguments))
+                                .map_err(|message| RuntimeError::new(paren, &message))?;
+                        self.alloc(Object::Userdata(Userdata { class, object }))
+                    }
+                    None => {
+                        if initializer.is_none() {
+                            check_arity(paren, 0, arguments.len())?;
+                        }
+                        self.alloc(Object::LoxInstance(LoxInstance {
+                            class,
+                            fields: HashMap::new(),
+                        }))
+                    }
+                };
+                let instance = Value::object(instance);
+                if let Some(initializer) = initializer {
+                    self.with_root(instance, |this| {
+                        let initializer = this.bind(initializer, instance);
+                        this.with_root(initializer, |this| this.call(initializer, paren, arguments))
+                    })?;
+                }
+                return Ok(instance);
+            }
+            _ => {
+                return Err(RuntimeError::new(
+                    paren,
+                    "Can only call functions and classes.",
+                ))
             }
+        };
+        check_arity(paren, declaration.params.len(), arguments.len())?;
+        if self.calls.len() >= self.max_call_depth {
+            return Err(RuntimeError::exceeded(paren.line, Limit::CallDepth));
+        }
+
+        let mut environment = Environment {
+            enclosing: Some(closure),
+            ..Environment::default()
+        };
+        for (param, argument) in declaration.params.iter().zip(arguments) {
+            environment.values.insert(param.symbol, *argument);
+        }
+        let environment = self.alloc(Object::Environment(environment));
+        self.calls
+            .push((declaration.name.lexeme.clone(), paren.line));
+        let result = self.execute_block(&declaration.body, environment);
+        let result = match result {
+            Err(Interrupt::Error(error)) => Err(Interrupt::Error(self.traced(error))),
+            result => result,
+        };
+        self.calls.pop();
+        match result {
+            Err(Interrupt::Error(error)) => Err(error),
+            // the environment of a bound initializer declares this
+            _ if is_initializer => Ok(self.environment(closure).values[&Symbol::THIS]),
+            Ok(()) => Ok(Value::NIL),
+            Err(Interrupt::Return(value)) => Ok(value),
         }
-        return Ok(res);
     }
 
-    fn unary(&mut self) -> Result<Expression, Errors> {
-        if self.check_and_consume(&[TokenType::Bang, TokenType::Minus]) {
-            let operator = self.previous().clone();
-            let right = self.unary()?;
-            return Ok(Expression::Unary {
-                operator,
-                right: Box::new(right),
-            });
+    // define declares a variable in the current environment
+    fn define(&mut self, name: Symbol, value: Value) {
+        self.environment_mut(self.environment)
+            .values
+            .insert(name, value);
+    }
+
+    fn look_up(&self, id: ExprId, name: &Token) -> Result<Value, RuntimeError> {
+        let environment = self.environment(self.resolved(id));
+        match environment.values.get(&name.symbol) {
+            Some(value) => Ok(*value),
+            None => Err(undefined(name)),
         }
+    }
 
-        return self.primary();
+    fn assign(&mut self, id: ExprId, name: &Token, value: Value) -> Result<(), RuntimeError> {
+        let environment = self.resolved(id);
+        match self
+            .environment_mut(environment)
+            .values
+            .get_mut(&name.symbol)
+        {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(undefined(name)),
+        }
     }
 
-    fn primary(&mut self) -> Result<Expression, Errors> {
-        if self.check_and_consume(&[TokenType::False]) {
-            return Ok(Expression::Literal {
-                value: self.previous().clone(),
-            });
+    // resolved returns the environment a variable lives in: as many environments out as the
+    // resolver found it, or the globals if it did not
+    fn resolved(&self, id: ExprId) -> ObjRef {
+        match self.locals.get(&id) {
+            Some(depth) => self.ancestor(*depth),
+            None => self.globals,
         }
-        if self.check_and_consume(&[TokenType::True]) {
-            return Ok(Expression::Literal {
-                value: self.previous().clone(),
-            });
+    }
+
+    // ancestor returns the environment depth environments out of the current one
+    fn ancestor(&self, depth: usize) -> ObjRef {
+        let mut environment = self.environment;
+        for _ in 0..depth {
+            environment = self
+                .environment(environment)
+                .enclosing
+                .unwrap_or(self.globals);
         }
-        if self.check_and_consume(&[TokenType::Nil]) {
-            return Ok(Expression::Literal {
-                value: self.previous().clone(),
-            });
+        environment
+    }
+
+    fn environment(&self, obj: ObjRef) -> &Environment {
+        match self.heap.get(obj) {
+            Object::Environment(environment) => environment,
+            object => panic!("{:?} is not an environment", object),
         }
+    }
 
-        if self.check_and_consume(&[TokenType::Number, TokenType::String]) {
-            return Ok(Expression::Literal {
-                value: self.previous().clone(),
-            });
+    fn environment_mut(&mut self, obj: ObjRef) -> &mut Environment {
+        match self.heap.get_mut(obj) {
+            Object::Environment(environment) => environment,
+            object => panic!("{:?} is not an environment", object),
         }
+    }
 
-        if self.check_and_consume(&[TokenType::LeftParen]) {
-            let expr = self.expression()?;
-            self.consume(TokenType::RightParen)?;
-            return Ok(Expression::Grouping {
-                expr: Box::new(expr),
-            });
+    // step counts a step of the program, and stops it if it went over a limit
+    fn step(&mut self, token: &Token) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        if self.step_limit.is_some_and(|limit| self.steps > limit) {
+            return Err(RuntimeError::exceeded(token.line, Limit::Steps));
         }
-        Err(vec![Error::UnexpectedToken(self.peek().clone())])
+        if self.heap.take_exhausted() {
+            return Err(RuntimeError::exceeded(token.line, Limit::Memory));
+        }
+        Ok(())
+    }
+
+    // with_root keeps value alive while f runs
+    fn with_root<T>(&mut self, value: Value, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.temps.push(value);
+        let result = f(self);
+        self.temps.pop();
+        result
+    }
+
+    // alloc puts an object on the heap, collecting garbage first if it is time to. Everything the
+    // new object refers to must be rooted.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        self.collect_if_due();
+        self.heap.alloc(object)
+    }
+
+    // alloc_string puts a string on the heap, collecting garbage first if it is time to
+    fn alloc_string(&mut self, val: &str) -> Value {
+        self.collect_if_due();
+        self.heap.alloc_string(val)
     }
 
-    // same as match from the book, however match is reserved in rust
-    // checks whether any of the token_types match the current token
-    fn check_and_consume(&mut self, token_types: &[TokenType]) -> bool {
-        for tt in token_types {
-            if self.check(tt) {
-                self.advance();
-                return true;
+    fn collect_if_due(&mut self) {
+        if self.heap.should_collect() {
+            self.heap.mark_object(self.globals);
+            self.heap.mark_object(self.environment);
+            for value in &self.temps {
+                self.heap.mark_value(*value);
             }
+            self.heap.collect();
         }
-        false
     }
 
-    fn check(&self, token_type: &TokenType) -> bool {
-        if self.is_at_end() {
-            return false;
+    fn literal(&mut self, token: &Token) -> Result<Value, RuntimeError> {
+        match (token.kind, &token.literal) {
+            (TokenType::Nil, _) => Ok(Value::NIL),
+            (TokenType::True, _) => Ok(Value::from(true)),
+            (TokenType::False, _) => Ok(Value::from(false)),
+            (_, Some(Literal::Number(val))) => Ok(Value::from(*val)),
+            (_, Some(Literal::String(val))) => Ok(self.alloc_string(val)),
+            _ => Err(RuntimeError::new(token, "Invalid literal.")),
         }
-        &self.peek().kind == token_type
     }
 
-    fn consume(&mut self, expected: TokenType) -> Result<&Token, Errors> {
-        if self.peek().kind == expected {
-            return Ok(self.advance());
+    fn unary(&self, operator: &Token, right: Value) -> Result<Value, RuntimeError> {
+        match (operator.kind, right.as_number()) {
+            (TokenType::Minus, Some(val)) => Ok(Value::from(-val)),
+            (TokenType::Minus, None) => {
+                Err(RuntimeError::new(operator, "Operand must be a number."))
+            }
+            (TokenType::Bang, _) => Ok(Value::from(!right.is_truthy())),
+            _ => Err(RuntimeError::new(operator, "Invalid unary operator.")),
         }
-        Err(vec![Error::MissingToken(expected)])
     }
 
-    fn synchronize(&mut self) {
-        self.advance();
+    fn binary(
+        &mut self,
+        left: Value,
+        operator: &Token,
+        right: Value,
+    ) -> Result<Value, RuntimeError> {
+        if let (TokenType::Plus, Some(left), Some(right)) = (
+            operator.kind,
+            self.heap.as_str(left),
+            self.heap.as_str(right),
+        ) {
+            let val = format!("{}{}", left, right);
+            return Ok(self.alloc_string(&val));
+        }
+        match (operator.kind, left.as_number(), right.as_number()) {
+            (TokenType::EqualEqual, _, _) => Ok(Value::from(left == right)),
+            (TokenType::BangEqual, _, _) => Ok(Value::from(left != right)),
+            (TokenType::Plus, Some(left), Some(right)) => Ok(Value::from(left + right)),
+            (TokenType::Plus, _, _) => Err(RuntimeError::new(
+                operator,
+                "Operands must be two numbers or two strings.",
+            )),
+            (kind, Some(left), Some(right)) => match kind {
+                TokenType::Minus => Ok(Value::from(left - right)),
+                TokenType::Star => Ok(Value::from(left * right)),
+                TokenType::Slash => Ok(Value::from(left / right)),
+                TokenType::Greater => Ok(Value::from(left > right)),
+                TokenType::GreaterEqual => Ok(Value::from(left >= right)),
+                TokenType::Less => Ok(Value::from(left < right)),
+                TokenType::LessEqual => Ok(Value::from(left <= right)),
+                _ => Err(RuntimeError::new(operator, "Invalid binary operator.")),
+            },
+            _ => Err(RuntimeError::new(operator, "Operands must be numbers.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use std::mem;
+
+    // parse scans source with the symbols of the heap of interpreter, like Lox does
+    fn parse(interpreter: &mut Interpreter, source: &str) -> Vec<Statement> {
+        let symbols = mem::take(&mut interpreter.heap.symbols);
+        let mut scanner = Scanner::with_symbols(source, symbols);
+        scanner.scan_tokens();
+        let (tokens, symbols) = scanner.into_parts();
+        interpreter.heap.symbols = symbols;
+        Parser::new(tokens).parse().unwrap()
+    }
 
-        while !self.is_at_end() {
-            if self.previous().kind == TokenType::Semicolon {
-                return;
+    // evaluate returns the value of a single expression the way print would show it
+    fn evaluate(source: &str) -> Result<String, RuntimeError> {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_gc_stress(true);
+        match parse(&mut interpreter, &format!("{};", source)).as_slice() {
+            statements @ [Statement::Expression { .. }] => {
+                let value = interpreter.interpret(statements)?;
+                Ok(interpreter.heap.display(value).to_string())
             }
+            statements => panic!("expected a single expression, got {:?}", statements),
         }
+    }
 
-        match self.peek().kind {
-            TokenType::Class => return,
-            TokenType::Fun => return,
-            TokenType::Var => return,
-            TokenType::For => return,
-            TokenType::If => return,
-            TokenType::While => return,
-            TokenType::Print => return,
-            TokenType::Return => return,
-            _ => (),
+    #[test]
+    fn interpreter_evaluates_expressions() {
+        let cases = [
+            ("1 + 2 * 3", "7"),
+            ("(1 + 2) * 3", "9"),
+            ("-0x10 / 4", "-4"),
+            ("\"a\" + \"b\" + \"c\"", "abc"),
+            ("\"a\" == \"a\"", "true"),
+            ("!nil", "true"),
+            ("1 < 2 == true", "true"),
+            ("\"1\" != 1", "true"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(evaluate(source), Ok(expected.to_string()), "{}", source);
         }
-        self.advance();
     }
 
-    fn advance(&mut self) -> &Token {
-        if !self.is_at_end() {
-            self.current += 1;
-        }
-        self.previous()
+    #[test]
+    fn interpreter_reports_type_errors() {
+        let error = evaluate("1 +\n\"a\"").unwrap_err();
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!(error.line, 1);
+        assert!(evaluate("-\"a\"").is_err());
     }
 
-    // peek should never panic on unwrap, since we only iterate over the indices of the vector
-    // if we were exposing advance and previous, this should have some more checks or a default value
-    fn peek(&self) -> &Token {
-        return self.tokens.get(self.current).unwrap();
+    // run resolves and interprets a program under gc stress, and returns the global result the
+    // way print would show it
+    fn run(interpreter: &mut Interpreter, source: &str) -> Result<String, RuntimeError> {
+        interpreter.set_gc_stress(true);
+        let statements = parse(interpreter, source);
+        interpreter.resolve(&statements).unwrap();
+        interpreter.interpret(&statements)?;
+        let result = interpreter.global("result").unwrap();
+        Ok(interpreter.heap.display(result).to_string())
     }
 
-    // previous should never panic on unwrap, since we only iterate over the indices of the vector
-    // if we were exposing advance and previous, this should have some more checks or a default value
-    fn previous(&self) -> &Token {
-        return self.tokens.get(self.current - 1).unwrap();
+    #[test]
+    fn interpreter_calls_functions() {
+        let mut interpreter = Interpreter::new();
+        let source = "
+            fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            var result = fib(15);
+        ";
+        assert_eq!(run(&mut interpreter, source), Ok("610".to_string()));
+
+        let error = run(&mut interpreter, "fun f() { f(); }\nf();").unwrap_err();
+        assert_eq!(error.message, "Stack overflow.");
+        let error = run(&mut interpreter, "fun g(a) {}\ng();").unwrap_err();
+        assert_eq!(error.message, "Expected 1 arguments but got 0.");
+        assert_eq!(error.line, 2);
+        // the interpreter recovers from errors
+        let result = run(&mut interpreter, "var result = clock() > 0;");
+        assert_eq!(result, Ok("true".to_string()));
+    }
+
+    #[test]
+    fn closures_see_the_variables_in_scope_where_they_are_declared() {
+        let mut interpreter = Interpreter::new();
+        let source = "
+            var a = \"global\";
+            var result;
+            {
+                fun show() { result = a; }
+                var a = \"block\";
+                show();
+            }
+        ";
+        assert_eq!(run(&mut interpreter, source), Ok("global".to_string()));
+
+        let source = "
+            fun counter() { var i = 0; fun count() { i = i + 1; return i; } return count; }
+            var count = counter();
+            count();
+            var result = count();
+        ";
+        assert_eq!(run(&mut interpreter, source), Ok("2".to_string()));
     }
 
-    fn is_at_end(&self) -> bool {
-        self.peek().kind == TokenType::Eof
+    #[test]
+    fn interpreter_stops_at_step_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_step_limit(Some(3));
+        let statements = parse(&mut interpreter, "1 + 2;");
+        assert_eq!(interpreter.interpret(&statements), Ok(Value::from(3.0)));
+        let statements = parse(&mut interpreter, "1 + 2 + 3;");
+        let error = interpreter.interpret(&statements).unwrap_err();
+        assert_eq!(error.message, "Step limit exceeded.");
     }
 }
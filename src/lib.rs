@@ -0,0 +1,45 @@
+// rust-lox is a Rust implementation of Lox from Crafting Interpreters. The library runs Lox
+// programs for whoever embeds it through Lox, which runs them on the Backend it is given:
+//
+//     let mut lox = Lox::with_backend(Backend::Vm);
+//     let value = lox.run("var a = 1; a + 2;")?;
+//     assert_eq!(value, Value::from(3.0));
+//
+// and gives the scanner, the parser and the syntax tree to tools that want to look at programs.
+// The binary in main.rs is a thin command line interface over it.
+pub mod ast;
+mod chunk;
+mod compiler;
+#[cfg(feature = "serde")]
+mod convert;
+mod debug;
+mod error;
+mod heap;
+mod interpreter;
+mod lox;
+mod loxc;
+mod native;
+mod object;
+mod optimizer;
+mod parser;
+mod resolver;
+mod scanner;
+mod symbol;
+mod token;
+mod tokentype;
+mod value;
+mod vm;
+
+#[cfg(feature = "serde")]
+pub use crate::convert::ConvertError;
+pub use crate::error::{Diagnostic, Error};
+pub use crate::heap::GcStats;
+pub use crate::interpreter::{Limit, RuntimeError, StackFrame};
+pub use crate::lox::{Backend, Lox};
+pub use crate::loxc::LoadError;
+pub use crate::native::{Args, NativeClass};
+pub use crate::parser::{Error as ParseError, Parser};
+pub use crate::scanner::{keywords, ScanError, Scanner};
+pub use crate::token::Token;
+pub use crate::tokentype::{Category, Literal, TokenType};
+pub use crate::value::Value;
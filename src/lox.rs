@@ -1,33 +1,1109 @@
-use crate::interpreter::Interpreter;
+use crate::ast::Statement;
+use crate::compiler::{CompileError, Compiler};
+#[cfg(feature = "serde")]
+use crate::convert::{self, ConvertError};
+use crate::debug;
+use crate::error::{Diagnostic, Error};
+use crate::heap::{GcStats, Heap, ObjRef};
+use crate::interpreter::{self, Interpreter, Output, RuntimeError};
+use crate::loxc;
+use crate::native::{Args, NativeClass};
+use crate::object::{Constructor, Native};
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
 use crate::scanner::Scanner;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::mem;
+use std::rc::Rc;
+
+// Backend is the way Lox::run executes programs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    // walk the syntax tree with the Interpreter
+    TreeWalker,
+    // compile the syntax tree to bytecode and run it on the Vm
+    Vm,
+}
 
 pub struct Lox {
-    pub interpreter: Interpreter,
-    pub had_error: bool,
-    pub had_runtime_error: bool,
+    backend: Backend,
+    interpreter: Interpreter,
+    vm: Vm,
+    // when set, the vm backend writes the bytecode of every program to the output before running it
+    disassemble: bool,
+    // when set, every program is written to the output as the optimizer left it before running it
+    dump_ast: bool,
+    // when set, programs are optimized before they run, and the vm uses superinstructions and
+    // inline caches
+    optimize: bool,
+    had_error: bool,
+    had_runtime_error: bool,
+    // the natives and classes the embedder defined, which reset keeps
+    natives: Vec<Native>,
+    classes: Vec<(Rc<str>, Constructor)>,
+    // where print writes, shared with both backends
+    output: Output,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Lox::new()
+    }
 }
 
 impl Lox {
     pub fn new() -> Lox {
+        Lox::with_backend(Backend::TreeWalker)
+    }
+
+    pub fn with_backend(backend: Backend) -> Lox {
         Lox {
-            interpreter: Interpreter::new(vec![]),
+            backend,
+            interpreter: Interpreter::new(),
+            vm: Vm::new(),
+            disassemble: false,
+            dump_ast: false,
+            optimize: true,
             had_error: false,
             had_runtime_error: false,
+            natives: Vec::new(),
+            classes: Vec::new(),
+            output: interpreter::stdout(),
+        }
+    }
+
+    // run runs a program and gives the value of its last statement if that is an expression
+    // statement, nil otherwise
+    pub fn run(&mut self, source: &str) -> Result<Value, Error> {
+        self.run_source(source).map(|(value, _)| value)
+    }
+
+    // evaluate runs source like run does, but only gives a value if the program ends with an
+    // expression statement. The prompt uses it to show the value of expressions.
+    pub fn evaluate(&mut self, source: &str) -> Result<Option<Value>, Error> {
+        let (value, has_value) = self.run_source(source)?;
+        Ok(has_value.then_some(value))
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    // set_backend makes later programs run on backend. Each backend has globals of its own.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    // set_optimize turns the optimizer, superinstructions and inline caches on or off. They are
+    // on by default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // set_dump_ast makes every program written to the output as the optimizer left it before it
+    // runs
+    pub fn set_dump_ast(&mut self, dump_ast: bool) {
+        self.dump_ast = dump_ast;
+    }
+
+    // set_disassemble makes the vm write the bytecode of every program to the output before it
+    // runs, and set_trace the stack and the instruction before every instruction
+    pub fn set_disassemble(&mut self, disassemble: bool) {
+        self.disassemble = disassemble;
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.vm.set_trace(trace);
+    }
+
+    // set_output makes print write to output instead of stdout, on both backends. So do the
+    // debugging aids.
+    //
+    //     lox.set_output(File::create("out.txt")?);
+    pub fn set_output<W: Write + 'static>(&mut self, output: W) {
+        let output: Output = Rc::new(RefCell::new(output));
+        self.interpreter.set_output(Rc::clone(&output));
+        self.vm.set_output(Rc::clone(&output));
+        self.output = output;
+    }
+
+    // had_error tells whether a program did not compile, and had_runtime_error whether one failed
+    // while running, since the Lox was new or reset
+    pub fn had_error(&self) -> bool {
+        self.had_error
+    }
+
+    pub fn had_runtime_error(&self) -> bool {
+        self.had_runtime_error
+    }
+
+    // display shows a value that run gave the way print does. Values only stay valid until the
+    // next program runs, and one that is no longer shows as <invalid value>.
+    pub fn display(&self, value: Value) -> String {
+        if !self.heap().contains(value) {
+            return "<invalid value>".to_string();
         }
+        self.heap().display(value).to_string()
     }
 
-    pub fn run(&mut self, source: &str) {
+    // type_name names the type of a value that run gave, like "number" or "instance", or is
+    // "invalid" for a value that is no longer alive
+    pub fn type_name(&self, value: Value) -> &'static str {
+        if !self.heap().contains(value) {
+            return "invalid";
+        }
+        self.heap().type_name(value)
+    }
+
+    // tokens describes every token the scanner finds in source, one per line
+    pub fn tokens(&self, source: &str) -> Vec<String> {
         let mut scanner = Scanner::new(source);
-        let _tokens = scanner.scan_tokens();
-        // let parser = Parser::new(tokens);
-        // let statements = parser.parse();
-        //
-        // self.interpreter.interpret(statements);
+        scanner.scan_tokens();
+        scanner
+            .tokens()
+            .iter()
+            .map(|token| {
+                let described = format!("[line {}] {:?} {}", token.line, token.kind, token);
+                described.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    // syntax gives the syntax tree of every statement in source as the parser left it, before it
+    // is resolved or optimized
+    pub fn syntax(&mut self, source: &str) -> Result<Vec<String>, Error> {
+        let statements = self.parse(self.backend, source)?;
+        Ok(statements.iter().map(ToString::to_string).collect())
+    }
+
+    // reset forgets every global and everything programs allocated, as if the Lox was new, but
+    // keeps its settings
+    pub fn reset(&mut self) {
+        self.interpreter.reset();
+        self.vm.reset();
+        for native in &self.natives {
+            self.interpreter.define_native(native.clone());
+            self.vm.define_native(native.clone());
+        }
+        for (name, constructor) in &self.classes {
+            self.interpreter
+                .define_class(Rc::clone(name), constructor.clone());
+            self.vm.define_class(Rc::clone(name), constructor.clone());
+        }
+        self.had_error = false;
+        self.had_runtime_error = false;
+    }
+
+    // define_native makes a Rust function a global of programs on both backends. Programs must call
+    // it with arity arguments, which it gets as Args, and an error it returns is a runtime error
+    // at the call:
+    //
+    //     lox.define_native("square", 1, |args| {
+    //         let x = args.number(0)?;
+    //         Ok(Value::from(x * x))
+    //     });
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut Args) -> Result<Value, String> + 'static,
+    {
+        let native = Native {
+            name: name.into(),
+            arity,
+            function: Rc::new(function),
+        };
+        self.interpreter.define_native(native.clone());
+        self.vm.define_native(native.clone());
+        self.natives.push(native);
+    }
+
+    // define_class makes a Rust type a class of programs on both backends, which calls constructor
+    // with arity arguments to make an instance. Instances are owned by the runtime, and their
+    // methods and properties are what their NativeClass implementation says:
+    //
+    //     var f = File("log.txt");
+    //     f.write("started");
+    //
+    // Programs can subclass it. The instances of subclasses are made by constructor as well, and
+    // have the methods the subclass declares besides.
+    pub fn define_class<T, F>(&mut self, name: &str, arity: usize, constructor: F)
+    where
+        T: NativeClass,
+        F: Fn(&mut Args) -> Result<T, String> + 'static,
+    {
+        let name: Rc<str> = name.into();
+        let constructor = Constructor {
+            arity,
+            function: Rc::new(move |args| {
+                let object = constructor(args)?;
+                Ok(Rc::new(RefCell::new(object)) as Rc<RefCell<dyn NativeClass>>)
+            }),
+        };
+        self.interpreter
+            .define_class(Rc::clone(&name), constructor.clone());
+        self.vm.define_class(Rc::clone(&name), constructor.clone());
+        self.classes.push((name, constructor));
+    }
+
+    // global gives the value of a global variable of the current backend, like a function a
+    // program declared
+    pub fn global(&self, name: &str) -> Option<Value> {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.global(name),
+            Backend::Vm => self.vm.global_named(name),
+        }
+    }
+
+    // call calls a function or class that a program declared, or a native, with arguments from
+    // Rust, and gives what it returns. It runs on the current backend, among the globals the
+    // programs before it left, so a handler can be called again and again without parsing it:
+    //
+    //     lox.run("fun on_event(evt) { print evt; }")?;
+    //     let on_event = lox.global("on_event").unwrap();
+    //     let evt = lox.alloc_string("click");
+    //     lox.call(on_event, &[evt])?;
+    //
+    // Values only stay valid while a program refers to them, or until the next call or program
+    // runs. Calling with a value that is no longer valid is an Error::InvalidValue.
+    pub fn call(&mut self, callee: Value, arguments: &[Value]) -> Result<Value, Error> {
+        let heap = self.heap();
+        if !heap.contains(callee) || !arguments.iter().all(|argument| heap.contains(*argument)) {
+            return Err(Error::InvalidValue);
+        }
+        let result = match self.backend {
+            Backend::TreeWalker => self.interpreter.call_function(callee, arguments),
+            Backend::Vm => self.vm.call_function(callee, arguments),
+        };
+        result.map_err(|error| self.runtime_error(error))
+    }
+
+    // alloc_string makes a Lox string on the heap of the current backend, to pass to call
+    pub fn alloc_string(&mut self, text: &str) -> Value {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.heap.alloc_string(text),
+            Backend::Vm => self.vm.heap.alloc_string(text),
+        }
+    }
+
+    // deserialize makes a Rust value out of a value a program gave, like an instance it set up as
+    // a configuration:
+    //
+    //     #[derive(Deserialize)]
+    //     struct Config { name: String, port: u16 }
+    //
+    //     let value = lox.run("class Config {} var c = Config(); c.port = 80; c.name = \"app\"; c;")?;
+    //     let config: Config = lox.deserialize(value)?;
+    //
+    // Instances convert to structs and maps through their fields; Lox has no lists, so nothing
+    // converts to a sequence.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(
+        &self,
+        value: Value,
+    ) -> Result<T, ConvertError> {
+        if !self.heap().contains(value) {
+            return Err(serde::de::Error::custom(Error::InvalidValue));
+        }
+        convert::from_value(self.heap(), value)
+    }
+
+    // serialize makes a Rust value into a value for programs on the current backend. Structs and
+    // maps become instances of classes without methods, named after the struct or Map. Like the
+    // values passed to call, it only stays valid until the next call or program runs.
+    #[cfg(feature = "serde")]
+    pub fn serialize<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<Value, ConvertError> {
+        let backend = self.backend;
+        let heap = match backend {
+            Backend::TreeWalker => &mut self.interpreter.heap,
+            Backend::Vm => &mut self.vm.heap,
+        };
+        convert::to_value(heap, backend, value)
+    }
+
+    // compile turns source into the contents of a .loxc file. With strip set the line table is
+    // left out.
+    pub fn compile(&mut self, source: &str, strip: bool) -> Result<Vec<u8>, Error> {
+        let statements = self.check_with(Backend::Vm, source)?;
+        let script = self.compile_statements(source, &statements)?;
+        Ok(loxc::serialize(script, &self.vm.heap, strip))
+    }
+
+    // run_compiled runs the contents of a .loxc file on the vm, whatever the backend is, and gives
+    // the value of the program like run does. Files that are corrupt or were written by another
+    // version are rejected before anything runs.
+    pub fn run_compiled(&mut self, bytes: &[u8]) -> Result<Value, Error> {
+        let script = loxc::deserialize(bytes, &mut self.vm.heap).map_err(Error::Load)?;
+        self.execute(script)
+    }
+
+    // globals gives the names and values of the global variables of the current backend, natives
+    // included, sorted by name
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals = match self.backend {
+            Backend::TreeWalker => self.interpreter.globals(),
+            Backend::Vm => self.vm.globals(),
+        };
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
+    }
+
+    // gc_stats describes the garbage collector of the current backend
+    pub fn gc_stats(&self) -> GcStats {
+        match self.backend {
+            Backend::TreeWalker => self.interpreter.gc_stats(),
+            Backend::Vm => self.vm.gc_stats(),
+        }
+    }
+
+    // set_gc_stress makes every allocation collect garbage on both backends, for testing the
+    // collector
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.interpreter.set_gc_stress(stress);
+        self.vm.set_gc_stress(stress);
+    }
+
+    // set_step_limit limits how many steps a single run or call may take on both backends, which
+    // are instructions on the vm and expressions on the tree-walker. None means there is no limit.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.interpreter.set_step_limit(limit);
+        self.vm.set_step_limit(limit);
+    }
+
+    // set_max_call_depth sets how deep calls may nest on both backends before programs are
+    // stopped with a stack overflow. The default is 64.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.interpreter.set_max_call_depth(depth);
+        self.vm.set_max_call_depth(depth);
+    }
+
+    // set_heap_limit limits how many bytes the objects programs keep alive may take on both
+    // backends. None means there is no limit.
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.interpreter.set_heap_limit(limit);
+        self.vm.set_heap_limit(limit);
+    }
+
+    fn heap(&self) -> &Heap {
+        match self.backend {
+            Backend::TreeWalker => &self.interpreter.heap,
+            Backend::Vm => &self.vm.heap,
+        }
+    }
+
+    // run_source runs a program on the backend, and gives its value and whether that came from an
+    // expression statement
+    fn run_source(&mut self, source: &str) -> Result<(Value, bool), Error> {
+        let statements = self.check(source)?;
+        let has_value = matches!(statements.last(), Some(Statement::Expression { .. }));
+        let value = match self.backend {
+            Backend::TreeWalker => match self.interpreter.interpret(&statements) {
+                Ok(value) => value,
+                Err(error) => return Err(self.runtime_error(error)),
+            },
+            Backend::Vm => {
+                let script = self.compile_statements(source, &statements)?;
+                self.execute(script)?
+            }
+        };
+        Ok((value, has_value))
+    }
+
+    // parse scans source with the symbols of the heap of backend, so that the names in the program
+    // key the variables and properties of that runtime
+    fn parse(&mut self, backend: Backend, source: &str) -> Result<Vec<Statement>, Error> {
+        let heap = match backend {
+            Backend::TreeWalker => &mut self.interpreter.heap,
+            Backend::Vm => &mut self.vm.heap,
+        };
+        let mut scanner = Scanner::with_symbols(source, mem::take(&mut heap.symbols));
+        scanner.scan_tokens();
+        let mut diagnostics: Vec<_> = scanner
+            .errors()
+            .iter()
+            .map(|error| Diagnostic::new(source, error.span, &error.message))
+            .collect();
+        let (tokens, symbols) = scanner.into_parts();
+        heap.symbols = symbols;
+
+        let mut parser = Parser::new(tokens);
+        match parser.parse() {
+            // the parser skipped over anything the scanner could not make sense of, so the
+            // program is only what the user wrote if there were no scan errors
+            Ok(statements) if diagnostics.is_empty() => return Ok(statements),
+            Ok(_) => {}
+            Err(errors) => diagnostics.extend(
+                errors
+                    .iter()
+                    .map(|error| Diagnostic::new(source, error.token().span(), &error.to_string())),
+            ),
+        }
+        Err(self.compile_error(diagnostics))
+    }
+
+    // check parses source and resolves it for the backend, and gives the optimized program if
+    // there were no errors
+    fn check(&mut self, source: &str) -> Result<Vec<Statement>, Error> {
+        self.check_with(self.backend, source)
+    }
+
+    fn check_with(&mut self, backend: Backend, source: &str) -> Result<Vec<Statement>, Error> {
+        let statements = self.parse(backend, source)?;
+        // the vm compiler finds scoping errors too, but the optimizer could remove the code they
+        // are in
+        let resolved = match backend {
+            Backend::TreeWalker => self.interpreter.resolve(&statements),
+            Backend::Vm => Resolver::resolve(&statements, &mut HashMap::new()),
+        };
+        if let Err(errors) = resolved {
+            return Err(self.compile_errors(source, &errors));
+        }
+        let statements = if self.optimize {
+            optimizer::optimize(statements)
+        } else {
+            statements
+        };
+        if self.dump_ast {
+            // like the trace, the dump is for debugging and an output that fails does not stop
+            // the program
+            let mut output = self.output.borrow_mut();
+            for statement in &statements {
+                let _ = writeln!(output, "{}", statement);
+            }
+        }
+        Ok(statements)
     }
 
-    // TODO expand error() to also report the offending character for Rust like error reporting
-    pub(crate) fn error(&mut self, line: usize, message: &str) {
+    fn compile_statements(
+        &mut self,
+        source: &str,
+        statements: &[Statement],
+    ) -> Result<ObjRef, Error> {
+        Compiler::compile(statements, &mut self.vm.heap, self.optimize)
+            .map_err(|errors| self.compile_errors(source, &errors))
+    }
+
+    fn compile_errors(&mut self, source: &str, errors: &[CompileError]) -> Error {
+        let diagnostics = errors
+            .iter()
+            .map(|error| Diagnostic::new(source, error.token.span(), &error.message))
+            .collect();
+        self.compile_error(diagnostics)
+    }
+
+    fn execute(&mut self, script: ObjRef) -> Result<Value, Error> {
+        if self.disassemble {
+            let disassembled = debug::disassemble_function(script, &self.vm.heap);
+            let _ = write!(self.output.borrow_mut(), "{}", disassembled);
+        }
+        self.vm.set_inline_caching(self.optimize);
+        self.vm
+            .interpret(script)
+            .map_err(|error| self.runtime_error(error))
+    }
+
+    fn compile_error(&mut self, diagnostics: Vec<Diagnostic>) -> Error {
         self.had_error = true;
-        println!("[line {}] Error: {}", line, message);
+        Error::Compile(diagnostics)
+    }
+
+    fn runtime_error(&mut self, error: RuntimeError) -> Error {
+        self.had_runtime_error = true;
+        Error::Runtime(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Limit;
+    use crate::loxc::LoadError;
+    use std::cell::Cell;
+
+    #[test]
+    fn run_reports_scan_errors() {
+        let mut lox = Lox::new();
+        assert_eq!(lox.run("1 + 2;"), Ok(Value::from(3.0)));
+        assert!(!lox.had_error);
+
+        let error = lox.run("var a = #;").unwrap_err();
+        assert!(lox.had_error);
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Error: unexpected character '#'\n  |\n1 | var a = #;\n  |         ^"
+        );
+    }
+
+    #[test]
+    fn run_reports_runtime_errors() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            let error = lox.run("\n1 + \"a\";").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Operands must be two numbers or two strings.\n[line 2] in script"
+            );
+            assert!(!lox.had_error);
+            assert!(lox.had_runtime_error);
+        }
+    }
+
+    // Shared is an output that the test can read back
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Shared {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Closed is an output that can not be written to
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn print_writes_to_the_output() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            let output = Shared::default();
+            lox.set_output(output.clone());
+            lox.run("print 1 + 2; print \"a\";").unwrap();
+            assert_eq!(output.take(), "3\na\n", "{:?}", backend);
+
+            // the output is kept when everything else is forgotten
+            lox.reset();
+            lox.set_dump_ast(true);
+            lox.run("print 60 * 60;").unwrap();
+            assert_eq!(output.take(), "(print 3600)\n3600\n", "{:?}", backend);
+
+            lox.set_output(Closed);
+            let error = lox.run("\nprint 1;").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Could not print: broken pipe.\n[line 2] in script",
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn evaluate_gives_the_value_of_the_last_expression() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            let value = lox.evaluate("var a = \"b\";\na + \"c\";").unwrap();
+            assert_eq!(lox.display(value.unwrap()), "bc");
+            assert_eq!(lox.evaluate("1;"), Ok(Some(Value::from(1.0))));
+            assert_eq!(lox.evaluate("1; var b = 2;"), Ok(None));
+            assert!(lox.evaluate("-nil;").is_err());
+            assert!(lox.had_runtime_error);
+        }
+    }
+
+    #[test]
+    fn globals_follow_the_backend() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.run("var b = 1; var a = \"x\";").unwrap();
+            let globals: Vec<_> = lox
+                .globals()
+                .into_iter()
+                .map(|(name, value)| format!("{} = {}", name, lox.display(value)))
+                .collect();
+            assert_eq!(globals, ["a = x", "b = 1", "clock = <native fn>"]);
+        }
+    }
+
+    #[test]
+    fn type_names_follow_the_backend() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.run("class A { f() {} } fun g() {} var a = A();")
+                .unwrap();
+            let cases = [
+                ("nil;", "nil"),
+                ("1 < 2;", "boolean"),
+                ("1;", "number"),
+                ("\"a\";", "string"),
+                ("clock;", "native function"),
+                ("g;", "function"),
+                ("a.f;", "function"),
+                ("A;", "class"),
+                ("a;", "instance"),
+            ];
+            for (source, expected) in cases {
+                let value = lox.run(source).unwrap();
+                assert_eq!(lox.type_name(value), expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_and_syntax_describe_source() {
+        let mut lox = Lox::new();
+        assert_eq!(
+            lox.tokens("a + 1;"),
+            [
+                "[line 1] Identifier a",
+                "[line 1] Plus +",
+                "[line 1] Number 1",
+                "[line 1] Semicolon ;",
+                "[line 1] Eof"
+            ]
+        );
+        assert_eq!(
+            lox.syntax("print 1 + 2;"),
+            Ok(vec!["(print (+ 1 2))".to_string()])
+        );
+        assert!(lox.syntax("print;").is_err());
+        assert!(lox.had_error);
+    }
+
+    #[test]
+    fn reset_forgets_globals_but_keeps_settings() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            assert!(lox.run("var a = 1; -nil;").is_err());
+            lox.reset();
+            assert!(!lox.had_runtime_error);
+            assert_eq!(lox.globals().len(), 1);
+            lox.run("print \"a\" + \"b\";").unwrap();
+            assert!(lox.gc_stats().collections > 0);
+        }
+    }
+
+    #[test]
+    fn runtime_errors_show_the_calls_that_were_running() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            let source = "fun f() {\n  -nil;\n}\nclass A { g() { f(); } }\nA().g();";
+            let error = lox.run(source).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Operand must be a number.\n[line 2] in f()\n[line 4] in g()\n[line 5] in script",
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn natives_are_defined_on_both_backends() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.define_native("add", 2, |args| {
+                Ok(Value::from(args.number(0)? + args.number(1)?))
+            });
+            lox.define_native("shout", 1, |args| {
+                let text = args.string(0)?.to_uppercase();
+                Ok(args.alloc_string(&text))
+            });
+            // a buggy native asking for an argument it does not take
+            lox.define_native("second", 1, |args| args.get(1));
+            assert_eq!(lox.run("add(1, 2);"), Ok(Value::from(3.0)));
+            let value = lox.run("shout(\"a\" + \"b\");").unwrap();
+            assert_eq!(lox.display(value), "AB");
+
+            let errors = [
+                (
+                    "add(1);",
+                    "Expected 2 arguments but got 1.\n[line 1] in script",
+                ),
+                (
+                    "fun f() {\n  add(1, nil);\n}\nf();",
+                    "Argument 2 must be a number.\n[line 2] in f()\n[line 4] in script",
+                ),
+                ("second(1);", "Argument 2 is missing.\n[line 1] in script"),
+            ];
+            for (source, expected) in errors {
+                let error = lox.run(source).unwrap_err();
+                assert_eq!(error.to_string(), expected, "{:?}", backend);
+            }
+
+            // natives stay when everything else is forgotten
+            lox.reset();
+            assert_eq!(lox.run("add(2, 2);"), Ok(Value::from(4.0)));
+        }
+    }
+
+    #[test]
+    fn values_that_are_no_longer_alive_are_rejected() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            let p = lox.run("class P {} P();").unwrap();
+            assert_eq!(lox.display(p), "P instance");
+            // the instance is collected and its slot reused by the next program
+            let q = lox.run("class Q {} var q = Q(); q;").unwrap();
+            assert_eq!(lox.display(q), "Q instance");
+            assert_eq!(lox.display(p), "<invalid value>", "{:?}", backend);
+            assert_eq!(lox.type_name(p), "invalid");
+            assert_eq!(lox.call(p, &[]), Err(Error::InvalidValue));
+            let q_class = lox.global("Q").unwrap();
+            assert_eq!(lox.call(q_class, &[p]), Err(Error::InvalidValue));
+            #[cfg(feature = "serde")]
+            assert!(lox.deserialize::<String>(p).is_err());
+
+            // and so, unless their generations happen to collide, are values of another Lox
+            let mut other = Lox::with_backend(backend);
+            let string = other.alloc_string("other");
+            assert_eq!(lox.display(string), "<invalid value>", "{:?}", backend);
+            assert_eq!(lox.call(string, &[]), Err(Error::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn globals_can_be_called_from_rust() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            let source = "var events = \"\";\n\
+                fun on_event(evt) {\n\
+                  events = events + evt;\n\
+                  return events;\n\
+                }\n\
+                class Point { init(x, y) { this.x = x; this.y = y; } }\n\
+                fun fail() {\n\
+                  return -nil;\n\
+                }";
+            lox.run(source).unwrap();
+            assert_eq!(lox.global("missing"), None);
+
+            let on_event = lox.global("on_event").unwrap();
+            for evt in ["a", "b", "c"] {
+                let evt = lox.alloc_string(evt);
+                lox.call(on_event, &[evt]).unwrap();
+            }
+            let events = lox.global("events").unwrap();
+            assert_eq!(lox.display(events), "abc", "{:?}", backend);
+
+            let point = lox.global("Point").unwrap();
+            let point = lox
+                .call(point, &[Value::from(1.0), Value::from(2.0)])
+                .unwrap();
+            assert_eq!(lox.display(point), "Point instance");
+            let clock = lox.global("clock").unwrap();
+            assert!(lox.call(clock, &[]).unwrap().as_number().is_some());
+
+            let errors = [
+                (
+                    lox.global("fail").unwrap(),
+                    "Operand must be a number.\n[line 8] in fail()",
+                ),
+                (on_event, "Expected 1 arguments but got 0.\n[line 0]"),
+                (
+                    Value::from(1.0),
+                    "Can only call functions and classes.\n[line 0]",
+                ),
+            ];
+            for (callee, expected) in errors {
+                let error = lox.call(callee, &[]).unwrap_err();
+                assert_eq!(error.to_string(), expected, "{:?}", backend);
+            }
+        }
+    }
+
+    // Counter counts up, and counts how many counters were dropped
+    struct Counter {
+        count: f64,
+        dropped: Rc<Cell<usize>>,
+    }
+
+    impl NativeClass for Counter {
+        fn arity(&self, method: &str) -> Option<usize> {
+            match method {
+                "add" => Some(1),
+                "describe" => Some(0),
+                _ => None,
+            }
+        }
+
+        fn call(&mut self, method: &str, args: &mut Args) -> Result<Value, String> {
+            match method {
+                "add" => {
+                    self.count += args.number(0)?;
+                    Ok(Value::from(self.count))
+                }
+                _ => Ok(args.alloc_string(&format!("counted {}", self.count))),
+            }
+        }
+
+        fn get(&self, name: &str, _: &mut Args) -> Option<Value> {
+            (name == "count").then_some(Value::from(self.count))
+        }
+
+        fn set(&mut self, name: &str, args: &mut Args) -> Result<(), String> {
+            match name {
+                "count" => self.count = args.number(0)?,
+                _ => return Err(format!("Can't set property '{}'.", name)),
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    #[test]
+    fn native_classes_have_methods_and_properties() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let dropped = Rc::new(Cell::new(0));
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            let counters = Rc::clone(&dropped);
+            lox.define_class("Counter", 1, move |args| {
+                Ok(Counter {
+                    count: args.number(0)?,
+                    dropped: Rc::clone(&counters),
+                })
+            });
+
+            let source = "var c = Counter(1);\nc.add(2);\nvar add = c.add;\nadd(3);\nc.count = c.count * 10;\nc.describe();";
+            let value = lox.run(source).unwrap();
+            assert_eq!(lox.display(value), "counted 60", "{:?}", backend);
+            let value = lox.run("c;").unwrap();
+            assert_eq!(lox.display(value), "Counter instance");
+            assert_eq!(lox.type_name(value), "instance");
+
+            let errors = [
+                ("c.missing;", "Undefined property 'missing'."),
+                ("c.add();", "Expected 1 arguments but got 0."),
+                ("c.add(\"a\");", "Argument 1 must be a number."),
+                ("c.other = 1;", "Can't set property 'other'."),
+            ];
+            for (source, expected) in errors {
+                let error = lox.run(source).unwrap_err();
+                assert_eq!(
+                    error.to_string(),
+                    format!("{}\n[line 1] in script", expected)
+                );
+            }
+
+            // the counter is dropped once nothing refers to it any more
+            assert_eq!(dropped.get(), 0);
+            lox.run("c = nil; add = nil; var a = \"a\"; a + \"b\";")
+                .unwrap();
+            assert_eq!(dropped.get(), 1, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn native_classes_are_classes_lox_can_subclass() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            lox.define_class("Counter", 1, move |args| {
+                Ok(Counter {
+                    count: args.number(0)?,
+                    dropped: Rc::new(Cell::new(0)),
+                })
+            });
+
+            let value = lox.run("Counter;").unwrap();
+            assert_eq!(lox.display(value), "Counter", "{:?}", backend);
+            assert_eq!(lox.type_name(value), "class");
+
+            // instances of the subclass are made by the constructor and then initialized, and
+            // have the methods of both
+            let source = "
+                class Tally < Counter {
+                    init(start) { this.add(start); }
+                    twice(n) { this.add(n); return this.add(n); }
+                }
+                var t = Tally(1);
+                t.twice(3);
+                var twice = t.twice;
+                twice(1);
+                t.describe();
+            ";
+            let value = lox.run(source).unwrap();
+            assert_eq!(lox.display(value), "counted 10", "{:?}", backend);
+            let value = lox.run("t;").unwrap();
+            assert_eq!(lox.display(value), "Tally instance");
+
+            let errors = [
+                (
+                    "class A < Counter {} A();",
+                    "Expected 1 arguments but got 0.",
+                ),
+                ("t.missing;", "Undefined property 'missing'."),
+                ("t.missing();", "Undefined property 'missing'."),
+            ];
+            for (source, expected) in errors {
+                let error = lox.run(source).unwrap_err();
+                assert_eq!(
+                    error.to_string(),
+                    format!("{}\n[line 1] in script", expected),
+                    "{:?}",
+                    backend
+                );
+            }
+
+            lox.reset();
+            let value = lox.run("class A < Counter {} A(2).count;").unwrap();
+            assert_eq!(value, Value::from(2.0), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn compiled_programs_run_on_the_vm() {
+        let mut lox = Lox::new();
+        let bytes = lox.compile("print 1 + 2;\n-nil;", false).unwrap();
+        assert!(matches!(lox.run_compiled(&bytes), Err(Error::Runtime(_))));
+        assert!(lox.had_runtime_error);
+
+        let bytes = lox.compile("1 + 2;", false).unwrap();
+        assert_eq!(lox.run_compiled(&bytes), Ok(Value::from(3.0)));
+
+        assert!(matches!(
+            lox.compile("print;", false),
+            Err(Error::Compile(_))
+        ));
+        assert!(lox.had_error);
+        assert_eq!(
+            lox.run_compiled(b"LOX"),
+            Err(Error::Load(LoadError::NotLoxc))
+        );
+    }
+
+    #[test]
+    fn limits_stop_programs_with_runtime_errors() {
+        let limit = |lox: &mut Lox, source: &str| match lox.run(source) {
+            Err(Error::Runtime(error)) => error.limit(),
+            result => panic!("expected a runtime error, got {:?}", result),
+        };
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_step_limit(Some(1000));
+            assert_eq!(limit(&mut lox, "while (true) {}"), Some(Limit::Steps));
+            // every run gets the whole budget
+            lox.run("var i = 0; while (i < 10) i = i + 1;").unwrap();
+            lox.set_step_limit(None);
+
+            lox.set_max_call_depth(10);
+            let source = "fun f(n) { if (n > 0) f(n - 1); }";
+            assert_eq!(
+                limit(&mut lox, &format!("{} f(10);", source)),
+                Some(Limit::CallDepth)
+            );
+            lox.run(&format!("{} f(8);", source)).unwrap();
+            assert_eq!(limit(&mut lox, "f(nil + 1);"), None);
+            // an error that reads like a limit is still just an error
+            lox.define_native("overflow", 0, |_| Err(Limit::CallDepth.message().into()));
+            assert_eq!(limit(&mut lox, "overflow();"), None);
+
+            lox.set_heap_limit(Some(256 * 1024));
+            lox.reset();
+            // garbage is collected to stay under the limit, only what is kept counts
+            lox.run("class Node {} for (var i = 0; i < 100000; i = i + 1) Node();")
+                .unwrap();
+            let source =
+                "var head; while (true) { var node = Node(); node.next = head; head = node; }";
+            assert_eq!(limit(&mut lox, source), Some(Limit::Memory));
+            lox.run("head = nil; Node();").unwrap();
+        }
+    }
+
+    #[test]
+    fn deep_calls_in_deep_expressions_do_not_overflow_the_stack() {
+        let nest = |open: &str, close: &str, depth| {
+            let body = format!("{}g(n - 1) + 1{}", open.repeat(depth), close.repeat(depth));
+            format!(
+                "fun g(n) {{ if (n == 0) return 0; return {}; }} g(62);",
+                body
+            )
+        };
+        for (source, expected) in [
+            (nest("(", ")", 120), 62.0),
+            (nest("(1 + ", ")", 60), 3782.0),
+        ] {
+            for backend in [Backend::TreeWalker, Backend::Vm] {
+                let mut lox = Lox::with_backend(backend);
+                match lox.run(&source) {
+                    Ok(value) => assert_eq!(value, Value::from(expected), "{:?}", backend),
+                    // the tree-walker recurses on the Rust stack, and may stop before the vm does
+                    Err(Error::Runtime(error)) if backend == Backend::TreeWalker => {
+                        assert_eq!(error.limit(), Some(Limit::CallDepth))
+                    }
+                    result => panic!("{:?} gave {:?}", backend, result),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gc_stats_follow_the_backend() {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            lox.set_gc_stress(true);
+            lox.run("print \"a\" + \"b\";").unwrap();
+            assert!(lox.gc_stats().collections > 0);
+        }
+    }
+
+    #[test]
+    fn long_chains_are_not_nested() {
+        let sum = format!("1{};", " + 1".repeat(5_000));
+        let text = format!("\"\"{};", " + \"a\"".repeat(200));
+        let calls = format!(
+            "class A {{ f() {{ return this; }} }} A(){}.f;",
+            ".f()".repeat(2_000)
+        );
+        let logical = format!("false{} or true;", " or false".repeat(5_000));
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            let mut lox = Lox::with_backend(backend);
+            assert_eq!(lox.run(&sum), Ok(Value::from(5001.0)), "{:?}", backend);
+            let value = lox.run(&text).unwrap();
+            assert_eq!(lox.display(value), "a".repeat(200));
+            let value = lox.run(&calls).unwrap();
+            assert_eq!(lox.display(value), "<fn f>");
+            assert_eq!(lox.run(&logical), Ok(Value::from(true)));
+        }
+    }
+
+    // the seed corpus of the fuzz targets doubles as a smoke test that Lox::run never panics,
+    // also when every allocation collects garbage
+    #[test]
+    fn run_survives_fuzz_seeds() {
+        let seeds = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/seeds");
+        for entry in std::fs::read_dir(seeds).unwrap() {
+            let source = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for backend in [Backend::TreeWalker, Backend::Vm] {
+                let mut lox = Lox::with_backend(backend);
+                lox.set_step_limit(Some(10_000));
+                lox.set_gc_stress(true);
+                let _ = lox.run(&source);
+            }
+        }
     }
 }
@@ -0,0 +1,777 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::heap::{Heap, ObjRef};
+use crate::object::{Function, Object};
+use crate::value::Value;
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+// A .loxc file holds a compiled script, so it can be shipped without being compiled again:
+//
+// magic      4 bytes   "LOXC"
+// version    u16       FORMAT_VERSION, files of other versions are rejected
+// flags      u16       FLAG_DEBUG_INFO when the chunks contain their line table
+// checksum   u32       CRC-32 of everything after the header
+// script     the function running the top level code
+//
+// where a function is
+//
+// name       u32 length and UTF-8 bytes, empty for the script
+// arity      u8
+// upvalues   u16       the number of variables it captures
+// caches     u32       the number of inline caches its code refers to
+// chunk      the code, the constant pool and, with FLAG_DEBUG_INFO, the line table
+//
+// All numbers are little endian. Like `luac -s`, compiling with strip leaves out the line table,
+// which makes the file smaller but runtime errors can no longer say on which line they happened.
+pub(crate) const MAGIC: &[u8; 4] = b"LOXC";
+pub(crate) const FORMAT_VERSION: u16 = 3;
+const FLAG_DEBUG_INFO: u16 = 1;
+const HEADER_LEN: usize = 12;
+
+// every constant starts with its tag. Function constants are followed by the function, encoded
+// like the script.
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// the parser does not let functions nest deeper than this, so deeper files are not ours
+const MAX_NESTING: usize = 128;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum LoadError {
+    NotLoxc,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Truncated,
+    TrailingBytes,
+    InvalidConstant(u8),
+    InvalidString,
+    InvalidCode(String),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotLoxc => write!(f, "not a compiled Lox file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "compiled with format version {}, but this interpreter reads version {}",
+                version, FORMAT_VERSION
+            ),
+            LoadError::ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupt"),
+            LoadError::Truncated => write!(f, "unexpected end of file, the file is truncated"),
+            LoadError::TrailingBytes => write!(f, "unexpected data after the compiled script"),
+            LoadError::InvalidConstant(tag) => write!(f, "invalid constant type {}", tag),
+            LoadError::InvalidString => write!(f, "string constant is not valid UTF-8"),
+            LoadError::InvalidCode(message) => write!(f, "invalid bytecode: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// serialize encodes a compiled script as a .loxc file, leaving out the line tables when strip is
+// set
+pub(crate) fn serialize(script: ObjRef, heap: &Heap, strip: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_function(&mut body, script, heap, strip);
+
+    let mut file = Vec::with_capacity(HEADER_LEN + body.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    let flags = if strip { 0 } else { FLAG_DEBUG_INFO };
+    file.extend_from_slice(&flags.to_le_bytes());
+    file.extend_from_slice(&crc32(&body).to_le_bytes());
+    file.extend_from_slice(&body);
+    file
+}
+
+fn write_function(body: &mut Vec<u8>, function: ObjRef, heap: &Heap, strip: bool) {
+    let function = match heap.get(function) {
+        Object::Function(function) => function,
+        object => panic!("{:?} is not a function", object),
+    };
+    let name = function.name.as_deref().unwrap_or_default();
+    write_u32(body, name.len());
+    body.extend_from_slice(name.as_bytes());
+    // the compiler limits both, so they always fit
+    body.push(function.arity as u8);
+    body.extend_from_slice(&(function.upvalue_count as u16).to_le_bytes());
+    write_u32(body, function.caches.len());
+
+    let chunk = &function.chunk;
+    write_u32(body, chunk.code.len());
+    body.extend_from_slice(&chunk.code);
+    write_u32(body, chunk.constants.len());
+    for constant in &chunk.constants {
+        if constant.is_nil() {
+            body.push(TAG_NIL);
+        } else if let Some(val) = constant.as_bool() {
+            body.push(if val { TAG_TRUE } else { TAG_FALSE });
+        } else if let Some(val) = constant.as_number() {
+            body.push(TAG_NUMBER);
+            body.extend_from_slice(&val.to_le_bytes());
+        } else if let Some(obj) = constant.as_object() {
+            match heap.get(obj) {
+                Object::String(val) => {
+                    body.push(TAG_STRING);
+                    write_u32(body, val.len());
+                    body.extend_from_slice(val.as_bytes());
+                }
+                Object::Function(_) => {
+                    body.push(TAG_FUNCTION);
+                    write_function(body, obj, heap, strip);
+                }
+                object => panic!("{:?} can not be a constant", object),
+            }
+        }
+    }
+    if !strip {
+        write_u32(body, chunk.lines.len());
+        for (line, count) in &chunk.lines {
+            write_u32(body, *line);
+            write_u32(body, *count);
+        }
+    }
+}
+
+// deserialize decodes a .loxc file and checks that its code can safely be run by the vm. It
+// returns the script, which is allocated on heap together with the constants.
+pub(crate) fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<ObjRef, LoadError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(LoadError::NotLoxc);
+    }
+    let mut header = Reader::new(&bytes[MAGIC.len()..]);
+    let version = header.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let flags = header.u16()?;
+    let checksum = header.u32()?;
+    let body = &bytes[HEADER_LEN..];
+    if crc32(body) != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader::new(body);
+    let debug_info = flags & FLAG_DEBUG_INFO != 0;
+    let script = read_function(&mut reader, heap, debug_info, 0)?;
+    if !reader.is_empty() {
+        return Err(LoadError::TrailingBytes);
+    }
+    if script.name.is_some() || script.arity != 0 || script.upvalue_count != 0 {
+        return Err(LoadError::InvalidCode(
+            "the script is not a script".to_string(),
+        ));
+    }
+    Ok(heap.alloc(Object::Function(script)))
+}
+
+fn read_function(
+    reader: &mut Reader,
+    heap: &mut Heap,
+    debug_info: bool,
+    nesting: usize,
+) -> Result<Function, LoadError> {
+    if nesting > MAX_NESTING {
+        return Err(LoadError::InvalidCode(
+            "functions are nested too deeply".to_string(),
+        ));
+    }
+    let name = reader.string()?;
+    let name = (!name.is_empty()).then(|| name.into());
+    let arity = reader.u8()? as usize;
+    let upvalue_count = reader.u16()? as usize;
+    let cache_count = reader.len()?;
+    // every cache is referred to by an instruction of at least five bytes, so a count that large
+    // is corrupt, and is not trusted with an allocation
+    if cache_count > reader.bytes.len() {
+        return Err(LoadError::Truncated);
+    }
+
+    let mut chunk = Chunk::new();
+    let code_len = reader.len()?;
+    chunk.code = reader.bytes(code_len)?.to_vec();
+    let constant_count = reader.len()?;
+    for _ in 0..constant_count {
+        let constant = match reader.u8()? {
+            TAG_NIL => Value::NIL,
+            TAG_FALSE => Value::from(false),
+            TAG_TRUE => Value::from(true),
+            TAG_NUMBER => Value::from(f64::from_le_bytes(reader.array()?)),
+            TAG_STRING => {
+                let val = reader.string()?;
+                heap.alloc_string(val)
+            }
+            TAG_FUNCTION => {
+                let function = read_function(reader, heap, debug_info, nesting + 1)?;
+                Value::object(heap.alloc(Object::Function(function)))
+            }
+            tag => return Err(LoadError::InvalidConstant(tag)),
+        };
+        chunk.constants.push(constant);
+    }
+    if debug_info {
+        let runs = reader.len()?;
+        for _ in 0..runs {
+            let line = reader.len()?;
+            let count = reader.len()?;
+            chunk.lines.push((line, count));
+        }
+    }
+
+    let function = Function {
+        name,
+        arity,
+        upvalue_count,
+        chunk: Rc::new(chunk),
+        caches: (0..cache_count).map(|_| Cell::new(None)).collect(),
+    };
+    validate(&function, heap)?;
+    Ok(function)
+}
+
+// validate checks that every instruction of function is known and has its operands, that
+// constants, upvalues and caches it refers to exist and have the right type, that jumps land on
+// an instruction and that the code ends by returning, so the vm never reads past the chunk
+fn validate(function: &Function, heap: &Heap) -> Result<(), LoadError> {
+    let chunk = &function.chunk;
+    if !chunk.lines.is_empty() {
+        let covered: usize = chunk.lines.iter().map(|(_, count)| count).sum();
+        if covered != chunk.code.len() {
+            return invalid("the line table does not match the code".to_string());
+        }
+    }
+
+    // the opcode, operand and next offset of the instruction starting at every offset
+    let mut instructions = vec![None; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    let mut last = None;
+    while offset < chunk.code.len() {
+        let op = match OpCode::from_byte(chunk.code[offset]) {
+            Some(op) => op,
+            None => {
+                return invalid(format!(
+                    "unknown opcode {} at {}",
+                    chunk.code[offset], offset
+                ))
+            }
+        };
+        let width = op.operand_width();
+        let mut next = offset + 1 + width;
+        let operand = match chunk.code.get(offset + 1..next) {
+            Some(operand) => operand
+                .iter()
+                .rev()
+                .fold(0, |operand, byte| operand << 8 | *byte as usize),
+            None => return invalid(format!("missing operand of {:?} at {}", op, offset)),
+        };
+        let constant = || match chunk.constants.get(operand) {
+            Some(constant) => Ok(*constant),
+            None => invalid(format!("constant {} at {} does not exist", operand, offset)),
+        };
+        let object = |constant: Value| constant.as_object().map(|obj| heap.get(obj));
+        // the instructions naming a property or class keep the name in a constant, and some of
+        // them an inline cache
+        let (name, cache) = match op {
+            OpCode::Class | OpCode::Method | OpCode::GetSuper | OpCode::SuperInvoke => {
+                (Some(chunk.read_u16(offset + 1)), None)
+            }
+            OpCode::GetProperty | OpCode::SetProperty => (
+                Some(chunk.read_u16(offset + 1)),
+                Some(chunk.read_u16(offset + 3)),
+            ),
+            OpCode::Invoke => (
+                Some(chunk.read_u16(offset + 1)),
+                Some(chunk.read_u16(offset + 4)),
+            ),
+            OpCode::GetLocalProperty => (
+                Some(chunk.read_u16(offset + 2)),
+                Some(chunk.read_u16(offset + 4)),
+            ),
+            _ => (None, None),
+        };
+        if let Some(name) = name {
+            let name = chunk.constants.get(name as usize).copied();
+            if !matches!(name.and_then(object), Some(Object::String(_))) {
+                return invalid(format!("the name at {} is not a string", offset));
+            }
+        }
+        if cache.is_some_and(|cache| cache as usize >= function.caches.len()) {
+            return invalid(format!("the cache at {} does not exist", offset));
+        }
+        match op {
+            OpCode::Constant | OpCode::ConstantLong => {
+                constant()?;
+            }
+            OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal
+                if !matches!(object(constant()?), Some(Object::String(_))) =>
+            {
+                return invalid(format!("the global name at {} is not a string", offset));
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue if operand >= function.upvalue_count => {
+                return invalid(format!("upvalue {} at {} does not exist", operand, offset));
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PopJumpIfFalse => {
+                jumps.push((offset, next + operand))
+            }
+            OpCode::Loop => jumps.push((offset, next.wrapping_sub(operand))),
+            OpCode::Closure => {
+                let upvalue_count = match object(constant()?) {
+                    Some(Object::Function(closed)) => closed.upvalue_count,
+                    _ => return invalid(format!("the closure at {} has no function", offset)),
+                };
+                let upvalues = match chunk.code.get(next..next + 2 * upvalue_count) {
+                    Some(upvalues) => upvalues,
+                    None => {
+                        return invalid(format!("missing upvalues of the closure at {}", offset))
+                    }
+                };
+                for upvalue in upvalues.chunks(2) {
+                    let captures = match upvalue[0] {
+                        0 => (upvalue[1] as usize) < function.upvalue_count,
+                        1 => true,
+                        _ => false,
+                    };
+                    if !captures {
+                        return invalid(format!("invalid upvalue of the closure at {}", offset));
+                    }
+                }
+                next += upvalues.len();
+            }
+            _ => {}
+        }
+        instructions[offset] = Some((op, operand, next));
+        last = Some(op);
+        offset = next;
+    }
+    if last != Some(OpCode::Return) {
+        return invalid("the code does not end with a return".to_string());
+    }
+    for (offset, target) in jumps {
+        if !instructions.get(target).is_some_and(Option::is_some) {
+            return invalid(format!(
+                "the jump at {} does not land on an instruction",
+                offset
+            ));
+        }
+    }
+    validate_stack(function, &instructions)
+}
+
+// validate_stack follows every path through the code to find the height of the stack at each
+// instruction and the locals closures captured, so that no instruction pops more than there is,
+// locals only name slots the frame has and captured locals are closed before they are popped.
+// Like the compiler, every path has to reach an instruction with the same stack.
+fn validate_stack(
+    function: &Function,
+    instructions: &[Option<(OpCode, usize, usize)>],
+) -> Result<(), LoadError> {
+    let code = &function.chunk.code;
+    // the callee, or this in methods, and the parameters take the first slots
+    let mut stacks = vec![None; code.len()];
+    stacks[0] = Some((function.arity + 1, BTreeSet::new()));
+    let mut pending = vec![0];
+    while let Some(offset) = pending.pop() {
+        let (height, mut captured) = stacks[offset].clone().unwrap();
+        let (op, operand, next) = instructions[offset].unwrap();
+        let slot = match op {
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::AddLocal => Some(operand),
+            OpCode::GetLocalProperty => Some(operand & 0xff),
+            _ => None,
+        };
+        let mut slots = slot.into_iter().collect::<Vec<_>>();
+        if op == OpCode::Closure {
+            let upvalues = code[offset + 3..next].chunks(2);
+            let locals = upvalues.filter(|upvalue| upvalue[0] == 1);
+            let locals = locals
+                .map(|upvalue| upvalue[1] as usize)
+                .collect::<Vec<_>>();
+            slots.extend(&locals);
+            captured.extend(locals);
+        }
+        if let Some(slot) = slots.into_iter().find(|slot| *slot >= height) {
+            return invalid(format!("local {} at {} does not exist", slot, offset));
+        }
+
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::GetLocalProperty
+            | OpCode::Closure
+            | OpCode::Class => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::CloseUpvalue
+            | OpCode::Print
+            | OpCode::PopJumpIfFalse
+            | OpCode::Return => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::AddLocal
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method => (2, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            // calls leave the result where the callee or receiver was
+            OpCode::Call => (operand + 1, 1),
+            OpCode::Invoke => ((operand >> 16 & 0xff) + 1, 1),
+            // and super calls pop the superclass above the arguments as well
+            OpCode::SuperInvoke => ((operand >> 16) + 2, 1),
+        };
+        if pops > height {
+            return invalid(format!("{:?} at {} pops an empty stack", op, offset));
+        }
+        if op == OpCode::CloseUpvalue {
+            captured.remove(&(height - 1));
+        }
+        let height = height - pops + pushes;
+        // the vm reads open upvalues from the stack, until Return closes the ones below the result
+        if captured.range(height..).next().is_some() {
+            return invalid(format!("{:?} at {} pops a captured local", op, offset));
+        }
+
+        let mut successors = Vec::with_capacity(2);
+        if !matches!(op, OpCode::Return | OpCode::Jump | OpCode::Loop) {
+            successors.push(next);
+        }
+        match op {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PopJumpIfFalse => {
+                successors.push(next + operand)
+            }
+            OpCode::Loop => successors.push(next - operand),
+            _ => {}
+        }
+        let stack = (height, captured);
+        for successor in successors {
+            match &stacks[successor] {
+                None => {
+                    stacks[successor] = Some(stack.clone());
+                    pending.push(successor);
+                }
+                Some(other) if *other != stack => {
+                    return invalid(format!("the paths to {} leave different stacks", successor))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid<T>(message: String) -> Result<T, LoadError> {
+    Err(LoadError::InvalidCode(message))
+}
+
+fn write_u32(bytes: &mut Vec<u8>, val: usize) {
+    // the compiler caps chunks far below 4GiB, so lengths always fit
+    bytes.extend_from_slice(&(val as u32).to_le_bytes());
+}
+
+// Reader reads little endian values from the front of a byte slice
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() < len {
+            return Err(LoadError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    // len reads a u32 length or count
+    fn len(&mut self) -> Result<usize, LoadError> {
+        Ok(self.u32()? as usize)
+    }
+
+    // string reads a UTF-8 string preceded by its length
+    fn string(&mut self) -> Result<&'a str, LoadError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| LoadError::InvalidString)
+    }
+}
+
+// crc32 is the CRC-32 used by zip and png, computed bit by bit since files are small
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str, heap: &mut Heap) -> ObjRef {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let statements = Parser::new(scanner.into_parts().0).parse().unwrap();
+        Compiler::compile(&statements, heap, true).unwrap()
+    }
+
+    fn function(heap: &Heap, obj: ObjRef) -> &Function {
+        match heap.get(obj) {
+            Object::Function(function) => function,
+            object => panic!("{:?} is not a function", object),
+        }
+    }
+
+    fn constants(chunk: &Chunk, heap: &Heap) -> Vec<String> {
+        let constants = chunk.constants.iter();
+        constants
+            .map(|value| heap.display(*value).to_string())
+            .collect()
+    }
+
+    // load deserializes bytes on a heap of its own
+    fn load(bytes: &[u8]) -> Result<ObjRef, LoadError> {
+        deserialize(bytes, &mut Heap::new())
+    }
+
+    // corrupt compiles SOURCE, lets change alter the chunk of the script and serializes the result
+    fn corrupt(change: impl FnOnce(&mut Chunk)) -> Vec<u8> {
+        let mut heap = Heap::new();
+        let script = compile(SOURCE, &mut heap);
+        if let Object::Function(function) = heap.get_mut(script) {
+            change(Rc::make_mut(&mut function.chunk));
+        }
+        serialize(script, &heap, true)
+    }
+
+    const SOURCE: &str = "print 1.5 + 2;\nprint \"wörld\" == nil;\nfun f(a) { var b = a; fun g() { return b; } return g; }\nprint !true;";
+
+    #[test]
+    fn crc32_matches_the_reference() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn scripts_round_trip() {
+        let mut heap = Heap::new();
+        let script = compile(SOURCE, &mut heap);
+        let chunk = &function(&heap, script).chunk;
+        let mut loaded_heap = Heap::new();
+        let loaded = deserialize(&serialize(script, &heap, false), &mut loaded_heap).unwrap();
+        let loaded_chunk = &function(&loaded_heap, loaded).chunk;
+        assert_eq!(loaded_chunk.code, chunk.code);
+        assert_eq!(loaded_chunk.lines, chunk.lines);
+        assert_eq!(
+            constants(loaded_chunk, &loaded_heap),
+            ["1.5", "2", "wörld", "f", "<fn f>"].map(String::from)
+        );
+
+        // nested functions keep their name, arity, upvalues and code
+        let f = function(&heap, chunk.constants[4].as_object().unwrap());
+        let loaded_f = function(&loaded_heap, loaded_chunk.constants[4].as_object().unwrap());
+        assert_eq!(loaded_f.name, f.name);
+        assert_eq!(loaded_f.arity, 1);
+        assert_eq!(loaded_f.chunk.code, f.chunk.code);
+        let g = function(
+            &loaded_heap,
+            loaded_f.chunk.constants[0].as_object().unwrap(),
+        );
+        assert_eq!(g.upvalue_count, 1);
+
+        let stripped = deserialize(&serialize(script, &heap, true), &mut loaded_heap).unwrap();
+        let stripped_chunk = &function(&loaded_heap, stripped).chunk;
+        assert_eq!(stripped_chunk.code, chunk.code);
+        assert_eq!(
+            constants(stripped_chunk, &loaded_heap),
+            constants(chunk, &heap)
+        );
+        assert_eq!(stripped_chunk.line(0), 0);
+    }
+
+    #[test]
+    fn deserialize_rejects_bad_headers() {
+        let mut heap = Heap::new();
+        let bytes = serialize(compile(SOURCE, &mut heap), &heap, false);
+        assert_eq!(load(b"print 1;"), Err(LoadError::NotLoxc));
+        assert_eq!(load(&bytes[..6]), Err(LoadError::Truncated));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            load(&newer),
+            Err(LoadError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn deserialize_rejects_corrupt_files() {
+        let mut heap = Heap::new();
+        let bytes = serialize(compile(SOURCE, &mut heap), &heap, false);
+        for index in HEADER_LEN..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[index] ^= 0x40;
+            assert_eq!(
+                load(&corrupt),
+                Err(LoadError::ChecksumMismatch),
+                "flipped a bit at {}",
+                index
+            );
+        }
+        assert_eq!(
+            load(&bytes[..bytes.len() - 1]),
+            Err(LoadError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn deserialize_validates_code() {
+        let invalid = |bytes: Vec<u8>| matches!(load(&bytes), Err(LoadError::InvalidCode(_)));
+        assert!(load(&corrupt(|_| {})).is_ok());
+        // refer to a constant that does not exist
+        assert!(invalid(corrupt(|chunk| chunk.code[1] = 100)));
+        assert!(invalid(corrupt(|chunk| {
+            chunk.code.pop();
+        })));
+        assert!(invalid(corrupt(|chunk| chunk.code.push(200))));
+        // look up a global by a number
+        assert!(invalid(corrupt(|chunk| {
+            let at = chunk.code.len() - 2;
+            chunk.code.splice(at..at, [OpCode::GetGlobal as u8, 0, 0]);
+        })));
+        // jump into the middle of an instruction
+        assert!(invalid(corrupt(|chunk| {
+            chunk.code.splice(0..0, [OpCode::Jump as u8, 1, 0]);
+        })));
+        // read an upvalue the script does not have
+        assert!(invalid(corrupt(|chunk| {
+            chunk.code.splice(0..0, [OpCode::GetUpvalue as u8, 0]);
+        })));
+        // name a class by a number
+        assert!(invalid(corrupt(|chunk| {
+            chunk
+                .code
+                .splice(0..0, [OpCode::Class as u8, 0, 0, OpCode::Pop as u8]);
+        })));
+        // use an inline cache the script does not have
+        assert!(invalid(corrupt(|chunk| {
+            let at = chunk.code.len() - 2;
+            let get = [OpCode::GetProperty as u8, 2, 0, 0, 0];
+            chunk
+                .code
+                .splice(at..at, get.into_iter().chain([OpCode::Pop as u8]));
+        })));
+    }
+
+    #[test]
+    fn deserialize_validates_the_stack() {
+        let code = |code: &[u8]| load(&corrupt(|chunk| chunk.code = code.to_vec()));
+        let invalid = |result| matches!(result, Err(LoadError::InvalidCode(_)));
+        let [get, set, add_local, add, nil, pop, jump_if_false, ret] = [
+            OpCode::GetLocal,
+            OpCode::SetLocal,
+            OpCode::AddLocal,
+            OpCode::Add,
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::PopJumpIfFalse,
+            OpCode::Return,
+        ]
+        .map(|op| op as u8);
+        // the script itself is in slot 0
+        assert!(code(&[get, 0, ret]).is_ok());
+        assert!(code(&[nil, add_local, 1, ret]).is_ok());
+        // read and write locals above the stack
+        assert!(invalid(code(&[get, 200, ret])));
+        assert!(invalid(code(&[nil, set, 50, ret])));
+        assert!(invalid(code(&[nil, add_local, 2, ret])));
+        // pop more than was pushed
+        assert!(invalid(code(&[add, ret])));
+        assert!(invalid(code(&[pop, pop, ret])));
+        // reach the same instruction with different heights
+        assert!(invalid(code(&[nil, jump_if_false, 1, 0, nil, nil, ret])));
+
+        // pop a local a closure captured without closing it
+        let mut heap = Heap::new();
+        let script = compile("{ var x = 1; fun f() { return x; } }", &mut heap);
+        assert!(deserialize(&serialize(script, &heap, true), &mut Heap::new()).is_ok());
+        if let Object::Function(function) = heap.get_mut(script) {
+            let code = &mut Rc::make_mut(&mut function.chunk).code;
+            let close = code.len() - 3;
+            assert_eq!(code[close], OpCode::CloseUpvalue as u8);
+            code[close] = OpCode::Pop as u8;
+        }
+        assert!(invalid(deserialize(
+            &serialize(script, &heap, true),
+            &mut Heap::new()
+        )));
+    }
+
+    #[test]
+    fn caches_round_trip() {
+        let mut heap = Heap::new();
+        let script = compile("class A {}\nvar a = A();\na.x = 1;\nprint a.x;", &mut heap);
+        let mut loaded_heap = Heap::new();
+        let loaded = deserialize(&serialize(script, &heap, false), &mut loaded_heap).unwrap();
+        assert_eq!(function(&loaded_heap, loaded).caches.len(), 2);
+    }
+}
@@ -1,52 +1,112 @@
-extern crate core;
-mod ast;
-mod interpreter;
-mod lox;
-mod scanner;
-mod token;
-mod tokentype;
+// the prompt started without a script
+mod repl;
 
-use crate::lox::Lox;
+use rust_lox::{Backend, Error, Lox};
 use std::env;
-use std::fs::File;
-use std::io::{stdout, Read, Write};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    println!("Hello, world! {:?}", args);
+    let mut lox = Lox::new();
+    let mut args = Vec::new();
+    let mut strip = false;
+    let mut gc_stats = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--strip" => strip = true,
+            "--gc-stress" => lox.set_gc_stress(true),
+            "--gc-stats" => gc_stats = true,
+            "--vm" => lox.set_backend(Backend::Vm),
+            "--dump-ast" => lox.set_dump_ast(true),
+            "--no-optimize" => lox.set_optimize(false),
+            // the debugging flags only apply to the vm, so they select it as well
+            "--disassemble" => {
+                lox.set_backend(Backend::Vm);
+                lox.set_disassemble(true);
+            }
+            "--trace" => {
+                lox.set_backend(Backend::Vm);
+                lox.set_trace(true);
+            }
+            _ => args.push(arg),
+        }
+    }
 
-    if args.len() > 1 {
-        println!("usage: lox-rust [script]");
-        println!("{:}", args[1]);
+    if args.first().map(String::as_str) == Some("compile") {
+        match &args[1..] {
+            [script] => compile_file(
+                script,
+                &Path::new(script).with_extension("loxc"),
+                strip,
+                lox,
+            ),
+            [script, output] => compile_file(script, Path::new(output), strip, lox),
+            _ => usage(),
+        }
+    } else if args.len() > 1 {
+        usage();
     } else if args.len() == 1 {
-        run_file(&args[1]);
+        run_file(&args[0], gc_stats, lox);
     } else {
-        run_prompt();
+        repl::run_prompt(lox);
+    }
+}
+
+fn usage() {
+    println!(
+        "usage: lox-rust [--vm] [--no-optimize] [--dump-ast] [--disassemble] [--trace] [--gc-stress] [--gc-stats] [script]"
+    );
+    println!("       lox-rust compile [--strip] script [output]");
+    std::process::exit(64);
+}
+
+fn compile_file(path: &str, output: &Path, strip: bool, mut lox: Lox) {
+    let contents = fs::read_to_string(path).expect("something went wrong reading the file");
+    match lox.compile(&contents, strip) {
+        Ok(bytes) => fs::write(output, bytes).expect("something went wrong writing the file"),
+        Err(error) => fail(&error),
     }
 }
 
-fn run_file(path: &str) {
+fn run_file(path: &str, gc_stats: bool, mut lox: Lox) {
+    // compiled files are recognised by their extension, so that a source file which happens to
+    // start with the magic number is still read as source
+    if path.ends_with(".loxc") {
+        let bytes = fs::read(path).expect("something went wrong reading the file");
+        // compiled programs always run on the vm
+        lox.set_backend(Backend::Vm);
+        let result = lox.run_compiled(&bytes);
+        if gc_stats {
+            eprintln!("gc: {}", lox.gc_stats());
+        }
+        match result {
+            Err(Error::Load(error)) => {
+                eprintln!("{}: {}", path, error);
+                std::process::exit(65);
+            }
+            Err(error) => fail(&error),
+            Ok(_) => return,
+        }
+    }
     let mut file = File::open(path).expect("file not found");
     let mut contents = String::new();
     file.read_to_string(&mut contents)
         .expect("something went wrong reading the file");
-    let mut lox = Lox::new();
-    lox.run(&contents);
-    if lox.had_error {
-        std::process::exit(65);
+    let result = lox.run(&contents);
+    if gc_stats {
+        eprintln!("gc: {}", lox.gc_stats());
+    }
+    if let Err(error) = result {
+        fail(&error);
     }
 }
 
-fn run_prompt() {
-    let mut lox = Lox::new();
-    loop {
-        print!(">> ");
-        stdout().flush().unwrap();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        lox.run(&input);
-        if lox.had_error {
-            lox.had_error = false;
-        }
+// fail reports an error and exits with the status sysexits.h has for it
+fn fail(error: &Error) -> ! {
+    eprintln!("{}", error);
+    match error {
+        Error::Compile(_) | Error::Load(_) => std::process::exit(65),
+        Error::Runtime(_) | Error::InvalidValue => std::process::exit(70),
     }
 }
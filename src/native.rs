@@ -0,0 +1,164 @@
+use crate::heap::{Heap, ObjRef};
+use crate::object::{NativeMethod, Object};
+use crate::value::Value;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// NativeFn is the Rust side of a native function. An error it returns becomes a runtime error of
+// the program at the call.
+pub(crate) type NativeFn = Rc<dyn Fn(&mut Args) -> Result<Value, String>>;
+
+// ConstructorFn makes the Rust object behind a new instance of a native class
+pub(crate) type ConstructorFn =
+    Rc<dyn Fn(&mut Args) -> Result<Rc<RefCell<dyn NativeClass>>, String>>;
+
+// Args are the arguments a program passed to a native function, with helpers that check their
+// types. There are as many as the native function takes, asking for one past them is an error
+// like a mistyped argument rather than a panic.
+pub struct Args<'a> {
+    heap: &'a mut Heap,
+    values: &'a [Value],
+}
+
+impl<'a> Args<'a> {
+    pub(crate) fn new(heap: &'a mut Heap, values: &'a [Value]) -> Args<'a> {
+        Args { heap, values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // get gives the argument at index, counting from 0, whatever its type
+    pub fn get(&self, index: usize) -> Result<Value, String> {
+        self.values
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("Argument {} is missing.", index + 1))
+    }
+
+    pub fn number(&self, index: usize) -> Result<f64, String> {
+        self.get(index)?
+            .as_number()
+            .ok_or_else(|| mistyped(index, "a number"))
+    }
+
+    pub fn bool(&self, index: usize) -> Result<bool, String> {
+        self.get(index)?
+            .as_bool()
+            .ok_or_else(|| mistyped(index, "a boolean"))
+    }
+
+    pub fn string(&self, index: usize) -> Result<&str, String> {
+        self.heap
+            .as_str(self.get(index)?)
+            .ok_or_else(|| mistyped(index, "a string"))
+    }
+
+    // display shows the argument at index the way print does
+    pub fn display(&self, index: usize) -> Result<String, String> {
+        Ok(self.heap.display(self.get(index)?).to_string())
+    }
+
+    // alloc_string makes a Lox string, for the native function to return
+    pub fn alloc_string(&mut self, text: &str) -> Value {
+        self.heap.alloc_string(text)
+    }
+}
+
+// NativeClass is implemented by Rust types that programs hold instances of, like a file handle.
+// The runtime owns the instances, and drops them once programs no longer refer to them. They must
+// not keep Values, which the garbage collector would not know about.
+pub trait NativeClass: 'static {
+    // arity gives how many arguments the method called name takes, or None if there is no such
+    // method
+    fn arity(&self, method: &str) -> Option<usize>;
+
+    // call runs the method called name with as many arguments as arity said
+    fn call(&mut self, method: &str, args: &mut Args) -> Result<Value, String>;
+
+    // get gives the property called name, if there is one. Properties hide the methods of the
+    // same name. args has no arguments, but makes strings.
+    fn get(&self, name: &str, args: &mut Args) -> Option<Value> {
+        let _ = (name, args);
+        None
+    }
+
+    // set sets the property called name to the only argument
+    fn set(&mut self, name: &str, args: &mut Args) -> Result<(), String> {
+        let _ = args;
+        Err(format!("Can't set property '{}'.", name))
+    }
+}
+
+// userdata gives the Rust object behind value, if it is an instance of a native class
+pub(crate) fn userdata(heap: &Heap, value: Value) -> Option<Rc<RefCell<dyn NativeClass>>> {
+    match heap.get(value.as_object()?) {
+        Object::Userdata(userdata) => Some(Rc::clone(&userdata.object)),
+        _ => None,
+    }
+}
+
+// get_property gives the property name of the instance of a native class receiver, or else its
+// method name bound to it. If the Rust object has neither, the methods that Lox subclasses
+// declare are looked up next.
+pub(crate) fn get_property(
+    heap: &mut Heap,
+    receiver: Value,
+    object: &RefCell<dyn NativeClass>,
+    name: &str,
+) -> Option<Value> {
+    let object = object.borrow();
+    if let Some(value) = object.get(name, &mut Args::new(heap, &[])) {
+        return Some(value);
+    }
+    let method = NativeMethod {
+        receiver: receiver.as_object().unwrap(),
+        name: name.into(),
+        arity: object.arity(name)?,
+    };
+    Some(Value::object(heap.alloc(Object::NativeMethod(method))))
+}
+
+pub(crate) fn set_property(
+    heap: &mut Heap,
+    object: &RefCell<dyn NativeClass>,
+    name: &str,
+    value: Value,
+) -> Result<(), String> {
+    object
+        .borrow_mut()
+        .set(name, &mut Args::new(heap, &[value]))
+}
+
+// call_method calls a method of a native class bound to its instance
+pub(crate) fn call_method(
+    heap: &mut Heap,
+    method: ObjRef,
+    arguments: &[Value],
+) -> Result<Value, String> {
+    let (receiver, name, arity) = match heap.get(method) {
+        Object::NativeMethod(method) => (method.receiver, Rc::clone(&method.name), method.arity),
+        object => panic!("{:?} is not a native method", object),
+    };
+    if arguments.len() != arity {
+        return Err(format!(
+            "Expected {} arguments but got {}.",
+            arity,
+            arguments.len()
+        ));
+    }
+    let object = userdata(heap, Value::object(receiver)).expect("methods are bound to userdata");
+    let result = object
+        .borrow_mut()
+        .call(&name, &mut Args::new(heap, arguments));
+    result
+}
+
+fn mistyped(index: usize, expected: &str) -> String {
+    format!("Argument {} must be {}.", index + 1, expected)
+}
@@ -0,0 +1,323 @@
+use crate::ast;
+use crate::chunk::Chunk;
+use crate::heap::ObjRef;
+use crate::native::{ConstructorFn, NativeClass, NativeFn};
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::mem;
+use std::rc::Rc;
+
+// Object is anything that lives on the heap rather than in a Value. Each backend only creates the
+// kinds of objects it needs, besides the strings and natives they share.
+#[derive(Debug)]
+pub(crate) enum Object {
+    // strings are interned, so there is only ever one object with the same contents
+    String(Rc<str>),
+    Native(Native),
+    // the scopes, functions and classes of the tree-walker
+    Environment(Environment),
+    LoxFunction(LoxFunction),
+    LoxClass(LoxClass),
+    LoxInstance(LoxInstance),
+    // the compiled functions of the vm, the closures made from them and the variables they capture
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    // the classes of the vm, their instances and the shapes of those, and methods bound to an
+    // instance
+    Class(Class),
+    Instance(Instance),
+    Shape(Shape),
+    BoundMethod(BoundMethod),
+    // the instances of native classes and their methods bound to them, on both backends
+    Userdata(Userdata),
+    NativeMethod(NativeMethod),
+}
+
+impl Object {
+    // size is what the object counts towards the size of the heap, on top of its slot. It is taken
+    // when the object is allocated, objects that grow later are not counted again.
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            // the contents are shared with the intern table, which costs a handle and a hash
+            Object::String(val) => val.len() + mem::size_of::<(Rc<str>, ObjRef, u64)>(),
+            Object::Native(_)
+            | Object::LoxFunction(_)
+            | Object::Upvalue(_)
+            | Object::BoundMethod(_)
+            | Object::NativeMethod(_) => 0,
+            Object::Userdata(userdata) => mem::size_of_val(&*userdata.object.borrow()),
+            Object::Environment(environment) => {
+                environment.values.capacity() * mem::size_of::<(Symbol, Value, u64)>()
+            }
+            Object::LoxClass(class) => {
+                class.methods.capacity() * mem::size_of::<(Symbol, ObjRef)>()
+            }
+            Object::LoxInstance(instance) => {
+                instance.fields.capacity() * mem::size_of::<(Symbol, Value, u64)>()
+            }
+            Object::Function(function) => {
+                function.chunk.code.len()
+                    + function.chunk.constants.len() * mem::size_of::<Value>()
+                    + function.chunk.lines.len() * mem::size_of::<(usize, usize)>()
+                    + function.caches.len() * mem::size_of::<Cell<Option<InlineCache>>>()
+            }
+            Object::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+            Object::Class(class) => class.methods.capacity() * mem::size_of::<(ObjRef, ObjRef)>(),
+            Object::Instance(instance) => instance.fields.capacity() * mem::size_of::<Value>(),
+            Object::Shape(shape) => {
+                (shape.fields.capacity() + shape.transitions.capacity())
+                    * mem::size_of::<(ObjRef, usize, u64)>()
+            }
+        }
+    }
+
+    // trace adds the objects this one refers to to gray
+    pub(crate) fn trace(&self, gray: &mut Vec<ObjRef>) {
+        match self {
+            Object::String(_) | Object::Native(_) => {}
+            Object::Userdata(userdata) => gray.push(userdata.class),
+            Object::NativeMethod(method) => gray.push(method.receiver),
+            Object::Environment(environment) => {
+                gray.extend(
+                    environment
+                        .values
+                        .values()
+                        .filter_map(|value| value.as_object()),
+                );
+                gray.extend(environment.enclosing);
+            }
+            Object::LoxFunction(function) => gray.push(function.closure),
+            Object::LoxClass(class) => gray.extend(class.methods.values().copied()),
+            Object::LoxInstance(instance) => {
+                gray.push(instance.class);
+                gray.extend(
+                    instance
+                        .fields
+                        .values()
+                        .filter_map(|value| value.as_object()),
+                );
+            }
+            Object::Function(function) => {
+                let constants = function.chunk.constants.iter();
+                gray.extend(constants.filter_map(|value| value.as_object()));
+                // a cached shape must not be freed, or a new one could take its place and hit
+                for cache in function.caches.iter().filter_map(Cell::get) {
+                    gray.push(cache.shape);
+                    match cache.entry {
+                        CacheEntry::Field(_) => {}
+                        CacheEntry::Method(method) => gray.push(method),
+                        CacheEntry::Transition(shape) => gray.push(shape),
+                    }
+                }
+            }
+            Object::Closure(closure) => {
+                gray.push(closure.function);
+                gray.extend(closure.upvalues.iter().copied());
+            }
+            Object::Upvalue(Upvalue::Closed(value)) => gray.extend(value.as_object()),
+            // open upvalues point into the stack, which is a root
+            Object::Upvalue(Upvalue::Open(_)) => {}
+            Object::Class(class) => {
+                gray.push(class.name);
+                gray.push(class.shape);
+                for (name, method) in &class.methods {
+                    gray.push(*name);
+                    gray.push(*method);
+                }
+            }
+            Object::Instance(instance) => {
+                gray.push(instance.class);
+                gray.push(instance.shape);
+                gray.extend(instance.fields.iter().filter_map(|value| value.as_object()));
+            }
+            Object::Shape(shape) => {
+                gray.extend(shape.fields.keys().copied());
+                for (name, shape) in &shape.transitions {
+                    gray.push(*name);
+                    gray.push(*shape);
+                }
+            }
+            Object::BoundMethod(bound) => {
+                gray.extend(bound.receiver.as_object());
+                gray.push(bound.method);
+            }
+        }
+    }
+}
+
+// Native is a function implemented in Rust, like clock or the functions an embedder defines
+#[derive(Clone)]
+pub(crate) struct Native {
+    pub(crate) name: Rc<str>,
+    pub(crate) arity: usize,
+    pub(crate) function: NativeFn,
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+// Constructor is what a class defined by the embedder makes its instances with, which its Lox
+// subclasses inherit
+#[derive(Clone)]
+pub(crate) struct Constructor {
+    pub(crate) arity: usize,
+    pub(crate) function: ConstructorFn,
+}
+
+impl Debug for Constructor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native constructor>")
+    }
+}
+
+// Userdata is an instance of a native class, a Rust object the runtime owns for programs, and the
+// class it was made by, which is a Lox subclass if one was called
+pub(crate) struct Userdata {
+    pub(crate) class: ObjRef,
+    pub(crate) object: Rc<RefCell<dyn NativeClass>>,
+}
+
+impl Debug for Userdata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<instance of {:?}>", self.class)
+    }
+}
+
+// NativeMethod is a method of a native class bound to an instance of it
+#[derive(Debug)]
+pub(crate) struct NativeMethod {
+    pub(crate) receiver: ObjRef,
+    pub(crate) name: Rc<str>,
+    pub(crate) arity: usize,
+}
+
+// Environment holds the variables of a scope in the tree-walker, and refers to the environment of
+// the scope around it
+#[derive(Debug, Default)]
+pub(crate) struct Environment {
+    pub(crate) enclosing: Option<ObjRef>,
+    pub(crate) values: HashMap<Symbol, Value>,
+}
+
+// LoxFunction is a function of the tree-walker: its declaration and the environment it was
+// declared in. The environment of a method bound to an instance declares this.
+#[derive(Debug)]
+pub(crate) struct LoxFunction {
+    pub(crate) declaration: Rc<ast::Function>,
+    pub(crate) closure: ObjRef,
+    // initializers return this, however they return
+    pub(crate) is_initializer: bool,
+}
+
+// LoxClass is a class of the tree-walker. It has the methods of its superclass besides its own, so
+// looking a method up never walks up the superclasses.
+#[derive(Debug)]
+pub(crate) struct LoxClass {
+    pub(crate) name: Rc<str>,
+    pub(crate) methods: HashMap<Symbol, ObjRef>,
+    // set for the classes the embedder defines and their subclasses
+    pub(crate) constructor: Option<Constructor>,
+}
+
+#[derive(Debug)]
+pub(crate) struct LoxInstance {
+    pub(crate) class: ObjRef,
+    pub(crate) fields: HashMap<Symbol, Value>,
+}
+
+// Function is a function compiled for the vm. The chunk is shared with the call frames running it,
+// so the vm does not need to look the function up on the heap for every instruction.
+#[derive(Debug)]
+pub(crate) struct Function {
+    // None for the script around the top level code
+    pub(crate) name: Option<Rc<str>>,
+    pub(crate) arity: usize,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Rc<Chunk>,
+    // the inline caches of the property accesses and method calls in the code, which the
+    // instructions refer to by index
+    pub(crate) caches: Rc<[Cell<Option<InlineCache>>]>,
+}
+
+// Closure is a function of the vm together with the variables it captured from the functions
+// around it
+#[derive(Debug)]
+pub(crate) struct Closure {
+    pub(crate) function: ObjRef,
+    pub(crate) upvalues: Rc<[ObjRef]>,
+}
+
+// Upvalue is a variable captured by a closure. While the variable is in scope the upvalue points
+// at its slot on the stack, so every closure capturing it sees the same variable; once the
+// variable goes out of scope the value moves into the upvalue.
+#[derive(Debug)]
+pub(crate) enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+// Class is a class of the vm. Like the classes of the tree-walker it has the methods of its
+// superclass besides its own.
+#[derive(Debug)]
+pub(crate) struct Class {
+    // the string holding the name of the class
+    pub(crate) name: ObjRef,
+    // the closures of the methods by the strings holding their name
+    pub(crate) methods: HashMap<ObjRef, ObjRef>,
+    // the shape of the instances of the class that have no fields yet
+    pub(crate) shape: ObjRef,
+    // set for the classes the embedder defines and their subclasses
+    pub(crate) constructor: Option<Constructor>,
+}
+
+// Instance is an instance of a class of the vm. Its shape says which field is in which slot.
+#[derive(Debug)]
+pub(crate) struct Instance {
+    pub(crate) class: ObjRef,
+    pub(crate) shape: ObjRef,
+    pub(crate) fields: Vec<Value>,
+}
+
+// Shape is the layout of the fields of instances of a class, shared by all instances that got
+// the same fields in the same order. Every class has a shape of its own for instances without
+// fields, so a shape also tells the class of an instance apart; and a shape keeps the shapes it
+// turns into once another field is added, so instances built the same way end up sharing one.
+#[derive(Debug, Default)]
+pub(crate) struct Shape {
+    // the slots of the fields by the strings holding their name
+    pub(crate) fields: HashMap<ObjRef, usize>,
+    pub(crate) transitions: HashMap<ObjRef, ObjRef>,
+}
+
+// BoundMethod is a method of the vm taken off an instance, which is this once it is called
+#[derive(Debug)]
+pub(crate) struct BoundMethod {
+    pub(crate) receiver: Value,
+    pub(crate) method: ObjRef,
+}
+
+// InlineCache remembers what a property access or method call found on the last instance it
+// looked at, so the next instance of the same shape skips the lookup
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct InlineCache {
+    pub(crate) shape: ObjRef,
+    pub(crate) entry: CacheEntry,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum CacheEntry {
+    // the slot of a field
+    Field(usize),
+    // a method, when the instance has no field of that name
+    Method(ObjRef),
+    // setting a field the instance does not have yet turns it into this shape, whose new slot
+    // comes after the existing ones
+    Transition(ObjRef),
+}
@@ -0,0 +1,434 @@
+use crate::ast::{Expression, Function, Statement};
+use crate::token::Token;
+use crate::tokentype::{Literal, TokenType};
+use std::rc::Rc;
+
+// optimize rewrites a program into one that does the same with less work at runtime, for both
+// backends:
+//
+// - operators whose operands are all literals are evaluated, like `60 * 60`, `"a" + "b"`,
+//   `!nil` or `false and x`, unless they would fail at runtime, so the error is still reported
+// - branches of an if that can never run are left out, as are loops that never run
+// - statements after a return in the same block are left out, as are expression statements that
+//   are only a literal
+//
+// Dead code is only left out after the resolver has checked the program, so errors in it are still
+// reported.
+pub(crate) fn optimize(mut statements: Vec<Statement>) -> Vec<Statement> {
+    // the last statement gives the value of the program if it is an expression statement, so it
+    // stays even if it is only a literal
+    let last = match statements.last() {
+        Some(Statement::Expression { .. }) => statements.pop(),
+        _ => None,
+    };
+    let mut optimized = block(statements);
+    if let Some(Statement::Expression { expr }) = last {
+        optimized.push(Statement::Expression {
+            expr: expression(expr),
+        });
+    }
+    optimized
+}
+
+// Constant is the value of a literal
+#[derive(Clone, PartialEq)]
+enum Constant {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+}
+
+impl Constant {
+    fn of(expr: &Expression) -> Option<Constant> {
+        let value = match expr {
+            Expression::Literal { value } => value,
+            _ => return None,
+        };
+        match (value.kind, &value.literal) {
+            (TokenType::Nil, _) => Some(Constant::Nil),
+            (TokenType::True, _) => Some(Constant::Bool(true)),
+            (TokenType::False, _) => Some(Constant::Bool(false)),
+            (_, Some(Literal::Number(val))) => Some(Constant::Number(*val)),
+            (_, Some(Literal::String(val))) => Some(Constant::String(val.clone())),
+            _ => None,
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Constant::Nil | Constant::Bool(false))
+    }
+
+    // literal turns the constant back into an expression, placed at token
+    fn literal(self, token: &Token) -> Expression {
+        let (kind, lexeme, literal) = match self {
+            Constant::Nil => (TokenType::Nil, "nil".to_string(), None),
+            Constant::Bool(true) => (TokenType::True, "true".to_string(), None),
+            Constant::Bool(false) => (TokenType::False, "false".to_string(), None),
+            Constant::Number(val) => (
+                TokenType::Number,
+                val.to_string(),
+                Some(Literal::Number(val)),
+            ),
+            Constant::String(val) => (
+                TokenType::String,
+                format!("\"{}\"", val),
+                Some(Literal::String(val)),
+            ),
+        };
+        Expression::Literal {
+            value: Token::new(kind, lexeme.as_str(), literal, token.line, token.column),
+        }
+    }
+}
+
+fn block(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut optimized = Vec::with_capacity(statements.len());
+    for statement in statements {
+        let returns = matches!(statement, Statement::Return { .. });
+        optimized.extend(self::statement(statement));
+        if returns {
+            break;
+        }
+    }
+    optimized
+}
+
+// statement optimizes a single statement, which may leave nothing of it
+fn statement(statement: Statement) -> Option<Statement> {
+    let optimized = match statement {
+        Statement::Block { statements } => Statement::Block {
+            statements: block(statements),
+        },
+        Statement::Class {
+            name,
+            superclass,
+            methods,
+        } => Statement::Class {
+            name,
+            superclass,
+            methods: methods.into_iter().map(function).collect(),
+        },
+        Statement::Expression { expr } => {
+            let expr = expression(expr);
+            if Constant::of(&expr).is_some() {
+                return None;
+            }
+            Statement::Expression { expr }
+        }
+        Statement::Function(function) => Statement::Function(self::function(function)),
+        Statement::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let condition = expression(condition);
+            match Constant::of(&condition) {
+                Some(constant) if constant.is_truthy() => return self::statement(*then_branch),
+                Some(_) => return else_branch.and_then(|branch| self::statement(*branch)),
+                None => Statement::If {
+                    condition,
+                    then_branch: Box::new(nested(*then_branch)),
+                    else_branch: else_branch
+                        .and_then(|branch| self::statement(*branch))
+                        .map(Box::new),
+                },
+            }
+        }
+        Statement::Print { expr } => Statement::Print {
+            expr: expression(expr),
+        },
+        Statement::Return { keyword, value } => Statement::Return {
+            keyword,
+            value: value.map(expression),
+        },
+        Statement::Var { name, initializer } => Statement::Var {
+            name,
+            initializer: initializer.map(expression),
+        },
+        Statement::While { condition, body } => {
+            let condition = expression(condition);
+            if Constant::of(&condition).is_some_and(|constant| !constant.is_truthy()) {
+                return None;
+            }
+            Statement::While {
+                condition,
+                body: Box::new(nested(*body)),
+            }
+        }
+    };
+    Some(optimized)
+}
+
+// nested optimizes a statement that has to stay, like the body of a loop
+fn nested(statement: Statement) -> Statement {
+    self::statement(statement).unwrap_or(Statement::Block {
+        statements: Vec::new(),
+    })
+}
+
+fn function(function: Rc<Function>) -> Rc<Function> {
+    // a function that was just parsed is not shared yet
+    match Rc::try_unwrap(function) {
+        Ok(Function { name, params, body }) => Rc::new(Function {
+            name,
+            params,
+            body: block(body),
+        }),
+        Err(function) => function,
+    }
+}
+
+// Link is what a link of a chain adds to its left operand, see Expression::left
+enum Link {
+    Binary {
+        operator: Token,
+        right: Expression,
+    },
+    Call {
+        paren: Token,
+        arguments: Vec<Expression>,
+    },
+    Get {
+        name: Token,
+    },
+    Logical {
+        operator: Token,
+        right: Expression,
+    },
+}
+
+fn expression(expr: Expression) -> Expression {
+    // chains are taken apart and optimized from their innermost operand out
+    let mut links = Vec::new();
+    let mut expr = expr;
+    let innermost = loop {
+        expr = match expr {
+            Expression::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                links.push(Link::Binary {
+                    operator,
+                    right: *right,
+                });
+                *left
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                links.push(Link::Call { paren, arguments });
+                *callee
+            }
+            Expression::Get { expr, name } => {
+                links.push(Link::Get { name });
+                *expr
+            }
+            Expression::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                links.push(Link::Logical {
+                    operator,
+                    right: *right,
+                });
+                *left
+            }
+            innermost => break innermost,
+        };
+    };
+    let mut expr = operand(innermost);
+    while let Some(link) = links.pop() {
+        expr = match link {
+            Link::Binary { operator, right } => {
+                let right = expression(right);
+                match (Constant::of(&expr), Constant::of(&right)) {
+                    (Some(a), Some(b)) => match binary(&operator, a, b) {
+                        Some(constant) => constant.literal(&operator),
+                        None => Expression::Binary {
+                            left: Box::new(expr),
+                            operator,
+                            right: Box::new(right),
+                        },
+                    },
+                    _ => Expression::Binary {
+                        left: Box::new(expr),
+                        operator,
+                        right: Box::new(right),
+                    },
+                }
+            }
+            Link::Call { paren, arguments } => Expression::Call {
+                callee: Box::new(expr),
+                paren,
+                arguments: arguments.into_iter().map(expression).collect(),
+            },
+            Link::Get { name } => Expression::Get {
+                expr: Box::new(expr),
+                name,
+            },
+            Link::Logical { operator, right } => {
+                let right = expression(right);
+                match Constant::of(&expr) {
+                    // the result is the left operand if it decides the outcome, the right one
+                    // otherwise
+                    Some(constant) if constant.is_truthy() == (operator.kind == TokenType::Or) => {
+                        expr
+                    }
+                    Some(_) => right,
+                    None => Expression::Logical {
+                        left: Box::new(expr),
+                        operator,
+                        right: Box::new(right),
+                    },
+                }
+            }
+        };
+    }
+    expr
+}
+
+// operand optimizes an expression that is not a link of a chain
+fn operand(expr: Expression) -> Expression {
+    match expr {
+        Expression::Assign { id, name, expr } => Expression::Assign {
+            id,
+            name,
+            expr: Box::new(expression(*expr)),
+        },
+        Expression::Grouping { expr } => {
+            let expr = expression(*expr);
+            if Constant::of(&expr).is_some() {
+                return expr;
+            }
+            Expression::Grouping {
+                expr: Box::new(expr),
+            }
+        }
+        Expression::Set {
+            object,
+            name,
+            value,
+        } => Expression::Set {
+            object: Box::new(expression(*object)),
+            name,
+            value: Box::new(expression(*value)),
+        },
+        Expression::Unary { operator, right } => {
+            let right = expression(*right);
+            let folded = match (operator.kind, Constant::of(&right)) {
+                (TokenType::Minus, Some(Constant::Number(val))) => Some(Constant::Number(-val)),
+                (TokenType::Bang, Some(constant)) => Some(Constant::Bool(!constant.is_truthy())),
+                _ => None,
+            };
+            match folded {
+                Some(constant) => constant.literal(&operator),
+                None => Expression::Unary {
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        expr @ (Expression::Literal { .. }
+        | Expression::Super { .. }
+        | Expression::This { .. }
+        | Expression::Variable { .. }) => expr,
+        Expression::Binary { .. }
+        | Expression::Call { .. }
+        | Expression::Get { .. }
+        | Expression::Logical { .. } => unreachable!("links are optimized by expression"),
+    }
+}
+
+// binary evaluates a binary operator on two constants, or gives None if that fails at runtime
+fn binary(operator: &Token, left: Constant, right: Constant) -> Option<Constant> {
+    use Constant::{Bool, Number};
+    let constant = match (operator.kind, &left, &right) {
+        (TokenType::EqualEqual, _, _) => Bool(left == right),
+        (TokenType::BangEqual, _, _) => Bool(left != right),
+        (TokenType::Plus, Constant::String(a), Constant::String(b)) => {
+            // the result is as much part of the source as the strings it is made of
+            Constant::String(format!("{}{}", a, b).into())
+        }
+        (kind, &Number(a), &Number(b)) => match kind {
+            TokenType::Plus => Number(a + b),
+            TokenType::Minus => Number(a - b),
+            TokenType::Star => Number(a * b),
+            TokenType::Slash => Number(a / b),
+            TokenType::Greater => Bool(a > b),
+            TokenType::GreaterEqual => Bool(a >= b),
+            TokenType::Less => Bool(a < b),
+            TokenType::LessEqual => Bool(a <= b),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(constant)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn optimize(source: &str) -> Vec<String> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let statements = Parser::new(scanner.into_parts().0).parse().unwrap();
+        let statements = super::optimize(statements);
+        statements
+            .iter()
+            .map(|statement| statement.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn optimizer_folds_constants() {
+        let cases = [
+            ("print 60 * 60;", "(print 3600)"),
+            ("print (1 + 2) * -3 / 4;", "(print -2.25)"),
+            ("print \"a\" + \"b\" + \"c\";", "(print abc)"),
+            ("print 1 < 2 == !nil;", "(print true)"),
+            ("print \"a\" != \"a\";", "(print false)"),
+            ("print (0 / 0) == (0 / 0);", "(print false)"),
+            ("print nil or 1 and 2;", "(print 2)"),
+            ("print false and x;", "(print false)"),
+            ("print true and x;", "(print x)"),
+            ("print x and 1 + 1;", "(print (and x 2))"),
+            ("print x * (2 * 3);", "(print (* x 6))"),
+            // these fail at runtime, which must still happen
+            ("print 1 + \"a\";", "(print (+ 1 a))"),
+            ("print -\"a\";", "(print (-, a))"),
+            ("print nil < 1;", "(print (< nil 1))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(optimize(source), [expected], "{}", source);
+        }
+    }
+
+    #[test]
+    fn optimizer_removes_dead_code() {
+        assert_eq!(
+            optimize(
+                "if (1 > 2) print 1; else print 2;\nif (nil) print 3;\nwhile (false) print 4;"
+            ),
+            ["(print 2)"]
+        );
+        assert_eq!(
+            optimize("fun f() { print 1; return 2; print 3; }"),
+            ["(fun f () (print 1) (return 2))"]
+        );
+        assert_eq!(
+            optimize("if (x) { 1 + 2; } else if (true) print 3; else print 4;"),
+            ["(if x (block) (print 3))"]
+        );
+        // loops keep a body, even if nothing is left of it
+        assert_eq!(optimize("while (x) if (false) x;"), ["(while x (block))"]);
+        // the last statement is the value of the program
+        assert_eq!(optimize("1;\n2 + 3;"), ["(; 5)"]);
+    }
+}
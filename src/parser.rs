@@ -0,0 +1,762 @@
+use crate::ast::{ExprId, Expression, Function, Statement};
+use crate::token::Token;
+use crate::tokentype::TokenType;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::slice;
+
+// the maximum number of nested expressions and statements, deeper input is rejected instead of
+// overflowing the stack. Operands chained by operators or calls, like in `1 + 2 + 3` or
+// `a.b().c`, are not nested: the parser folds them in a loop, and the passes after it walk such
+// chains with a loop as well.
+const MAX_DEPTH: usize = 128;
+// the maximum height of an expression, which counts the operands chained to it as well as how deep
+// it is nested. Dropping the syntax tree still recurses into chains, but takes much less stack per
+// level than the passes do.
+const MAX_HEIGHT: usize = 10_000;
+// the most arguments a call may pass, and parameters a function may have, so the vm can encode
+// their number in a byte
+pub(crate) const MAX_ARGUMENTS: usize = 255;
+
+#[derive(Debug)]
+pub enum Error {
+    MissingToken(TokenType, Token),
+    UnexpectedToken(Token),
+    TooDeep(Token),
+    TooLong(Token),
+    InvalidAssignmentTarget(Token),
+    TooManyArguments(Token),
+    TooManyParameters(Token),
+}
+
+impl Error {
+    // token returns the token at which the error was found
+    pub fn token(&self) -> &Token {
+        match self {
+            Error::MissingToken(_, token) => token,
+            Error::UnexpectedToken(token) => token,
+            Error::TooDeep(token) => token,
+            Error::TooLong(token) => token,
+            Error::InvalidAssignmentTarget(token) => token,
+            Error::TooManyArguments(token) => token,
+            Error::TooManyParameters(token) => token,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingToken(expected, found) => {
+                // identifiers have no fixed lexeme to quote
+                match expected {
+                    TokenType::Identifier => write!(f, "expected a name but found ")?,
+                    expected => write!(f, "expected '{}' but found ", expected)?,
+                }
+                match found.kind {
+                    TokenType::Eof => write!(f, "the end of the input"),
+                    _ => write!(f, "'{}'", found.lexeme),
+                }
+            }
+            Error::UnexpectedToken(token) if token.kind == TokenType::Eof => {
+                write!(f, "unexpected end of the input")
+            }
+            Error::UnexpectedToken(token) => write!(f, "unexpected token '{}'", token.lexeme),
+            Error::TooDeep(_) => write!(f, "code is nested too deeply"),
+            Error::TooLong(_) => write!(f, "expression is too long"),
+            Error::InvalidAssignmentTarget(_) => write!(f, "invalid assignment target"),
+            Error::TooManyArguments(_) => {
+                write!(f, "can't have more than {} arguments", MAX_ARGUMENTS)
+            }
+            Error::TooManyParameters(_) => {
+                write!(f, "can't have more than {} parameters", MAX_ARGUMENTS)
+            }
+        }
+    }
+}
+
+type Errors = Vec<Error>;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    // for every token, whether the scanner dropped an error token right before it
+    after_error: Vec<bool>,
+    current: usize,
+    // how many expressions and statements we are currently nested in, see MAX_DEPTH
+    depth: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        // the scanner already reported the errors behind any error tokens, so we drop them here
+        // instead of reporting them again as unexpected tokens
+        let mut kept = Vec::with_capacity(tokens.len());
+        let mut after_error = Vec::with_capacity(tokens.len());
+        let mut skipped = false;
+        for token in tokens {
+            if token.kind == TokenType::Error {
+                skipped = true;
+                continue;
+            }
+            kept.push(token);
+            after_error.push(skipped);
+            skipped = false;
+        }
+        Parser {
+            tokens: kept,
+            after_error,
+            current: 0,
+            depth: 0,
+        }
+    }
+
+    // parse parses a whole program. When a statement contains an error we skip to the start of the
+    // next statement and keep going, so all errors in the program are reported at once.
+    // Errors in statements that contained an error token are left out, as they are most likely
+    // caused by the scanner error, which has been reported already. This means parse can fail
+    // without returning any errors.
+    pub fn parse(&mut self) -> Result<Vec<Statement>, Errors> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        let mut failed = false;
+        while !self.is_at_end() {
+            let start = self.current;
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(statement_errors) => {
+                    failed = true;
+                    if !self.after_error[start..=self.current].contains(&true) {
+                        errors.extend(statement_errors);
+                    }
+                    self.recover(start);
+                }
+            }
+        }
+        if failed {
+            Err(errors)
+        } else {
+            Ok(statements)
+        }
+    }
+
+    fn declaration(&mut self) -> Result<Statement, Errors> {
+        if self.check_and_consume(&[TokenType::Class]) {
+            return self.class_declaration();
+        }
+        if self.check_and_consume(&[TokenType::Fun]) {
+            return Ok(Statement::Function(self.function()?));
+        }
+        if self.check_and_consume(&[TokenType::Var]) {
+            return self.var_declaration();
+        }
+        self.statement()
+    }
+
+    fn class_declaration(&mut self) -> Result<Statement, Errors> {
+        let name = self.consume(TokenType::Identifier)?.clone();
+        let mut superclass = None;
+        if self.check_and_consume(&[TokenType::Less]) {
+            superclass = Some(Expression::Variable {
+                id: ExprId::next(),
+                name: self.consume(TokenType::Identifier)?.clone(),
+            });
+        }
+        self.consume(TokenType::LeftBrace)?;
+        let mut methods = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.nested(Self::function)?);
+        }
+        self.consume(TokenType::RightBrace)?;
+        Ok(Statement::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    // function parses the name, parameters and body of a function or method, after the fun keyword
+    // if there is one
+    fn function(&mut self) -> Result<Rc<Function>, Errors> {
+        let name = self.consume(TokenType::Identifier)?.clone();
+        self.consume(TokenType::LeftParen)?;
+        let mut params = Vec::new();
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    return Err(vec![Error::TooManyParameters(self.peek().clone())]);
+                }
+                params.push(self.consume(TokenType::Identifier)?.clone());
+                if !self.check_and_consume(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen)?;
+        self.consume(TokenType::LeftBrace)?;
+        let body = self.nested(Self::block)?;
+        Ok(Rc::new(Function { name, params, body }))
+    }
+
+    fn var_declaration(&mut self) -> Result<Statement, Errors> {
+        let name = self.consume(TokenType::Identifier)?.clone();
+        let mut initializer = None;
+        if self.check_and_consume(&[TokenType::Equal]) {
+            initializer = Some(self.expression()?);
+        }
+        self.consume(TokenType::Semicolon)?;
+        Ok(Statement::Var { name, initializer })
+    }
+
+    fn statement(&mut self) -> Result<Statement, Errors> {
+        if self.check_and_consume(&[TokenType::For]) {
+            return self.for_statement();
+        }
+        if self.check_and_consume(&[TokenType::If]) {
+            return self.if_statement();
+        }
+        if self.check_and_consume(&[TokenType::Print]) {
+            return self.print_statement();
+        }
+        if self.check_and_consume(&[TokenType::Return]) {
+            return self.return_statement();
+        }
+        if self.check_and_consume(&[TokenType::While]) {
+            return self.while_statement();
+        }
+        if self.check_and_consume(&[TokenType::LeftBrace]) {
+            let statements = self.nested(Self::block)?;
+            return Ok(Statement::Block { statements });
+        }
+        self.expression_statement()
+    }
+
+    // for_statement desugars a for loop into a while loop, so the backends never see one:
+    //
+    // { initializer; while (condition) { body; increment; } }
+    fn for_statement(&mut self) -> Result<Statement, Errors> {
+        self.consume(TokenType::LeftParen)?;
+        let initializer = if self.check_and_consume(&[TokenType::Semicolon]) {
+            None
+        } else if self.check_and_consume(&[TokenType::Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let condition = if self.check(&TokenType::Semicolon) {
+            // a missing condition is always true
+            let token = self.peek();
+            let token = Token::new(TokenType::True, "true", None, token.line, token.column);
+            Expression::Literal { value: token }
+        } else {
+            self.expression()?
+        };
+        self.consume(TokenType::Semicolon)?;
+
+        let increment = if self.check(&TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen)?;
+
+        let mut body = self.nested(Self::statement)?;
+        if let Some(expr) = increment {
+            body = Statement::Block {
+                statements: vec![body, Statement::Expression { expr }],
+            };
+        }
+        let mut statement = Statement::While {
+            condition,
+            body: Box::new(body),
+        };
+        if let Some(initializer) = initializer {
+            statement = Statement::Block {
+                statements: vec![initializer, statement],
+            };
+        }
+        Ok(statement)
+    }
+
+    fn if_statement(&mut self) -> Result<Statement, Errors> {
+        self.consume(TokenType::LeftParen)?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen)?;
+        let then_branch = Box::new(self.nested(Self::statement)?);
+        let mut else_branch = None;
+        if self.check_and_consume(&[TokenType::Else]) {
+            else_branch = Some(Box::new(self.nested(Self::statement)?));
+        }
+        Ok(Statement::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn print_statement(&mut self) -> Result<Statement, Errors> {
+        let expr = self.expression()?;
+        self.consume(TokenType::Semicolon)?;
+        Ok(Statement::Print { expr })
+    }
+
+    fn return_statement(&mut self) -> Result<Statement, Errors> {
+        let keyword = self.previous().clone();
+        let mut value = None;
+        if !self.check(&TokenType::Semicolon) {
+            value = Some(self.expression()?);
+        }
+        self.consume(TokenType::Semicolon)?;
+        Ok(Statement::Return { keyword, value })
+    }
+
+    fn while_statement(&mut self) -> Result<Statement, Errors> {
+        self.consume(TokenType::LeftParen)?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen)?;
+        let body = Box::new(self.nested(Self::statement)?);
+        Ok(Statement::While { condition, body })
+    }
+
+    // block parses the declarations up to the closing brace of a block, whose opening brace has
+    // been consumed. Like parse, it keeps going after an error to report the errors of every
+    // declaration in the block.
+    fn block(&mut self) -> Result<Vec<Statement>, Errors> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            let start = self.current;
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(declaration_errors) => {
+                    errors.extend(declaration_errors);
+                    self.recover(start);
+                }
+            }
+        }
+        if let Err(error) = self.consume(TokenType::RightBrace) {
+            errors.extend(error);
+        }
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn expression_statement(&mut self) -> Result<Statement, Errors> {
+        let expr = self.expression()?;
+        self.consume(TokenType::Semicolon)?;
+        Ok(Statement::Expression { expr })
+    }
+
+    fn expression(&mut self) -> Result<Expression, Errors> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> Result<Expression, Errors> {
+        let expr = self.binary(0)?;
+
+        if self.check_and_consume(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = self.nested(Self::assignment)?;
+            return match expr {
+                Expression::Variable { name, .. } => Ok(Expression::Assign {
+                    id: ExprId::next(),
+                    name,
+                    expr: Box::new(value),
+                }),
+                Expression::Get { expr, name } => Ok(Expression::Set {
+                    object: expr,
+                    name,
+                    value: Box::new(value),
+                }),
+                _ => Err(vec![Error::InvalidAssignmentTarget(equals)]),
+            };
+        }
+        Ok(expr)
+    }
+
+    // binary parses the binary and logical operators of an expression by precedence climbing:
+    // the operators that bind at least as tightly as precedence are folded into the expression
+    // from left to right, and the right operand of each takes the operators binding tighter than
+    // it, so `1 + 2 * 3 - 4` becomes `(1 + (2 * 3)) - 4`
+    fn binary(&mut self, precedence: u8) -> Result<Expression, Errors> {
+        let mut res = self.unary()?;
+        let mut height = None;
+
+        while let Some(binds) = binding(&self.peek().kind).filter(|binds| *binds >= precedence) {
+            let operator = self.advance().clone();
+            let right = self.binary(binds + 1)?;
+            if !self.fold(&mut height, &res, slice::from_ref(&right)) {
+                return Err(vec![Error::TooLong(operator)]);
+            }
+            let (left, right) = (Box::new(res), Box::new(right));
+            res = match operator.kind {
+                TokenType::Or | TokenType::And => Expression::Logical {
+                    left,
+                    operator,
+                    right,
+                },
+                _ => Expression::Binary {
+                    left,
+                    operator,
+                    right,
+                },
+            };
+        }
+        Ok(res)
+    }
+
+    fn unary(&mut self) -> Result<Expression, Errors> {
+        if self.check_and_consume(&[TokenType::Bang, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Self::unary)?;
+            return Ok(Expression::Unary {
+                operator,
+                right: Box::new(right),
+            });
+        }
+
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Expression, Errors> {
+        let mut res = self.primary()?;
+        let mut height = None;
+
+        while self.check_and_consume(&[TokenType::LeftParen, TokenType::Dot]) {
+            if self.previous().kind == TokenType::Dot {
+                let name = self.consume(TokenType::Identifier)?.clone();
+                if !self.fold(&mut height, &res, &[]) {
+                    return Err(vec![Error::TooLong(name)]);
+                }
+                res = Expression::Get {
+                    expr: Box::new(res),
+                    name,
+                };
+                continue;
+            }
+            let mut arguments = Vec::new();
+            if !self.check(&TokenType::RightParen) {
+                loop {
+                    if arguments.len() >= MAX_ARGUMENTS {
+                        return Err(vec![Error::TooManyArguments(self.peek().clone())]);
+                    }
+                    arguments.push(self.expression()?);
+                    if !self.check_and_consume(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            let paren = self.consume(TokenType::RightParen)?.clone();
+            if !self.fold(&mut height, &res, &arguments) {
+                return Err(vec![Error::TooLong(paren)]);
+            }
+            res = Expression::Call {
+                callee: Box::new(res),
+                paren,
+                arguments,
+            };
+        }
+        Ok(res)
+    }
+
+    fn primary(&mut self) -> Result<Expression, Errors> {
+        if self.check_and_consume(&[TokenType::False]) {
+            return Ok(Expression::Literal {
+                value: self.previous().clone(),
+            });
+        }
+        if self.check_and_consume(&[TokenType::True]) {
+            return Ok(Expression::Literal {
+                value: self.previous().clone(),
+            });
+        }
+        if self.check_and_consume(&[TokenType::Nil]) {
+            return Ok(Expression::Literal {
+                value: self.previous().clone(),
+            });
+        }
+
+        if self.check_and_consume(&[TokenType::Number, TokenType::String]) {
+            return Ok(Expression::Literal {
+                value: self.previous().clone(),
+            });
+        }
+
+        if self.check_and_consume(&[TokenType::This]) {
+            return Ok(Expression::This {
+                id: ExprId::next(),
+                keyword: self.previous().clone(),
+            });
+        }
+
+        if self.check_and_consume(&[TokenType::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Dot)?;
+            let method = self.consume(TokenType::Identifier)?.clone();
+            return Ok(Expression::Super {
+                id: ExprId::next(),
+                keyword,
+                method,
+            });
+        }
+
+        if self.check_and_consume(&[TokenType::Identifier]) {
+            return Ok(Expression::Variable {
+                id: ExprId::next(),
+                name: self.previous().clone(),
+            });
+        }
+
+        if self.check_and_consume(&[TokenType::LeftParen]) {
+            let expr = self.expression()?;
+            self.consume(TokenType::RightParen)?;
+            return Ok(Expression::Grouping {
+                expr: Box::new(expr),
+            });
+        }
+        Err(vec![Error::UnexpectedToken(self.peek().clone())])
+    }
+
+    // nested parses a sub expression or statement with parse, keeping track of how deep we are so
+    // that deeply nested input results in an error instead of a stack overflow
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, Errors>) -> Result<T, Errors> {
+        if self.depth >= MAX_DEPTH {
+            return Err(vec![Error::TooDeep(self.peek().clone())]);
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    // fold works out the height of the expression a loop builds by folding right into left, whose
+    // height is known once the loop folded something already, and returns whether that is within
+    // MAX_HEIGHT
+    fn fold(&self, height: &mut Option<usize>, left: &Expression, right: &[Expression]) -> bool {
+        let left = height.unwrap_or_else(|| left.height());
+        let folded = right.iter().map(Expression::height).fold(left, usize::max) + 1;
+        *height = Some(folded);
+        self.depth + folded <= MAX_HEIGHT
+    }
+
+    // same as match from the book, however match is reserved in rust
+    // checks whether any of the token_types match the current token
+    fn check_and_consume(&mut self, token_types: &[TokenType]) -> bool {
+        for tt in token_types {
+            if self.check(tt) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn check(&self, token_type: &TokenType) -> bool {
+        if self.is_at_end() {
+            return false;
+        }
+        &self.peek().kind == token_type
+    }
+
+    fn consume(&mut self, expected: TokenType) -> Result<&Token, Errors> {
+        if self.peek().kind == expected {
+            return Ok(self.advance());
+        }
+        Err(vec![Error::MissingToken(expected, self.peek().clone())])
+    }
+
+    // recover skips to the next statement after the declaration that started at start failed. A
+    // declaration that failed in a block it parsed to the end has recovered inside that block
+    // already, so we carry on right after it.
+    fn recover(&mut self, start: usize) {
+        if self.current > start && self.previous().kind == TokenType::RightBrace {
+            return;
+        }
+        self.synchronize();
+    }
+
+    fn synchronize(&mut self) {
+        self.advance();
+
+        while !self.is_at_end() {
+            if self.previous().kind == TokenType::Semicolon {
+                return;
+            }
+
+            match self.peek().kind {
+                TokenType::Class => return,
+                TokenType::Fun => return,
+                TokenType::Var => return,
+                TokenType::For => return,
+                TokenType::If => return,
+                TokenType::While => return,
+                TokenType::Print => return,
+                TokenType::Return => return,
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    // advance consumes the current token and returns it. Once we reach the end we keep returning
+    // the Eof token.
+    fn advance(&mut self) -> &Token {
+        if self.is_at_end() {
+            return self.peek();
+        }
+        self.current += 1;
+        self.previous()
+    }
+
+    // peek should never panic on unwrap, since we only iterate over the indices of the vector
+    // if we were exposing advance and previous, this should have some more checks or a default value
+    fn peek(&self) -> &Token {
+        self.tokens.get(self.current).unwrap()
+    }
+
+    // previous should never panic on unwrap, since we only iterate over the indices of the vector
+    // if we were exposing advance and previous, this should have some more checks or a default value
+    fn previous(&self) -> &Token {
+        self.tokens.get(self.current - 1).unwrap()
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().kind == TokenType::Eof
+    }
+}
+
+// binding is how tightly the binary operator kind binds its operands, from or binding the least
+// to multiplication and division binding the most
+fn binding(kind: &TokenType) -> Option<u8> {
+    match kind {
+        TokenType::Or => Some(0),
+        TokenType::And => Some(1),
+        TokenType::BangEqual | TokenType::EqualEqual => Some(2),
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            Some(3)
+        }
+        TokenType::Minus | TokenType::Plus => Some(4),
+        TokenType::Slash | TokenType::Star => Some(5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Result<Vec<Statement>, Errors> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        Parser::new(scanner.into_parts().0).parse()
+    }
+
+    fn parse_expression(source: &str) -> String {
+        match parse(&format!("{};", source)).unwrap().as_slice() {
+            [Statement::Expression { expr }] => expr.to_string(),
+            statements => panic!("expected a single expression, got {:?}", statements),
+        }
+    }
+
+    #[test]
+    fn parser_skips_error_tokens() {
+        let mut scanner = Scanner::new("1 + # 2 @;");
+        scanner.scan_tokens();
+        assert_eq!(scanner.errors().len(), 2);
+
+        let mut parser = Parser::new(scanner.tokens().to_vec());
+        let statements = parser
+            .parse()
+            .expect("error tokens should not reach the parser");
+        assert_eq!(statements.len(), 1);
+
+        // the missing operand is the scanner's fault, which it has reported already
+        let errors = parse("print 0x;\nprint 1 +;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].token().line, 2);
+    }
+
+    #[test]
+    fn parser_respects_precedence() {
+        assert_eq!(parse_expression("1 + 2 * 3"), "(+ 1 (* 2 3))");
+        assert_eq!(
+            parse_expression("-1 < 2 == !true"),
+            "(== (< (-, 1) 2) (!, true))"
+        );
+        assert_eq!(parse_expression("(1 - 2) / 3"), "(/ (group (- 1 2)) 3)");
+    }
+
+    #[test]
+    fn parser_parses_classes() {
+        assert_eq!(parse_expression("a.b.c"), "(. (. a b) c)");
+        assert_eq!(
+            parse_expression("a.b(1).c = 2"),
+            "(= (. (call (. a b) 1) c) 2)"
+        );
+        assert_eq!(parse_expression("super.m(this)"), "(call (super m) this)");
+        let statements = parse("class B < A { init(x) { this.x = x; } get() {} }").unwrap();
+        assert_eq!(
+            statements[0].to_string(),
+            "(class B < A (fun init (x) (; (= (. this x) x))) (fun get ()))"
+        );
+        assert!(parse("super;").is_err());
+        assert!(parse("class A { var a; }").is_err());
+    }
+
+    #[test]
+    fn parser_parses_statements() {
+        let statements = parse("print 1;\n2 + 3;").unwrap();
+        assert!(matches!(statements[0], Statement::Print { .. }));
+        assert!(matches!(statements[1], Statement::Expression { .. }));
+    }
+
+    #[test]
+    fn parser_recovers_from_errors() {
+        for source in ["1", "1 +;", ") 1 2 3;", "(1;", "!"] {
+            assert!(parse(source).is_err(), "{} should not parse", source);
+        }
+
+        // every broken statement is reported once, and parsing continues after it
+        let errors = parse("print 1 +;\nprint 2;\nprint (3;\n").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.token().line).collect();
+        assert_eq!(lines, [1, 3]);
+    }
+
+    #[test]
+    fn parser_rejects_deep_nesting() {
+        let source = format!("{}1{};", "(".repeat(10_000), ")".repeat(10_000));
+        assert!(matches!(
+            parse(&source).unwrap_err().first(),
+            Some(Error::TooDeep(_))
+        ));
+        assert!(matches!(
+            parse(&format!("{}1;", "-".repeat(10_000)))
+                .unwrap_err()
+                .first(),
+            Some(Error::TooDeep(_))
+        ));
+        assert!(parse(&format!("{}1{};", "(".repeat(50), ")".repeat(50))).is_ok());
+
+        // chains of operators and calls are not nested, only their height is limited
+        for chain in [" + 1", " or 1", " < 1", " * 1", "()", ".x"] {
+            let source = format!("print 1{};", chain.repeat(MAX_HEIGHT));
+            assert!(
+                matches!(parse(&source).unwrap_err().first(), Some(Error::TooLong(_))),
+                "{}",
+                chain
+            );
+            assert!(parse(&format!("print 1{};", chain.repeat(2_000))).is_ok());
+        }
+        let source = format!(
+            "print {}1{}{};",
+            "(".repeat(100),
+            " + 1".repeat(1_000),
+            ")".repeat(100)
+        );
+        assert!(parse(&source).is_ok());
+    }
+}
@@ -0,0 +1,382 @@
+use rust_lox::{keywords, Category, Lox, Scanner, Value};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::time::Instant;
+
+// the file in the home directory that keeps the lines typed in earlier sessions
+const HISTORY_FILE: &str = ".lox_history";
+
+// the ANSI colors input and values are painted with
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const BLUE: &str = "34";
+const MAGENTA: &str = "35";
+const CYAN: &str = "36";
+const GRAY: &str = "90";
+
+// the meta-commands of the prompt, with the argument they take and what they do
+const COMMANDS: [(&str, &str, &str); 8] = [
+    (":help", "", "list the commands"),
+    (":env", "", "show the global variables and their values"),
+    (
+        ":type",
+        "expr",
+        "show the type of the value of an expression",
+    ),
+    (
+        ":ast",
+        "code",
+        "show the syntax tree of code as the parser left it",
+    ),
+    (":tokens", "code", "show the tokens of code"),
+    (":load", "file", "run a script"),
+    (
+        ":reset",
+        "",
+        "forget every global, as if the prompt was started again",
+    ),
+    (":time", "code", "run code and show how long it took"),
+];
+
+// run_prompt runs every line typed as a program and prints the value of expression statements.
+// Lines that leave a block, a call or a string open are continued on the next line, until it is
+// complete or an empty line is typed. Lines can be edited, earlier ones are kept in the history
+// and tab completes keywords and globals. Ctrl-C drops the input typed so far, Ctrl-D quits. Lines
+// starting with a colon are meta-commands, :help lists them. Input and values are colored when
+// the prompt runs in a terminal.
+pub(crate) fn run_prompt(mut lox: Lox) {
+    let color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let mut editor = match Editor::<LoxHelper, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            eprintln!("could not start the prompt: {}", error);
+            std::process::exit(74);
+        }
+    };
+    editor.set_helper(Some(LoxHelper {
+        globals: Vec::new(),
+        color,
+    }));
+    let history = history_file();
+    if let Some(history) = &history {
+        // there is no history the first time
+        let _ = editor.load_history(history);
+    }
+
+    let mut input = String::new();
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.globals = lox.globals().into_iter().map(|(name, _)| name).collect();
+        }
+        let prompt = if input.is_empty() { ">> " } else { ".. " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("{}", error);
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        let continued = !input.is_empty();
+        if !continued && line.trim_start().starts_with(':') {
+            command(&mut lox, &line, color);
+            continue;
+        }
+        input.push_str(&line);
+        input.push('\n');
+        if is_incomplete(&input) && !(continued && line.trim().is_empty()) {
+            continue;
+        }
+        if let Some(value) = evaluate(&mut lox, &input) {
+            println!("{}", painted(&lox, value, color));
+        }
+        input.clear();
+    }
+
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("could not save the history: {}", error);
+        }
+    }
+}
+
+// command runs a meta-command, a line starting with a colon
+fn command(lox: &mut Lox, line: &str, color: bool) {
+    let line = line.trim();
+    let (name, argument) = match line.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (line, ""),
+    };
+    let Some((_, parameter, _)) = COMMANDS.iter().find(|(command, _, _)| *command == name) else {
+        eprintln!("unknown command {}, :help lists the commands", name);
+        return;
+    };
+    if !parameter.is_empty() && argument.is_empty() {
+        eprintln!("usage: {} {}", name, parameter);
+        return;
+    }
+    match name {
+        ":help" => {
+            for (command, parameter, description) in COMMANDS {
+                let usage = format!("{} {}", command, parameter);
+                println!("{:<14}{}", usage, description);
+            }
+        }
+        ":env" => {
+            for (name, value) in lox.globals() {
+                println!("{} = {}", name, painted(lox, value, color));
+            }
+        }
+        ":type" => {
+            if let Some(value) = evaluate(lox, &statement(argument)) {
+                println!("{}", lox.type_name(value));
+            }
+        }
+        ":ast" => match lox.syntax(&statement(argument)) {
+            Ok(trees) => {
+                for tree in trees {
+                    println!("{}", tree);
+                }
+            }
+            Err(error) => eprintln!("{}", error),
+        },
+        ":tokens" => {
+            for token in lox.tokens(argument) {
+                println!("{}", token);
+            }
+        }
+        ":load" => match fs::read_to_string(argument) {
+            Ok(source) => {
+                if let Err(error) = lox.run(&source) {
+                    eprintln!("{}", error);
+                }
+            }
+            Err(error) => eprintln!("could not read {}: {}", argument, error),
+        },
+        ":reset" => lox.reset(),
+        ":time" => {
+            let start = Instant::now();
+            let value = evaluate(lox, &statement(argument));
+            let elapsed = start.elapsed();
+            if let Some(value) = value {
+                println!("{}", painted(lox, value, color));
+            }
+            println!("took {:?}", elapsed);
+        }
+        _ => unreachable!("{} is in COMMANDS", name),
+    }
+}
+
+// evaluate runs source and gives the value of its expression statement, if it ends with one,
+// reporting errors
+fn evaluate(lox: &mut Lox, source: &str) -> Option<Value> {
+    lox.evaluate(source).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        None
+    })
+}
+
+// statement makes a statement of the code typed after a command, which usually leaves off the
+// semicolon of an expression
+fn statement(code: &str) -> String {
+    let code = code.trim_end();
+    if code.ends_with(';') || code.ends_with('}') {
+        code.to_string()
+    } else {
+        format!("{};", code)
+    }
+}
+
+// painted shows a value like print does, colored by its type if color is set
+fn painted(lox: &Lox, value: Value, color: bool) -> String {
+    let text = lox.display(value);
+    if !color {
+        return text;
+    }
+    let code = match lox.type_name(value) {
+        "string" => GREEN,
+        "number" | "boolean" | "nil" => YELLOW,
+        "instance" => BLUE,
+        _ => CYAN,
+    };
+    paint(&text, code)
+}
+
+fn paint(text: &str, code: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+// highlight colors the keywords, literals, operators and comments of a line of input
+fn highlight(line: &str) -> String {
+    let mut scanner = Scanner::new(line);
+    scanner.scan_tokens();
+    let mut highlighted = String::with_capacity(line.len());
+    let mut end = 0;
+    for (range, category) in scanner.highlights() {
+        highlighted.push_str(&line[end..range.start]);
+        let text = &line[range.clone()];
+        match category {
+            Category::Keyword => highlighted.push_str(&paint(text, MAGENTA)),
+            Category::Literal => highlighted.push_str(&paint(text, GREEN)),
+            Category::Operator => highlighted.push_str(&paint(text, CYAN)),
+            Category::Comment => highlighted.push_str(&paint(text, GRAY)),
+            Category::Identifier | Category::Punctuation => highlighted.push_str(text),
+        }
+        end = range.end;
+    }
+    highlighted.push_str(&line[end..]);
+    highlighted
+}
+
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    scanner.scan_tokens();
+    scanner.is_incomplete()
+}
+
+fn history_file() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(HISTORY_FILE))
+}
+
+// LoxHelper completes the word before the cursor with the keywords and the globals of the
+// program, and colors the input if color is set
+struct LoxHelper {
+    globals: Vec<String>,
+    color: bool,
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // the name of a command is completed as a whole, colon included
+        if line.starts_with(':') && !line[..pos].contains(char::is_whitespace) {
+            return Ok((0, commands(&line[..pos])));
+        }
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |index| index + 1);
+        let word = &line[start..pos];
+        Ok((start, completions(word, &self.globals)))
+    }
+}
+
+// completions gives the keywords and globals that start with word, sorted
+fn completions(word: &str, globals: &[String]) -> Vec<String> {
+    if word.is_empty() {
+        return Vec::new();
+    }
+    let mut candidates: Vec<String> = keywords()
+        .map(String::from)
+        .chain(globals.iter().cloned())
+        .filter(|candidate| candidate.starts_with(word))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+// commands gives the meta-commands that start with word
+fn commands(word: &str) -> Vec<String> {
+    COMMANDS
+        .iter()
+        .map(|(command, _, _)| command.to_string())
+        .filter(|command| command.starts_with(word))
+        .collect()
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {
+    fn highlight<'l>(&self, line: &'l str, _: usize) -> Cow<'l, str> {
+        if self.color {
+            Cow::Owned(highlight(line))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    // every character typed can change the color of the line, like a quote opening a string
+    fn highlight_char(&self, _: &str, _: usize, _: bool) -> bool {
+        self.color
+    }
+}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_include_keywords_and_globals() {
+        let globals = ["counter".to_string(), "clock".to_string()];
+        assert_eq!(completions("c", &globals), ["class", "clock", "counter"]);
+        assert_eq!(completions("wh", &globals), ["while"]);
+        assert!(completions("", &globals).is_empty());
+    }
+
+    #[test]
+    fn commands_complete_by_name() {
+        assert_eq!(commands(":t"), [":type", ":tokens", ":time"]);
+        assert_eq!(commands(":"), COMMANDS.map(|(command, _, _)| command));
+    }
+
+    #[test]
+    fn code_after_commands_becomes_a_statement() {
+        assert_eq!(statement("1 + 2"), "1 + 2;");
+        assert_eq!(statement("f(); "), "f();");
+        assert_eq!(statement("{ print 1; }"), "{ print 1; }");
+    }
+
+    #[test]
+    fn input_is_colored_by_category() {
+        assert_eq!(
+            highlight("var a = 1; // one"),
+            "\x1b[35mvar\x1b[0m a \x1b[36m=\x1b[0m \x1b[32m1\x1b[0m; \x1b[90m// one\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn values_are_colored_by_type() {
+        let mut lox = Lox::new();
+        let value = lox.run("\"a\";").unwrap();
+        assert_eq!(painted(&lox, value, false), "a");
+        assert_eq!(painted(&lox, value, true), "\x1b[32ma\x1b[0m");
+        let value = lox.run("1;").unwrap();
+        assert_eq!(painted(&lox, value, true), "\x1b[33m1\x1b[0m");
+    }
+
+    #[test]
+    fn input_is_incomplete_until_everything_is_closed() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(!is_incomplete("fun f() {\n}\n"));
+    }
+}
@@ -0,0 +1,316 @@
+use crate::ast::{ExprId, Expression, Function, Statement};
+use crate::compiler::CompileError;
+use crate::symbol::Symbol;
+use crate::token::Token;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ClassKind {
+    None,
+    Class,
+    Subclass,
+}
+
+// Resolver works out which declaration every variable in a program refers to before the
+// tree-walker runs it. The tree-walker looks a local variable up as many environments out as the
+// resolver found it, so a closure keeps seeing the variables that were in scope where it was
+// declared, even when a variable of the same name is declared later. Globals are looked up by name.
+//
+// The resolver reports the same scoping errors as the compiler of the vm.
+pub(crate) struct Resolver<'a> {
+    // the block and function scopes we are in, innermost last. A variable maps to false from its
+    // declaration until its initializer has been resolved.
+    scopes: Vec<HashMap<Symbol, bool>>,
+    function: FunctionKind,
+    class: ClassKind,
+    // how many scopes out each local variable reference was found
+    locals: &'a mut HashMap<ExprId, usize>,
+    errors: Vec<CompileError>,
+}
+
+impl Resolver<'_> {
+    pub(crate) fn resolve(
+        statements: &[Statement],
+        locals: &mut HashMap<ExprId, usize>,
+    ) -> Result<(), Vec<CompileError>> {
+        let mut resolver = Resolver {
+            scopes: Vec::new(),
+            function: FunctionKind::Script,
+            class: ClassKind::None,
+            locals,
+            errors: Vec::new(),
+        };
+        resolver.statements(statements);
+        if resolver.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(resolver.errors)
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Block { statements } => {
+                self.scopes.push(HashMap::new());
+                self.statements(statements);
+                self.scopes.pop();
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            Statement::Expression { expr } | Statement::Print { expr } => self.expression(expr),
+            Statement::Function(function) => {
+                // functions may refer to themselves, so they are defined right away
+                self.declare(&function.name);
+                self.define(&function.name);
+                self.function(function, FunctionKind::Function);
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Statement::Return { keyword, value } => {
+                if self.function == FunctionKind::Script {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    if self.function == FunctionKind::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.expression(value);
+                }
+            }
+            Statement::Var { name, initializer } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.define(name);
+            }
+            Statement::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+        }
+    }
+
+    // class resolves a class declaration. Methods are resolved in a scope declaring this, inside a
+    // scope declaring super if the class has a superclass.
+    fn class(&mut self, name: &Token, superclass: Option<&Expression>, methods: &[Rc<Function>]) {
+        let enclosing = self.class;
+        self.class = ClassKind::Class;
+        self.declare(name);
+        self.define(name);
+        if let Some(superclass) = superclass {
+            if let Expression::Variable {
+                name: superclass_name,
+                ..
+            } = superclass
+            {
+                if superclass_name.symbol == name.symbol {
+                    self.error(superclass_name, "A class can't inherit from itself.");
+                }
+            }
+            self.class = ClassKind::Subclass;
+            self.expression(superclass);
+            self.scopes.push(HashMap::from([(Symbol::SUPER, true)]));
+        }
+        self.scopes.push(HashMap::from([(Symbol::THIS, true)]));
+        for method in methods {
+            let kind = if method.name.symbol == Symbol::INIT {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+        }
+        self.scopes.pop();
+        if superclass.is_some() {
+            self.scopes.pop();
+        }
+        self.class = enclosing;
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        let enclosing = self.function;
+        self.function = kind;
+        self.scopes.push(HashMap::new());
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.statements(&function.body);
+        self.scopes.pop();
+        self.function = enclosing;
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        // chains are resolved from their innermost operand out, see Expression::left
+        let (innermost, links) = expr.chain();
+        self.operand(innermost);
+        for link in links {
+            match link {
+                Expression::Binary { right, .. } | Expression::Logical { right, .. } => {
+                    self.expression(right)
+                }
+                Expression::Call { arguments, .. } => {
+                    for argument in arguments {
+                        self.expression(argument);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // operand resolves an expression that is not a link of a chain
+    fn operand(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Assign { id, name, expr } => {
+                self.expression(expr);
+                self.local(*id, name);
+            }
+            Expression::Grouping { expr } | Expression::Unary { right: expr, .. } => {
+                self.expression(expr)
+            }
+            Expression::Set { object, value, .. } => {
+                self.expression(value);
+                self.expression(object);
+            }
+            Expression::Literal { .. } => {}
+            Expression::Super { id, keyword, .. } => {
+                match self.class {
+                    ClassKind::None => self.error(keyword, "Can't use 'super' outside of a class."),
+                    ClassKind::Class => {
+                        self.error(keyword, "Can't use 'super' in a class with no superclass.")
+                    }
+                    ClassKind::Subclass => {}
+                }
+                self.local(*id, keyword);
+            }
+            Expression::This { id, keyword } => {
+                if self.class == ClassKind::None {
+                    self.error(keyword, "Can't use 'this' outside of a class.");
+                }
+                self.local(*id, keyword);
+            }
+            Expression::Variable { id, name } => {
+                let scope = self.scopes.last();
+                if scope.and_then(|scope| scope.get(&name.symbol)) == Some(&false) {
+                    self.error(name, "Can't read local variable in its own initializer.");
+                }
+                self.local(*id, name);
+            }
+            Expression::Binary { .. }
+            | Expression::Call { .. }
+            | Expression::Get { .. }
+            | Expression::Logical { .. } => unreachable!("links are resolved by expression"),
+        }
+    }
+
+    // local records how many scopes out the variable name is declared, unless it is a global
+    fn local(&mut self, id: ExprId, name: &Token) {
+        let scopes = self.scopes.iter().rev();
+        if let Some(depth) = scopes
+            .enumerate()
+            .find_map(|(depth, scope)| scope.contains_key(&name.symbol).then_some(depth))
+        {
+            self.locals.insert(id, depth);
+        }
+    }
+
+    fn declare(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if scope.insert(name.symbol, false).is_some() {
+                self.error(name, "Already a variable with this name in this scope.");
+            }
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.symbol, true);
+        }
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.errors.push(CompileError::new(token, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn resolve(source: &str) -> Result<HashMap<ExprId, usize>, Vec<String>> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let statements = Parser::new(scanner.into_parts().0).parse().unwrap();
+        let mut locals = HashMap::new();
+        match Resolver::resolve(&statements, &mut locals) {
+            Ok(()) => Ok(locals),
+            Err(errors) => Err(errors.into_iter().map(|error| error.message).collect()),
+        }
+    }
+
+    #[test]
+    fn resolver_finds_locals() {
+        let locals = resolve("var a; { var b; { a; b; fun f() { b; } } }").unwrap();
+        // a is a global, b is one scope out of the block and two out of the function
+        let mut depths: Vec<usize> = locals.into_values().collect();
+        depths.sort();
+        assert_eq!(depths, [1, 2]);
+    }
+
+    #[test]
+    fn resolver_reports_scoping_errors() {
+        assert_eq!(
+            resolve("{ var a = 1; var a = 2; }\n{ var b = b; }\nreturn 1;").unwrap_err(),
+            [
+                "Already a variable with this name in this scope.",
+                "Can't read local variable in its own initializer.",
+                "Can't return from top-level code.",
+            ]
+        );
+        // globals may be declared again and read in their own initializer
+        assert!(resolve("var a = 1; var a = a;").is_ok());
+
+        assert_eq!(
+            resolve("this;\nsuper.a;\nclass A { a() { super.a; } init() { return 1; } }\nclass B < B {}")
+                .unwrap_err(),
+            [
+                "Can't use 'this' outside of a class.",
+                "Can't use 'super' outside of a class.",
+                "Can't use 'super' in a class with no superclass.",
+                "Can't return a value from an initializer.",
+                "A class can't inherit from itself.",
+            ]
+        );
+    }
+}
@@ -1,30 +1,152 @@
-use crate::token::Token;
-use crate::tokentype::TokenType::{Eof, Identifier};
-use crate::tokentype::{Literal, TokenType};
-use phf;
+use crate::symbol::Interner;
+use crate::token::{Span, Token};
+use crate::tokentype::{Category, Literal, TokenType};
+use std::ops::Range;
+use std::rc::Rc;
+
+// ScanError is a diagnostic for a piece of source that could not be turned into a token. The
+// scanner still emits an Error token at that spot, which the parser skips.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ScanError {
+    pub(crate) message: String,
+    pub(crate) span: Span,
+}
+
+impl ScanError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
 
-pub(crate) struct Scanner<'a> {
+    pub fn column(&self) -> usize {
+        self.span.column
+    }
+}
+
+pub struct Scanner<'a> {
     source: &'a str,
     tokens: Vec<Token>,
+    errors: Vec<ScanError>,
+    // set when the source ends inside a string or block comment
+    unterminated: bool,
     start: usize,
     current: usize,
     line: usize,
+    // an index on the current line and its column, the column of current is counted on from there
+    // so that long lines are not counted over and over
+    column_mark: (usize, usize),
+    // the line and column at which the token that is being scanned starts
+    start_line: usize,
+    start_column: usize,
+    // the symbols of the lexemes scanned so far, so that tokens of the same text share it
+    symbols: Interner,
 }
 
 impl<'a> Scanner<'a> {
-    pub(crate) fn new<'b>(source: &'a str) -> Self {
+    pub fn new(source: &'a str) -> Self {
+        Scanner::with_symbols(source, Interner::default())
+    }
+
+    // with_symbols makes a scanner that gives names the symbols of symbols, like those of the heap
+    // the program is going to run on
+    pub(crate) fn with_symbols(source: &'a str, symbols: Interner) -> Self {
         Scanner {
             source,
             tokens: Vec::new(),
+            errors: Vec::new(),
+            unterminated: false,
             // TODO see if we can get rid of start and current using an iterator over tokens
             start: 0,
             current: 0,
             line: 1,
+            column_mark: (0, 1),
+            start_line: 1,
+            start_column: 1,
+            symbols,
+        }
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    // highlights gives the byte range and category of every token scan_tokens found, in order, for
+    // coloring the source. Comments, and strings that are still open, are not tokens but are
+    // given as well.
+    pub fn highlights(&self) -> Vec<(Range<usize>, Category)> {
+        let mut highlights = Vec::new();
+        let mut end = 0;
+        // the tokens are in order, so their starts are found by walking the source once, keeping
+        // the line and column of the walk
+        let mut chars = self.source.char_indices().peekable();
+        let (mut line, mut column) = (1, 1);
+        for token in &self.tokens {
+            if matches!(token.kind, TokenType::Eof | TokenType::Error) {
+                continue;
+            }
+            while (line, column) < (token.line, token.column) {
+                match chars.next() {
+                    Some((_, '\n')) => (line, column) = (line + 1, 1),
+                    Some(_) => column += 1,
+                    None => break,
+                }
+            }
+            let start = chars.peek().map_or(self.source.len(), |&(index, _)| index);
+            self.highlight_gap(end..start, &mut highlights);
+            end = start + token.lexeme.len();
+            highlights.push((start..end, token.kind.category()));
+        }
+        self.highlight_gap(end..self.source.len(), &mut highlights);
+        highlights
+    }
+
+    // highlight_gap highlights the source between two tokens, which is whitespace, comments or
+    // something that is not a token yet, like a string without its closing quote
+    fn highlight_gap(&self, gap: Range<usize>, highlights: &mut Vec<(Range<usize>, Category)>) {
+        let text = self.source[gap.clone()].trim_end();
+        let Some(offset) = text.find(|c: char| !c.is_whitespace()) else {
+            return;
+        };
+        let rest = &text[offset..];
+        let category = if rest.starts_with("//") || rest.starts_with("/*") {
+            Category::Comment
+        } else if rest.starts_with('"') {
+            Category::Literal
+        } else {
+            return;
+        };
+        highlights.push((gap.start + offset..gap.start + text.len(), category));
+    }
+
+    // into_parts gives the tokens, and the symbols back with the symbols of the tokens added
+    pub(crate) fn into_parts(self) -> (Vec<Token>, Interner) {
+        (self.tokens, self.symbols)
+    }
+
+    pub fn errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+
+    // is_incomplete reports whether the scanned source stops in the middle of something: a string
+    // or block comment that is not closed, or a paren or brace that is not. The REPL keeps reading
+    // lines until the input is complete.
+    pub fn is_incomplete(&self) -> bool {
+        let mut depth = 0;
+        for token in &self.tokens {
+            match token.kind {
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                _ => {}
+            }
         }
+        self.unterminated || depth > 0
     }
 
     // TODO scan tokens can probably be written as a single iterator
-    pub(crate) fn scan_tokens(&mut self) {
+    pub fn scan_tokens(&mut self) {
         // Here we can while loop until self.current <= self.source.len(), since indices range from
         // 0 to len()-1 this looks weird, however at self.current == self.source.len(), the or part
         // of the unwrap_or in advance() is relevant, since we have reached the end of the tokens in the
@@ -49,9 +171,13 @@ impl<'a> Scanner<'a> {
     }
 
     fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        if let Some(error) = self.skip_whitespace() {
+            return error;
+        }
         // set the start to the start of the token
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column();
         let c = self.advance();
         match c {
             '(' => self.new_token(TokenType::LeftParen, None),
@@ -104,136 +230,286 @@ impl<'a> Scanner<'a> {
             '"' => self.string(),
             '\0' => self.new_eof(),
             c => {
-                if c.is_digit(10) {
-                    self.number()
-                } else if c.is_alphabetic() {
+                if c.is_ascii_digit() {
+                    self.number(c)
+                } else if c.is_alphabetic() || c == '_' {
                     self.identifier()
                 } else {
-                    self.error_token(format!("unexpected character '{}' ", c).parse().unwrap())
+                    let span = self.span();
+                    self.error_token(span, format!("unexpected character '{}'", c))
                 }
             }
         }
     }
 
-    fn skip_whitespace(&mut self) {
+    // skip_whitespace skips whitespace and comments. An unterminated block comment is returned
+    // as an error token.
+    fn skip_whitespace(&mut self) -> Option<Token> {
         while !self.is_at_end() {
             match self.peek() {
                 ' ' | '\r' | '\t' => {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
+                }
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '*' => {
+                    if let Some(error) = self.block_comment() {
+                        return Some(error);
                     }
                 }
-                _ => return,
+                _ => return None,
             }
         }
+        None
+    }
+
+    // block_comment skips a `/* ... */` comment. Block comments nest, so `/* a /* b */ c */` is a
+    // single comment. An unterminated comment is reported at the line it was opened on.
+    fn block_comment(&mut self) -> Option<Token> {
+        let opening = Span {
+            line: self.line,
+            column: self.column(),
+            length: 2,
+        };
+        // consume the opening '/*'
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                self.start = self.current;
+                self.unterminated = true;
+                return Some(self.error_token(opening, "unterminated block comment".to_string()));
+            }
+            match (self.peek(), self.peek_next()) {
+                ('/', '*') => {
+                    self.advance();
+                    self.advance();
+                    depth += 1;
+                }
+                ('*', '/') => {
+                    self.advance();
+                    self.advance();
+                    depth -= 1;
+                }
+                ('\n', _) => {
+                    self.advance();
+                    self.new_line();
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+        None
     }
 
     fn identifier(&mut self) -> Token {
         // iterate over the entire keyword, by doing so, we apply maximal munch
-        while self.peek().is_alphabetic() {
-            let c = self.advance();
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
         }
         // check if the word matches any of our keywords
-        let text = self.source[self.start..self.current].to_string();
-        let token_type = KEYWORDS
-            .get(&text)
-            .unwrap_or(&TokenType::Identifier)
-            .clone();
-        if token_type == Identifier {
-            self.new_token(token_type, Some(Literal::Identifier(text)))
-        } else {
-            // known keywords dont need a literal value saved since we know the literal value by the keyword
-            self.new_token(token_type, None)
+        // identifiers need no literal, their name is the lexeme
+        let text = &self.source[self.start..self.current];
+        let token_type = *KEYWORDS.get(text).unwrap_or(&TokenType::Identifier);
+        self.new_token(token_type, None)
+    }
+
+    // number scans a numeric literal. Besides plain decimals like `12` and `1.5` we accept
+    // exponents (`1e-9`), digit separators (`1_000_000`) and the `0x`, `0b` and `0o` radix
+    // prefixes. Malformed literals such as `0x` or `1e` result in an error token.
+    fn number(&mut self, first: char) -> Token {
+        match self.number_literal(first) {
+            Ok(val) => self.new_token(TokenType::Number, Some(Literal::Number(val))),
+            Err(message) => {
+                let span = self.span();
+                self.error_token(span, message)
+            }
         }
     }
 
-    // number parses a number and saves that as a string, we delay the parsing of the number
-    // to a float until later. We could already do it here, but choose not to so we can keep the
-    // type of Lexeme a String instead of making it an enum of String | float64 | none
-    fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
-            self.advance();
+    fn number_literal(&mut self, first: char) -> Result<f64, String> {
+        if first == '0' {
+            let radix = match self.peek() {
+                'x' | 'X' => Some(16),
+                'b' | 'B' => Some(2),
+                'o' | 'O' => Some(8),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                let prefix = self.advance();
+                let digits_start = self.current;
+                if self.digits(radix, 0)? == 0 {
+                    return Err(format!("expected digits after '0{}'", prefix));
+                }
+                // we fold the digits ourselves instead of using from_str_radix, so literals that
+                // do not fit in an u64 lose precision like decimals do instead of failing
+                return Ok(self.source[digits_start..self.current]
+                    .chars()
+                    .filter_map(|c| c.to_digit(radix))
+                    .fold(0.0, |acc, digit| acc * radix as f64 + digit as f64));
+            }
         }
 
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        // the first digit was already consumed by scan_token
+        self.digits(10, 1)?;
+
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             // consume the dot in our number
             self.advance();
+            self.digits(10, 0)?;
         }
 
-        while self.peek().is_digit(10) {
+        if self.peek() == 'e' || self.peek() == 'E' {
             self.advance();
+            if self.peek() == '+' || self.peek() == '-' {
+                self.advance();
+            }
+            if self.digits(10, 0)? == 0 {
+                return Err("expected digits in exponent".to_string());
+            }
         }
 
-        let val = self.source[self.start..self.current]
+        self.source[self.start..self.current]
+            .replace('_', "")
             .parse::<f64>()
-            .unwrap();
-        self.new_token(TokenType::Number, Some(Literal::Number(val)))
+            .map_err(|_| {
+                format!(
+                    "invalid number literal '{}'",
+                    &self.source[self.start..self.current]
+                )
+            })
     }
 
-    fn string(&mut self) -> Token {
-        while self.peek() != '"' || self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+    // digits consumes a run of digits in the given radix and returns how many digits it has seen,
+    // starting from `seen`. Digits may be separated by a single '_', which has to sit between two
+    // digits, so `1__0`, `1_` and `0x_1` are rejected.
+    fn digits(&mut self, radix: u32, mut seen: usize) -> Result<usize, String> {
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                seen += 1;
+            } else if c == '_' {
+                if seen == 0 || !self.peek_next().is_digit(radix) {
+                    return Err("'_' must separate two digits in a number literal".to_string());
+                }
+            } else {
+                return Ok(seen);
             }
             self.advance();
         }
+    }
+
+    fn string(&mut self) -> Token {
+        while self.peek() != '"' && !self.is_at_end() {
+            self.advance();
+            if self.previous() == '\n' {
+                self.new_line();
+            }
+        }
 
         if self.is_at_end() {
-            return Token::new(
-                TokenType::Error,
-                "unterminated string".to_string(),
-                None,
-                self.line,
-            );
+            // point at the opening quote, the rest of the file is the unterminated string
+            let span = Span {
+                line: self.start_line,
+                column: self.start_column,
+                length: 1,
+            };
+            self.unterminated = true;
+            return self.error_token(span, "unterminated string".to_string());
         }
         self.advance();
         // The value of the string with the starting and ending '"' trimmed
-        let val = self.source[self.start + 1..self.current - 1].to_string();
-        Token::new(
-            TokenType::String,
-            self.source[self.start..self.current].to_string(),
-            Some(Literal::String(val)),
-            self.line,
-        )
+        let val = &self.source[self.start + 1..self.current - 1];
+        self.new_token(TokenType::String, Some(Literal::String(val.into())))
     }
 
+    // current is a byte offset into the source, so we step over the full width of multi-byte
+    // characters. At the end of the source we still step over the '\0' returned by peek.
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap_or('\0');
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
         c
     }
 
-    fn new_token(&self, token_type: TokenType, literal: Option<Literal>) -> Token {
-        let text = self.source[self.start..self.current].to_string();
-        Token::new(token_type, text, literal, self.line)
+    fn new_token(&mut self, token_type: TokenType, literal: Option<Literal>) -> Token {
+        let symbol = self.symbols.intern(&self.source[self.start..self.current]);
+        let text = Rc::clone(self.symbols.text(symbol));
+        let mut token = Token::new(
+            token_type,
+            text,
+            literal,
+            self.start_line,
+            self.start_column,
+        );
+        token.symbol = symbol;
+        token
     }
 
     fn new_eof(&self) -> Token {
-        Token::new(TokenType::Eof, "".to_string(), None, self.line)
+        Token::new(TokenType::Eof, "", None, self.start_line, self.start_column)
+    }
+
+    // new_line is called after consuming a '\n', so the columns on the next line start at 1 again
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column_mark = (self.current, 1);
+    }
+
+    fn column(&mut self) -> usize {
+        let (index, column) = self.column_mark;
+        let current = self.current.min(self.source.len());
+        let column = column + self.source[index..current].chars().count();
+        self.column_mark = (current, column);
+        column
+    }
+
+    // span returns the location of the token that is currently being scanned
+    fn span(&self) -> Span {
+        Span {
+            line: self.start_line,
+            column: self.start_column,
+            length: self.source[self.start..self.current.min(self.source.len())]
+                .chars()
+                .count()
+                .max(1),
+        }
     }
 
     fn peek(&self) -> char {
-        // If nth returns a None value, we are at the end of the source
-        self.source.chars().nth(self.current).unwrap_or('\0')
+        // If there is no next character, we are at the end of the source
+        self.remaining().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        self.source.chars().nth(self.current + 1).unwrap_or('\0')
+        self.remaining().nth(1).unwrap_or('\0')
+    }
+
+    fn previous(&self) -> char {
+        self.source
+            .get(..self.current)
+            .and_then(|consumed| consumed.chars().next_back())
+            .unwrap_or('\0')
     }
 
-    fn error_token(&self, message: String) -> Token {
-        Token::new(TokenType::Error, message, None, self.line)
+    fn remaining(&self) -> std::str::Chars<'a> {
+        self.source.get(self.current..).unwrap_or_default().chars()
+    }
+
+    // error_token records a diagnostic for the given span and returns an Error token covering the
+    // offending source, so the parser knows something was skipped there.
+    fn error_token(&mut self, span: Span, message: String) -> Token {
+        self.errors.push(ScanError { message, span });
+        let text = &self.source[self.start..self.current.min(self.source.len())];
+        Token::new(TokenType::Error, text, None, span.line, span.column)
     }
 }
 
@@ -256,6 +532,11 @@ static KEYWORDS: phf::Map<&'static str, TokenType> = phf::phf_map! {
     "while" => TokenType::While,
 };
 
+// keywords gives the reserved words of Lox, which the REPL completes
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    KEYWORDS.keys().copied()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -300,10 +581,10 @@ mod tests {
                     .clone()
                     .literal
                     .unwrap()
-                    .eq(&Literal::String("a string is here".to_string())));
+                    .eq(&Literal::String("a string is here".into())));
             }
             if token.kind == TokenType::Number {
-                assert_eq!(token.clone().literal.unwrap(), Literal::Number(123 as f64))
+                assert_eq!(token.clone().literal.unwrap(), Literal::Number(123.0))
             }
             assert_eq!(
                 token.clone().kind,
@@ -353,7 +634,7 @@ mod tests {
                     .clone()
                     .literal
                     .unwrap()
-                    .eq(&Literal::String("a string is here".to_string())));
+                    .eq(&Literal::String("a string is here".into())));
             }
             assert_eq!(
                 token.clone().kind,
@@ -395,18 +676,40 @@ mod tests {
         for (i, token) in scanner.tokens.iter().enumerate() {
             assert_eq!(token.kind, expected[i]);
             if token.kind == TokenType::Identifier {
-                assert_eq!(token.lexeme, "randomidentifier".to_string())
+                assert_eq!(token.lexeme.as_ref(), "randomidentifier")
             }
         }
     }
 
+    #[test]
+    fn scanner_scans_identifiers_with_digits_and_underscores() {
+        let mut scanner = Scanner::new("_a1 b_2c fun1 1a");
+        scanner.scan_tokens();
+        let tokens: Vec<(TokenType, &str)> = scanner
+            .tokens
+            .iter()
+            .map(|token| (token.kind, token.lexeme.as_ref()))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Identifier, "_a1"),
+                (TokenType::Identifier, "b_2c"),
+                (TokenType::Identifier, "fun1"),
+                (TokenType::Number, "1"),
+                (TokenType::Identifier, "a"),
+                (TokenType::Eof, ""),
+            ]
+        );
+    }
+
     #[test]
     fn scanner_scans_strings() {
         let source = "\"blablathisisastring\"";
         let mut scanner = Scanner::new(source);
         scanner.scan_tokens();
 
-        let expected = vec![TokenType::String, TokenType::Eof];
+        let expected = [TokenType::String, TokenType::Eof];
 
         for (i, token) in scanner.tokens.iter().enumerate() {
             if token.kind == TokenType::String {
@@ -414,7 +717,7 @@ mod tests {
                     .clone()
                     .literal
                     .unwrap()
-                    .eq(&Literal::String("blablathisisastring".to_string())));
+                    .eq(&Literal::String("blablathisisastring".into())));
             }
             assert_eq!(
                 token.clone().kind,
@@ -431,8 +734,10 @@ mod tests {
     fn scanner_scans_numbers() {
         let source = "123.123";
         let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
 
-        let expected = vec![TokenType::Number, TokenType::Eof];
+        let expected = [TokenType::Number, TokenType::Eof];
+        assert_eq!(scanner.tokens.len(), expected.len());
         for (i, token) in scanner.tokens.iter().enumerate() {
             if token.kind == TokenType::Number {
                 assert!(token.clone().literal.unwrap().eq(&Literal::Number(123.123)))
@@ -448,6 +753,221 @@ mod tests {
         }
     }
 
+    #[test]
+    fn scanner_scans_extended_numbers() {
+        let cases = [
+            ("0xFF", 255.0),
+            ("0Xff", 255.0),
+            ("0b1010", 10.0),
+            ("0o17", 15.0),
+            ("1e-9", 1e-9),
+            ("2.5E+3", 2500.0),
+            ("1_000_000", 1_000_000.0),
+            ("0xFF_FF", 65535.0),
+            ("0", 0.0),
+        ];
+        for (source, expected) in cases {
+            let mut scanner = Scanner::new(source);
+            scanner.scan_tokens();
+            assert_eq!(
+                scanner.tokens.len(),
+                2,
+                "{} should scan to a single number",
+                source
+            );
+            assert_eq!(scanner.tokens[0].kind, TokenType::Number, "{}", source);
+            assert_eq!(scanner.tokens[0].lexeme.as_ref(), source);
+            assert_eq!(
+                scanner.tokens[0].literal,
+                Some(Literal::Number(expected)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn scanner_reports_malformed_numbers() {
+        for source in ["0x", "0b2", "0o", "1e", "1e+", "1_", "1__0", "0x_1", "1.5_"] {
+            let mut scanner = Scanner::new(source);
+            scanner.scan_tokens();
+            assert_eq!(
+                scanner.tokens[0].kind,
+                TokenType::Error,
+                "{} should not scan as a number",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn scanner_skips_nested_block_comments() {
+        let source = "/* outer /* inner\n */ still\n comment */(\n/**/)";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+
+        let expected = [
+            (TokenType::LeftParen, 3),
+            (TokenType::RightParen, 4),
+            (TokenType::Eof, 4),
+        ];
+        assert_eq!(scanner.tokens.len(), expected.len());
+        for (token, (kind, line)) in scanner.tokens.iter().zip(expected) {
+            assert_eq!(token.kind, kind);
+            assert_eq!(token.line, line);
+        }
+    }
+
+    #[test]
+    fn scanner_reports_unterminated_block_comment() {
+        let source = "(\n/* open /* nested */\n\n";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+
+        assert_eq!(scanner.tokens.len(), 3);
+        assert_eq!(scanner.tokens[1].kind, TokenType::Error);
+        assert_eq!(scanner.tokens[1].line, 2);
+        assert_eq!(scanner.tokens[2].kind, TokenType::Eof);
+        assert_eq!(scanner.tokens[2].line, 4);
+    }
+
+    #[test]
+    fn highlights_cover_tokens_and_comments() {
+        let source = "var é = \"ü\" + 1; // done\nprint \"open";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let highlights: Vec<_> = scanner
+            .highlights()
+            .into_iter()
+            .map(|(range, category)| (&source[range], category))
+            .collect();
+        assert_eq!(
+            highlights,
+            [
+                ("var", Category::Keyword),
+                ("é", Category::Identifier),
+                ("=", Category::Operator),
+                ("\"ü\"", Category::Literal),
+                ("+", Category::Operator),
+                ("1", Category::Literal),
+                (";", Category::Punctuation),
+                ("// done", Category::Comment),
+                ("print", Category::Keyword),
+                ("\"open", Category::Literal),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_keep_their_columns() {
+        let source = format!("{}\"ü\nü\" + x;", "ü + ".repeat(10_000));
+        let mut scanner = Scanner::new(&source);
+        scanner.scan_tokens();
+        let tokens = scanner.tokens();
+        let x = &tokens[tokens.len() - 3];
+        assert_eq!((x.lexeme(), x.line, x.column), ("x", 2, 6));
+        let highlights = scanner.highlights();
+        assert_eq!(highlights.len(), 20_004);
+        assert_eq!(&source[highlights[20_002].0.clone()], "x");
+    }
+
+    #[test]
+    fn scanner_tells_incomplete_input() {
+        let incomplete = |source| {
+            let mut scanner = Scanner::new(source);
+            scanner.scan_tokens();
+            scanner.is_incomplete()
+        };
+        for source in [
+            "fun f() {",
+            "print (1 +",
+            "{ if (a) {\n}",
+            "\"open",
+            "/* open",
+        ] {
+            assert!(incomplete(source), "{}", source);
+        }
+        for source in [
+            "fun f() {}",
+            "print (1);",
+            "}",
+            "\"a\" // (",
+            "/* { */",
+            "print #;",
+        ] {
+            assert!(!incomplete(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn scanner_records_error_spans() {
+        let source = "var a = 1;\nvar b = #;\n\"open";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+
+        assert_eq!(
+            scanner.errors(),
+            &[
+                ScanError {
+                    message: "unexpected character '#'".to_string(),
+                    span: Span {
+                        line: 2,
+                        column: 9,
+                        length: 1
+                    },
+                },
+                ScanError {
+                    message: "unterminated string".to_string(),
+                    span: Span {
+                        line: 3,
+                        column: 1,
+                        length: 1
+                    },
+                },
+            ]
+        );
+        let error_tokens: Vec<&Token> = scanner
+            .tokens()
+            .iter()
+            .filter(|token| token.kind == TokenType::Error)
+            .collect();
+        assert_eq!(error_tokens.len(), 2);
+        assert_eq!(error_tokens[0].lexeme.as_ref(), "#");
+        assert_eq!(scanner.tokens().last().unwrap().kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scanner_handles_non_ascii_source() {
+        let source = "\"héllo wörld\" ünïcode € 1";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+
+        let kinds: Vec<TokenType> = scanner.tokens().iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenType::String,
+                TokenType::Identifier,
+                TokenType::Error,
+                TokenType::Number,
+                TokenType::Eof
+            ]
+        );
+        assert_eq!(
+            scanner.tokens()[0].literal,
+            Some(Literal::String("héllo wörld".into()))
+        );
+        assert_eq!(scanner.tokens()[1].lexeme.as_ref(), "ünïcode");
+        assert_eq!(
+            scanner.errors()[0].span,
+            Span {
+                line: 1,
+                column: 23,
+                length: 1
+            }
+        );
+    }
+
     #[test]
     fn peek_works() {
         let source = "/a|bcvd";
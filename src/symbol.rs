@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Symbol stands for a name in source code, like a variable, a property or a method. It is an id
+// handed out by an Interner, so symbols are copied, compared and hashed as the number they are, and
+// the maps of variables and properties keyed by them never look at the text. Only symbols from the
+// same interner can be compared, every heap has one for the programs that run on it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct Symbol(u32);
+
+impl Symbol {
+    // every interner starts with the names the runtimes declare themselves
+    pub(crate) const THIS: Symbol = Symbol(0);
+    pub(crate) const SUPER: Symbol = Symbol(1);
+    pub(crate) const INIT: Symbol = Symbol(2);
+    // the symbol of tokens that are not names, which nothing looks up
+    pub(crate) const NONE: Symbol = Symbol(u32::MAX);
+}
+
+// Interner gives the same text the same symbol for as long as it lives, and keeps the text so that
+// tokens of the same text share it. The text of a symbol is only needed to show it.
+pub(crate) struct Interner {
+    symbols: HashMap<Rc<str>, Symbol>,
+    texts: Vec<Rc<str>>,
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        let mut interner = Interner {
+            symbols: HashMap::new(),
+            texts: Vec::new(),
+        };
+        for name in ["this", "super", "init"] {
+            interner.intern(name);
+        }
+        interner
+    }
+}
+
+impl Interner {
+    pub(crate) fn intern(&mut self, text: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(text) {
+            return *symbol;
+        }
+        let symbol = Symbol(self.texts.len() as u32);
+        let text: Rc<str> = text.into();
+        self.texts.push(Rc::clone(&text));
+        self.symbols.insert(text, symbol);
+        symbol
+    }
+
+    // get gives the symbol of text if it was interned, without interning it
+    pub(crate) fn get(&self, text: &str) -> Option<Symbol> {
+        self.symbols.get(text).copied()
+    }
+
+    pub(crate) fn text(&self, symbol: Symbol) -> &Rc<str> {
+        &self.texts[symbol.0 as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_compare_by_id() {
+        let mut interner = Interner::default();
+        let a = interner.intern("apple");
+        assert_eq!(interner.intern(&String::from("apple")), a);
+        assert_ne!(interner.intern("pear"), a);
+        assert_eq!(interner.get("apple"), Some(a));
+        assert_eq!(interner.get("plum"), None);
+        assert_eq!(&**interner.text(a), "apple");
+
+        let map = HashMap::from([(a, 1)]);
+        assert_eq!(map.get(&interner.intern("apple")), Some(&1));
+    }
+
+    #[test]
+    fn interning_shares_the_text() {
+        let mut interner = Interner::default();
+        let a = interner.intern("apple");
+        let text = Rc::clone(interner.text(a));
+        interner.intern("apple");
+        assert!(Rc::ptr_eq(interner.text(a), &text));
+        assert_eq!(interner.intern("this"), Symbol::THIS);
+        assert_eq!(interner.intern("super"), Symbol::SUPER);
+        assert_eq!(interner.intern("init"), Symbol::INIT);
+    }
+}
@@ -1,27 +1,87 @@
+use crate::symbol::Symbol;
 use crate::tokentype::{Literal, TokenType};
 use std::fmt::{Display, Formatter};
+use std::rc::Rc;
 
-#[derive(Clone, Debug)]
-pub(crate) struct Token {
+#[derive(Clone, PartialEq, Debug)]
+pub struct Token {
     pub(crate) kind: TokenType,
-    pub(crate) lexeme: String,
+    pub(crate) lexeme: Rc<str>,
+    // the symbol of the lexeme, which names and keys variables and properties
+    pub(crate) symbol: Symbol,
     pub(crate) literal: Option<Literal>,
     pub(crate) line: usize,
-    // TODO add column and length of token for better error handling
+    pub(crate) column: usize,
+}
+
+// Span points at a range of characters on a single line of the source, so errors can show the
+// offending code.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Span {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) length: usize,
 }
 
 impl Token {
+    // new makes a token that is not a name, the scanner gives names their symbols. this and super
+    // are names of their own.
     pub(crate) fn new(
         kind: TokenType,
-        lexeme: String,
+        lexeme: impl Into<Rc<str>>,
         literal: Option<Literal>,
         line: usize,
+        column: usize,
     ) -> Token {
+        let symbol = match kind {
+            TokenType::This => Symbol::THIS,
+            TokenType::Super => Symbol::SUPER,
+            _ => Symbol::NONE,
+        };
         Token {
             kind,
-            lexeme,
+            lexeme: lexeme.into(),
+            symbol,
             literal,
             line,
+            column,
+        }
+    }
+
+    pub fn kind(&self) -> TokenType {
+        self.kind
+    }
+
+    // lexeme is the source text of the token
+    pub fn lexeme(&self) -> &str {
+        &self.lexeme
+    }
+
+    // literal is the value of a string or number token
+    pub fn literal(&self) -> Option<&Literal> {
+        self.literal.as_ref()
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    // column is the column of the first character of the token, counting from 1
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub(crate) fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            // tokens spanning multiple lines, like strings, are only pointed at on their first line
+            length: self
+                .lexeme
+                .lines()
+                .next()
+                .map_or(0, |line| line.chars().count())
+                .max(1),
         }
     }
 }
@@ -1,5 +1,6 @@
 use std::fmt;
-use std::fmt::{write, Formatter};
+use std::fmt::Formatter;
+use std::rc::Rc;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum TokenType {
@@ -53,11 +54,38 @@ pub enum TokenType {
     Error,
 }
 
+// Category is what kind of token a token type is, for coloring source. true, false and nil are
+// keywords, but they read as literals.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Category {
+    Punctuation,
+    Operator,
+    Identifier,
+    Literal,
+    Keyword,
+    Comment,
+}
+
+impl TokenType {
+    pub fn category(self) -> Category {
+        use TokenType::*;
+        match self {
+            LeftParen | RightParen | LeftBrace | RightBrace | Comma | Dot | Semicolon | Eof
+            | Error => Category::Punctuation,
+            Minus | Plus | Slash | Star | Bang | BangEqual | Equal | EqualEqual | Greater
+            | GreaterEqual | Less | LessEqual => Category::Operator,
+            Identifier => Category::Identifier,
+            String | Number | False | Nil | True => Category::Literal,
+            And | Class | Else | Fun | For | If | Or | Print | Return | Super | This | Var
+            | While => Category::Keyword,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Literal {
-    String(String),
+    String(Rc<str>),
     Number(f64),
-    Identifier(String),
 }
 
 impl fmt::Display for Literal {
@@ -65,7 +93,6 @@ impl fmt::Display for Literal {
         match self {
             Literal::String(val) => write!(f, "{}", val),
             Literal::Number(val) => write!(f, "{}", val),
-            Literal::Identifier(val) => write!(f, "{}", val),
         }
     }
 }
@@ -0,0 +1,100 @@
+use std::fmt::{Debug, Formatter};
+
+// Value is the result of evaluating an expression: nil, a boolean, a number or a handle to an
+// object, like a string, on the heap of the backend that created it. Strings are interned there,
+// so comparing two values compares strings too.
+//
+// There are two representations with the same interface. By default a value is a tagged enum;
+// with the nan-boxing feature it is packed into the 64 bits of a double, where anything that is
+// not a number hides in the payload of a quiet NaN.
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+#[cfg(not(feature = "nan-boxing"))]
+pub use tagged::Value;
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed;
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxed::Value;
+
+impl Value {
+    // like in Ruby, only nil and false are falsey
+    pub fn is_truthy(self) -> bool {
+        !self.is_nil() && self.as_bool() != Some(false)
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::NIL
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(val) = self.as_bool() {
+            write!(f, "Bool({})", val)
+        } else if let Some(val) = self.as_number() {
+            write!(f, "Number({:?})", val)
+        } else if let Some(obj) = self.as_object() {
+            write!(f, "Object({:?})", obj)
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    #[test]
+    fn values_round_trip() {
+        assert!(Value::NIL.is_nil());
+        assert_eq!(Value::NIL.as_bool(), None);
+        assert_eq!(Value::from(true).as_bool(), Some(true));
+        assert_eq!(Value::from(false).as_number(), None);
+        for val in [0.0, -0.0, 1.5, -1e300, f64::INFINITY, f64::MIN_POSITIVE] {
+            let value = Value::from(val);
+            assert_eq!(value.as_number().map(f64::to_bits), Some(val.to_bits()));
+            assert!(!value.is_nil());
+            assert_eq!(value.as_object(), None);
+        }
+        assert!(Value::from(f64::NAN).as_number().unwrap().is_nan());
+
+        let mut heap = Heap::new();
+        let value = heap.alloc_string("a");
+        assert!(value.as_object().is_some());
+        assert_eq!(value.as_number(), None);
+        assert_eq!(heap.as_str(value), Some("a"));
+    }
+
+    #[test]
+    fn equality_follows_lox() {
+        assert_eq!(Value::from(0.0), Value::from(-0.0));
+        assert_ne!(Value::from(f64::NAN), Value::from(f64::NAN));
+        assert_ne!(Value::from(0.0), Value::from(false));
+        assert_ne!(Value::NIL, Value::from(false));
+        assert_eq!(Value::from(true), Value::from(true));
+
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("a");
+        assert_eq!(heap.alloc_string("a"), a);
+        assert_ne!(heap.alloc_string("b"), a);
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nan_boxed_values_take_eight_bytes() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsey() {
+        assert!(!Value::NIL.is_truthy());
+        assert!(!Value::from(false).is_truthy());
+        assert!(Value::from(0.0).is_truthy());
+        assert!(Value::from(true).is_truthy());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::heap::{ObjRef, GENERATION_BITS};
+
+// Value packed into 64 bits. Numbers are stored as themselves. Everything else is a quiet NaN
+// with all of QNAN set, which arithmetic never produces: nil, false and true are told apart by
+// the low bits, and objects additionally have the sign bit set, the index of their handle in the
+// low 32 bits and its generation in the GENERATION_BITS above.
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const FALSE: Value = Value(QNAN | TAG_FALSE);
+const TRUE: Value = Value(QNAN | TAG_TRUE);
+
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+    pub(crate) fn object(obj: ObjRef) -> Value {
+        Value(SIGN_BIT | QNAN | (obj.generation as u64) << 32 | obj.index as u64)
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == Value::NIL.0
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self.0 {
+            bits if bits == TRUE.0 => Some(true),
+            bits if bits == FALSE.0 => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        if self.0 & QNAN != QNAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn as_object(self) -> Option<ObjRef> {
+        if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            Some(ObjRef {
+                index: self.0 as u32,
+                generation: (self.0 >> 32) as u32 & ((1 << GENERATION_BITS) - 1),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl PartialEq for Value {
+    // numbers compare as doubles, so NaN is not equal to itself and 0 equals -0. Everything else
+    // is equal when its bits are.
+    fn eq(&self, other: &Self) -> bool {
+        if self.0 == other.0 {
+            // only a NaN that is a number differs from itself
+            self.0 & QNAN == QNAN || !f64::from_bits(self.0).is_nan()
+        } else {
+            // 0 and -0 only differ in the sign bit
+            (self.0 | other.0) & !SIGN_BIT == 0
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        if val {
+            TRUE
+        } else {
+            FALSE
+        }
+    }
+}
+
+impl From<f64> for Value {
+    // a NaN could carry any payload, including one that looks like another kind of value, so all
+    // NaNs are stored as the same plain one
+    fn from(val: f64) -> Self {
+        if val.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(val.to_bits())
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::heap::ObjRef;
+
+// Value as an enum, which takes 16 bytes
+#[derive(Clone, Copy, PartialEq)]
+pub struct Value(Repr);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Repr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Object(ObjRef),
+}
+
+impl Value {
+    pub const NIL: Value = Value(Repr::Nil);
+
+    pub(crate) fn object(obj: ObjRef) -> Value {
+        Value(Repr::Object(obj))
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == Repr::Nil
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self.0 {
+            Repr::Bool(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self.0 {
+            Repr::Number(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_object(self) -> Option<ObjRef> {
+        match self.0 {
+            Repr::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        Value(Repr::Bool(val))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value(Repr::Number(val))
+    }
+}
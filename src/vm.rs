@@ -0,0 +1,1265 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::debug;
+use crate::heap::{GcStats, Heap, ObjRef};
+use crate::interpreter::{self, Limit, Output, RuntimeError, StackFrame, MAX_CALL_DEPTH};
+use crate::native::{self, Args};
+use crate::object::{
+    BoundMethod, CacheEntry, Class, Closure, Constructor, InlineCache, Instance, Native, Object,
+    Shape, Upvalue, Userdata,
+};
+use crate::value::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// CallFrame is a call of a closure that has not returned yet
+#[derive(Clone)]
+struct CallFrame {
+    closure: ObjRef,
+    // the code of the closure and the variables it captured, shared with it
+    chunk: Rc<Chunk>,
+    upvalues: Rc<[ObjRef]>,
+    caches: Rc<[Cell<Option<InlineCache>>]>,
+    // the next instruction to run
+    ip: usize,
+    // the stack slot of the closure, which is followed by its arguments and other locals
+    base: usize,
+}
+
+impl CallFrame {
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.chunk.code[self.ip];
+        self.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let val = u16::from_le_bytes([self.chunk.code[self.ip], self.chunk.code[self.ip + 1]]);
+        self.ip += 2;
+        val
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_u16();
+        self.chunk.constants[index as usize]
+    }
+}
+
+// Vm runs the bytecode produced by the compiler on a stack of values
+pub(crate) struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    // the globals by the string object holding their name
+    globals: HashMap<ObjRef, Value>,
+    // the upvalues still pointing into the stack, ordered by their slot, so closures declared in
+    // the same scope share them and they are closed when their variable goes out of scope
+    open_upvalues: Vec<ObjRef>,
+    // the string "init", which names initializers
+    init_string: ObjRef,
+    // when set, property accesses and method calls remember what they found in inline caches
+    inline_caching: bool,
+    // when set, the stack and the next instruction are printed before every instruction
+    trace: bool,
+    // the number of instructions run by the current call to interpret, and how many it may run
+    steps: usize,
+    step_limit: Option<usize>,
+    max_call_depth: usize,
+    pub(crate) heap: Heap,
+    output: Output,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub(crate) fn new() -> Vm {
+        let mut heap = Heap::new();
+        let init_string = heap.alloc_string("init").as_object().unwrap();
+        let mut vm = Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            inline_caching: true,
+            trace: false,
+            steps: 0,
+            step_limit: None,
+            max_call_depth: MAX_CALL_DEPTH,
+            heap,
+            output: interpreter::stdout(),
+        };
+        for native in interpreter::natives() {
+            vm.define_native(native);
+        }
+        vm
+    }
+
+    // define_class makes a class whose instances constructor makes a global
+    pub(crate) fn define_class(&mut self, name: Rc<str>, constructor: Constructor) {
+        let name = self.heap.alloc_string(&name).as_object().unwrap();
+        let shape = self.heap.alloc(Object::Shape(Shape::default()));
+        let class = self.heap.alloc(Object::Class(Class {
+            name,
+            methods: HashMap::new(),
+            shape,
+            constructor: Some(constructor),
+        }));
+        self.globals.insert(name, Value::object(class));
+    }
+
+    // define_native makes native a global
+    pub(crate) fn define_native(&mut self, native: Native) {
+        let name = self.heap.alloc_string(&native.name);
+        let native = self.heap.alloc(Object::Native(native));
+        self.globals
+            .insert(name.as_object().unwrap(), Value::object(native));
+    }
+
+    // reset forgets every global and everything programs allocated, but keeps the settings
+    pub(crate) fn reset(&mut self) {
+        let mut fresh = Vm::new();
+        fresh.trace = self.trace;
+        fresh.inline_caching = self.inline_caching;
+        fresh.step_limit = self.step_limit;
+        fresh.max_call_depth = self.max_call_depth;
+        fresh.heap.set_stress(self.heap.is_stressed());
+        fresh.heap.set_limit(self.heap.limit());
+        fresh.output = Rc::clone(&self.output);
+        *self = fresh;
+    }
+
+    // set_output makes print, and the trace, write to output
+    pub(crate) fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    pub(crate) fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub(crate) fn set_inline_caching(&mut self, inline_caching: bool) {
+        self.inline_caching = inline_caching;
+    }
+
+    // set_step_limit limits how many instructions a single call to interpret may run, None means
+    // there is no limit
+    pub(crate) fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    pub(crate) fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    // set_heap_limit limits how many bytes the objects programs keep alive may take, None means
+    // there is no limit
+    pub(crate) fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap.set_limit(limit);
+    }
+
+    pub(crate) fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // globals gives the names and values of the global variables
+    pub(crate) fn globals(&self) -> Vec<(String, Value)> {
+        let globals = self.globals.iter();
+        globals
+            .map(|(name, value)| {
+                let name = self.heap.as_str(Value::object(*name)).unwrap_or_default();
+                (name.to_string(), *value)
+            })
+            .collect()
+    }
+
+    // set_gc_stress makes every allocation collect garbage, for testing the collector
+    pub(crate) fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    // interpret runs the script function produced by the compiler, and gives the value it returns
+    pub(crate) fn interpret(&mut self, script: ObjRef) -> Result<Value, RuntimeError> {
+        self.steps = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        // the script is on the stack while its closure is allocated, so it can not be collected
+        self.stack.push(Value::object(script));
+        let closure = self.alloc(Object::Closure(Closure {
+            function: script,
+            upvalues: Rc::new([]),
+        }));
+        self.stack[0] = Value::object(closure);
+        self.call(closure, 0)
+            .expect("the script takes no arguments and is the first call");
+        let result = self.run();
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result.map(|()| self.pop())
+    }
+
+    // call_function calls a function or class from Rust with arguments, and gives what it returns.
+    // The stack trace of an error leaves out the caller.
+    pub(crate) fn call_function(
+        &mut self,
+        callee: Value,
+        arguments: &[Value],
+    ) -> Result<Value, RuntimeError> {
+        self.steps = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        // the callee and the arguments are rooted on the stack, where a call expects them
+        self.stack.push(callee);
+        self.stack.extend_from_slice(arguments);
+        let result = match self.call_value(callee, arguments.len()) {
+            Err(fault) => Err(fault.at_line(0)),
+            // natives, and classes without an initializer, are done at once
+            Ok(()) if self.frames.is_empty() => Ok(()),
+            Ok(()) => self.run(),
+        };
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result.map(|()| self.pop())
+    }
+
+    // global_named gives the value of the global variable called name
+    pub(crate) fn global_named(&self, name: &str) -> Option<Value> {
+        let name = self.heap.interned(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        // the frame of the running function is kept in a local rather than looked up for every
+        // instruction
+        let mut frame = self.frame();
+        loop {
+            if self.trace {
+                // tracing is for debugging, so an output that fails does not stop the program
+                let mut output = self.output.borrow_mut();
+                let _ = writeln!(output, "{}", debug::stack(&self.stack, &self.heap));
+                let instruction =
+                    debug::disassemble_instruction(&frame.chunk, &self.heap, frame.ip);
+                let _ = writeln!(output, "{}", instruction.0);
+            }
+            let start = frame.ip;
+            if let Some(limit) = self.exceeded() {
+                let mut error = RuntimeError::exceeded(frame.chunk.line(start), limit);
+                error.trace = self.stack_trace(error.line);
+                return Err(error);
+            }
+            match self.step(&mut frame) {
+                Ok(false) => {}
+                Ok(true) => return Ok(()),
+                Err(fault) => {
+                    let mut error = fault.at_line(frame.chunk.line(start));
+                    error.trace = self.stack_trace(error.line);
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    // exceeded counts an instruction, and tells which limit the program went over if it did
+    fn exceeded(&mut self) -> Option<Limit> {
+        self.steps += 1;
+        if self.step_limit.is_some_and(|limit| self.steps > limit) {
+            return Some(Limit::Steps);
+        }
+        if self.heap.take_exhausted() {
+            return Some(Limit::Memory);
+        }
+        None
+    }
+
+    // stack_trace describes the calls that are running, the innermost first, which is at line
+    fn stack_trace(&self, line: usize) -> Vec<StackFrame> {
+        let mut trace = Vec::new();
+        for (index, frame) in self.frames.iter().rev().enumerate() {
+            let function = match self.heap.get(frame.closure) {
+                Object::Closure(closure) => match self.heap.get(closure.function) {
+                    Object::Function(function) => function.name.as_deref().map(str::to_string),
+                    object => panic!("{:?} is not a function", object),
+                },
+                object => panic!("{:?} is not a closure", object),
+            };
+            // the frames around the innermost one are at the call they made
+            let line = match index {
+                0 => line,
+                _ => frame.chunk.line(frame.ip - 1),
+            };
+            trace.push(StackFrame { function, line });
+        }
+        trace
+    }
+
+    // step runs the next instruction of frame, and returns whether the script has returned
+    fn step(&mut self, frame: &mut CallFrame) -> Result<bool, Fault> {
+        let byte = frame.read_byte();
+        let op = OpCode::from_byte(byte).ok_or_else(|| format!("Unknown opcode {}.", byte))?;
+        match op {
+            OpCode::Constant => {
+                let index = frame.read_byte() as usize;
+                self.stack.push(frame.chunk.constants[index]);
+            }
+            OpCode::ConstantLong => {
+                let low = frame.read_u16() as usize;
+                let index = low | (frame.read_byte() as usize) << 16;
+                self.stack.push(frame.chunk.constants[index]);
+            }
+            OpCode::Nil => self.stack.push(Value::NIL),
+            OpCode::True => self.stack.push(Value::from(true)),
+            OpCode::False => self.stack.push(Value::from(false)),
+            OpCode::Pop => {
+                self.pop();
+            }
+            OpCode::GetLocal => {
+                let slot = frame.read_byte() as usize;
+                self.stack.push(self.stack[frame.base + slot]);
+            }
+            OpCode::SetLocal => {
+                let slot = frame.read_byte() as usize;
+                self.stack[frame.base + slot] = self.peek(0);
+            }
+            OpCode::GetGlobal => {
+                let name = frame.read_constant();
+                match self.global(name) {
+                    Some(value) => self.stack.push(value),
+                    None => return Err(self.undefined(name).into()),
+                }
+            }
+            OpCode::DefineGlobal => {
+                let name = frame.read_constant();
+                if let Some(name) = name.as_object() {
+                    self.globals.insert(name, self.peek(0));
+                }
+                self.pop();
+            }
+            OpCode::SetGlobal => {
+                let name = frame.read_constant();
+                let value = self.peek(0);
+                match name
+                    .as_object()
+                    .and_then(|name| self.globals.get_mut(&name))
+                {
+                    Some(global) => *global = value,
+                    None => return Err(self.undefined(name).into()),
+                }
+            }
+            OpCode::GetUpvalue => {
+                let index = frame.read_byte() as usize;
+                let value = match self.upvalue(frame.upvalues[index]) {
+                    Upvalue::Open(slot) => self.stack[*slot],
+                    Upvalue::Closed(value) => *value,
+                };
+                self.stack.push(value);
+            }
+            OpCode::SetUpvalue => {
+                let index = frame.read_byte() as usize;
+                let value = self.peek(0);
+                match self.heap.get_mut(frame.upvalues[index]) {
+                    Object::Upvalue(Upvalue::Open(slot)) => {
+                        let slot = *slot;
+                        self.stack[slot] = value;
+                    }
+                    Object::Upvalue(Upvalue::Closed(closed)) => *closed = value,
+                    object => panic!("{:?} is not an upvalue", object),
+                }
+            }
+            OpCode::GetProperty => {
+                let name = frame.read_constant();
+                let cache = frame.read_u16() as usize;
+                let cache = &frame.caches[cache];
+                let value = self.get_property(self.peek(0), name, cache)?;
+                let top = self.stack.len() - 1;
+                self.stack[top] = value;
+            }
+            OpCode::GetLocalProperty => {
+                let slot = frame.read_byte() as usize;
+                let name = frame.read_constant();
+                let cache = frame.read_u16() as usize;
+                let cache = &frame.caches[cache];
+                let value = self.get_property(self.stack[frame.base + slot], name, cache)?;
+                self.stack.push(value);
+            }
+            OpCode::SetProperty => {
+                let name = frame.read_constant();
+                let cache = frame.read_u16() as usize;
+                let cache = &frame.caches[cache];
+                let value = self.peek(0);
+                self.set_property(self.peek(1), name, value, cache)?;
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(value);
+            }
+            OpCode::GetSuper => {
+                let name = frame.read_constant();
+                // this and the superclass stay on the stack while the method is bound
+                let method = self.super_method(self.peek(0), name)?;
+                let bound = self.bind(self.peek(1), method);
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(bound);
+            }
+            OpCode::Equal => {
+                let right = self.pop();
+                let left = self.pop();
+                self.stack.push(Value::from(left == right));
+            }
+            OpCode::Greater => self.comparison(|left, right| left > right)?,
+            OpCode::GreaterEqual => self.comparison(|left, right| left >= right)?,
+            OpCode::Less => self.comparison(|left, right| left < right)?,
+            OpCode::LessEqual => self.comparison(|left, right| left <= right)?,
+            OpCode::Add => {
+                // the operands stay on the stack while the result is allocated, so a
+                // collection can not free them
+                let result = self.add(self.peek(1), self.peek(0))?;
+                self.stack.truncate(self.stack.len().saturating_sub(2));
+                self.stack.push(result);
+            }
+            OpCode::AddLocal => {
+                let slot = frame.read_byte() as usize;
+                let result = self.add(self.peek(0), self.stack[frame.base + slot])?;
+                let top = self.stack.len() - 1;
+                self.stack[top] = result;
+            }
+            OpCode::Subtract => self.arithmetic(|left, right| left - right)?,
+            OpCode::Multiply => self.arithmetic(|left, right| left * right)?,
+            OpCode::Divide => self.arithmetic(|left, right| left / right)?,
+            OpCode::Not => {
+                let value = self.pop();
+                self.stack.push(Value::from(!value.is_truthy()));
+            }
+            OpCode::Negate => match self.pop().as_number() {
+                Some(val) => self.stack.push(Value::from(-val)),
+                None => return Err("Operand must be a number.".into()),
+            },
+            OpCode::Print => {
+                let value = self.pop();
+                let printed = writeln!(self.output.borrow_mut(), "{}", self.heap.display(value));
+                printed.map_err(interpreter::print_error)?;
+            }
+            OpCode::Jump => {
+                let distance = frame.read_u16() as usize;
+                frame.ip += distance;
+            }
+            OpCode::JumpIfFalse => {
+                let distance = frame.read_u16() as usize;
+                if !self.peek(0).is_truthy() {
+                    frame.ip += distance;
+                }
+            }
+            OpCode::PopJumpIfFalse => {
+                let distance = frame.read_u16() as usize;
+                if !self.pop().is_truthy() {
+                    frame.ip += distance;
+                }
+            }
+            OpCode::Loop => {
+                let distance = frame.read_u16() as usize;
+                frame.ip -= distance;
+            }
+            OpCode::Call => {
+                let count = frame.read_byte() as usize;
+                let callee = self.peek(count);
+                // the caller continues here once the call returns
+                self.frames.last_mut().unwrap().ip = frame.ip;
+                self.call_value(callee, count)?;
+                *frame = self.frame();
+            }
+            OpCode::Invoke => {
+                let name = frame.read_constant();
+                let count = frame.read_byte() as usize;
+                let cache = frame.read_u16() as usize;
+                let cache = &frame.caches[cache];
+                self.frames.last_mut().unwrap().ip = frame.ip;
+                self.invoke(name, count, cache)?;
+                *frame = self.frame();
+            }
+            OpCode::SuperInvoke => {
+                let name = frame.read_constant();
+                let count = frame.read_byte() as usize;
+                let superclass = self.pop();
+                let method = self.super_method(superclass, name)?;
+                self.frames.last_mut().unwrap().ip = frame.ip;
+                self.call(method, count)?;
+                *frame = self.frame();
+            }
+            OpCode::Closure => {
+                let function = frame.read_constant();
+                let function = function.as_object().unwrap();
+                let count = match self.heap.get(function) {
+                    Object::Function(function) => function.upvalue_count,
+                    object => panic!("{:?} is not a function", object),
+                };
+                let mut upvalues = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let is_local = frame.read_byte() == 1;
+                    let index = frame.read_byte() as usize;
+                    upvalues.push(if is_local {
+                        self.capture_upvalue(frame.base + index)
+                    } else {
+                        frame.upvalues[index]
+                    });
+                }
+                let closure = self.alloc(Object::Closure(Closure {
+                    function,
+                    upvalues: upvalues.into(),
+                }));
+                self.stack.push(Value::object(closure));
+            }
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+            OpCode::Return => {
+                let result = self.pop();
+                self.close_upvalues(frame.base);
+                self.frames.pop();
+                self.stack.truncate(frame.base);
+                self.stack.push(result);
+                if self.frames.is_empty() {
+                    return Ok(true);
+                }
+                *frame = self.frame();
+            }
+            OpCode::Class => {
+                let name = frame.read_constant().as_object().unwrap();
+                // the shape is on the stack while the class is allocated
+                let shape = self.alloc(Object::Shape(Shape::default()));
+                self.stack.push(Value::object(shape));
+                let class = self.alloc(Object::Class(Class {
+                    name,
+                    methods: HashMap::new(),
+                    shape,
+                    constructor: None,
+                }));
+                let top = self.stack.len() - 1;
+                self.stack[top] = Value::object(class);
+            }
+            OpCode::Inherit => {
+                let superclass = self.peek(1).as_object().map(|obj| self.heap.get(obj));
+                let (methods, constructor) = match superclass {
+                    Some(Object::Class(superclass)) => {
+                        (superclass.methods.clone(), superclass.constructor.clone())
+                    }
+                    _ => return Err("Superclass must be a class.".into()),
+                };
+                let subclass = self.pop().as_object();
+                if let Some(Object::Class(subclass)) = subclass.map(|obj| self.heap.get_mut(obj)) {
+                    subclass.methods = methods;
+                    subclass.constructor = constructor;
+                }
+            }
+            OpCode::Method => {
+                let name = frame.read_constant().as_object().unwrap();
+                let method = self.pop().as_object();
+                let class = self.peek(0).as_object();
+                if let (Some(method), Some(class)) = (method, class) {
+                    if let Object::Class(class) = self.heap.get_mut(class) {
+                        class.methods.insert(name, method);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // frame returns a copy of the innermost call frame, which run keeps in a local for speed
+    fn frame(&self) -> CallFrame {
+        self.frames
+            .last()
+            .cloned()
+            .expect("there is a frame while the vm runs")
+    }
+
+    fn call_value(&mut self, callee: Value, count: usize) -> Result<(), Fault> {
+        let object = callee.as_object().map(|obj| self.heap.get(obj));
+        match object {
+            Some(Object::Closure(_)) => self.call(callee.as_object().unwrap(), count),
+            Some(Object::BoundMethod(bound)) => {
+                let method = bound.method;
+                let slot = self.stack.len() - count - 1;
+                self.stack[slot] = bound.receiver;
+                self.call(method, count)
+            }
+            Some(Object::Class(class)) => {
+                let initializer = class.methods.get(&self.init_string).copied();
+                let (shape, constructor) = (class.shape, class.constructor.clone());
+                let class = callee.as_object().unwrap();
+                let slot = self.stack.len() - count - 1;
+                // the class is on the stack while the instance is allocated, and the instance
+                // takes its place as this. The classes the embedder defines make their instances
+                // with the Rust constructor, which gets the arguments an initializer of a
+                // subclass gets too.
+                let instance = match constructor {
+                    Some(constructor) => {
+                        check_arity(constructor.arity, count)?;
+                        let arguments = &self.stack[slot + 1..];
+                        let object =
+                            (constructor.function)(&mut Args::new(&mut self.heap, arguments))?;
+                        self.alloc(Object::Userdata(Userdata { class, object }))
+                    }
+                    None => {
+                        if initializer.is_none() {
+                            check_arity(0, count)?;
+                        }
+                        self.alloc(Object::Instance(Instance {
+                            class,
+                            shape,
+                            fields: Vec::new(),
+                        }))
+                    }
+                };
+                self.stack[slot] = Value::object(instance);
+                match initializer {
+                    Some(initializer) => self.call(initializer, count),
+                    None => {
+                        self.stack.truncate(slot + 1);
+                        Ok(())
+                    }
+                }
+            }
+            Some(Object::Native(native)) => {
+                check_arity(native.arity, count)?;
+                let function = Rc::clone(&native.function);
+                let arguments = &self.stack[self.stack.len() - count..];
+                let result = function(&mut Args::new(&mut self.heap, arguments))?;
+                self.stack.truncate(self.stack.len() - count - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Some(Object::NativeMethod(_)) => {
+                let method = callee.as_object().unwrap();
+                let arguments = &self.stack[self.stack.len() - count..];
+                let result = native::call_method(&mut self.heap, method, arguments)?;
+                self.stack.truncate(self.stack.len() - count - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            _ => Err("Can only call functions and classes.".into()),
+        }
+    }
+
+    // call starts running closure, whose arguments are on top of the stack
+    fn call(&mut self, closure: ObjRef, count: usize) -> Result<(), Fault> {
+        let (function, upvalues) = match self.heap.get(closure) {
+            Object::Closure(closure) => (closure.function, Rc::clone(&closure.upvalues)),
+            object => panic!("{:?} is not a closure", object),
+        };
+        let function = match self.heap.get(function) {
+            Object::Function(function) => function,
+            object => panic!("{:?} is not a function", object),
+        };
+        check_arity(function.arity, count)?;
+        if self.frames.len() >= self.max_call_depth {
+            return Err(Fault::Limit(Limit::CallDepth));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            chunk: Rc::clone(&function.chunk),
+            upvalues,
+            caches: Rc::clone(&function.caches),
+            ip: 0,
+            base: self.stack.len() - count - 1,
+        });
+        Ok(())
+    }
+
+    // invoke calls the method name of the receiver below the arguments on the stack, without
+    // binding it first. A field holding a function is called like any other value.
+    fn invoke(
+        &mut self,
+        name: Value,
+        count: usize,
+        cache: &Cell<Option<InlineCache>>,
+    ) -> Result<(), Fault> {
+        let slot = self.stack.len() - count - 1;
+        if let Some(userdata) = native::userdata(&self.heap, self.stack[slot]) {
+            let text = self.heap.as_str(name).unwrap_or_default().to_string();
+            return match native::get_property(&mut self.heap, self.stack[slot], &userdata, &text) {
+                Some(callee) => {
+                    self.stack[slot] = callee;
+                    self.call_value(callee, count)
+                }
+                None => {
+                    let method = self.subclass_method(self.stack[slot], name)?;
+                    self.call(method, count)
+                }
+            };
+        }
+        let instance = self.instance(self.stack[slot], "Only instances have properties.")?;
+        let entry = match self.cached(instance.shape, cache) {
+            Some(entry) => entry,
+            None => {
+                let entry = self.lookup(name, instance)?;
+                self.remember(instance.shape, entry, cache);
+                entry
+            }
+        };
+        match entry {
+            CacheEntry::Field(index) => {
+                let callee = instance.fields[index];
+                self.stack[slot] = callee;
+                self.call_value(callee, count)
+            }
+            CacheEntry::Method(method) => self.call(method, count),
+            CacheEntry::Transition(_) => unreachable!("lookups never find transitions"),
+        }
+    }
+
+    // get_property gives the field name of receiver, or else its method name bound to it. The
+    // receiver must be rooted.
+    fn get_property(
+        &mut self,
+        receiver: Value,
+        name: Value,
+        cache: &Cell<Option<InlineCache>>,
+    ) -> Result<Value, String> {
+        if let Some(userdata) = native::userdata(&self.heap, receiver) {
+            let text = self.heap.as_str(name).unwrap_or_default().to_string();
+            if let Some(value) = native::get_property(&mut self.heap, receiver, &userdata, &text) {
+                return Ok(value);
+            }
+            let method = self.subclass_method(receiver, name)?;
+            return Ok(self.bind(receiver, method));
+        }
+        let instance = self.instance(receiver, "Only instances have properties.")?;
+        let entry = match self.cached(instance.shape, cache) {
+            Some(entry) => entry,
+            None => {
+                let entry = self.lookup(name, instance)?;
+                self.remember(instance.shape, entry, cache);
+                entry
+            }
+        };
+        match entry {
+            CacheEntry::Field(index) => Ok(instance.fields[index]),
+            CacheEntry::Method(method) => Ok(self.bind(receiver, method)),
+            CacheEntry::Transition(_) => unreachable!("lookups never find transitions"),
+        }
+    }
+
+    // set_property sets the field name of receiver to value. An instance that does not have the
+    // field yet moves on to the shape with it, which is created the first time an instance of that
+    // shape gets the field. The receiver and value must be rooted.
+    fn set_property(
+        &mut self,
+        receiver: Value,
+        name: Value,
+        value: Value,
+        cache: &Cell<Option<InlineCache>>,
+    ) -> Result<(), String> {
+        if let Some(userdata) = native::userdata(&self.heap, receiver) {
+            let name = self.heap.as_str(name).unwrap_or_default().to_string();
+            return native::set_property(&mut self.heap, &userdata, &name, value);
+        }
+        let shape = self
+            .instance(receiver, "Only instances have fields.")?
+            .shape;
+        let entry = match self.cached(shape, cache) {
+            Some(entry) => entry,
+            None => {
+                let entry = self.transition(name, shape);
+                self.remember(shape, entry, cache);
+                entry
+            }
+        };
+        let instance = match self.heap.get_mut(receiver.as_object().unwrap()) {
+            Object::Instance(instance) => instance,
+            object => panic!("{:?} is not an instance", object),
+        };
+        match entry {
+            CacheEntry::Field(index) => instance.fields[index] = value,
+            CacheEntry::Transition(shape) => {
+                instance.fields.push(value);
+                instance.shape = shape;
+            }
+            CacheEntry::Method(_) => unreachable!("setting a field never finds a method"),
+        }
+        Ok(())
+    }
+
+    // instance gives the instance value is, or the error message if it is not one
+    fn instance(&self, value: Value, message: &str) -> Result<&Instance, String> {
+        match value.as_object().map(|obj| self.heap.get(obj)) {
+            Some(Object::Instance(instance)) => Ok(instance),
+            _ => Err(message.into()),
+        }
+    }
+
+    // cached gives what cache remembers for instances of shape
+    fn cached(&self, shape: ObjRef, cache: &Cell<Option<InlineCache>>) -> Option<CacheEntry> {
+        match cache.get() {
+            Some(cached) if self.inline_caching && cached.shape == shape => Some(cached.entry),
+            _ => None,
+        }
+    }
+
+    fn remember(&self, shape: ObjRef, entry: CacheEntry, cache: &Cell<Option<InlineCache>>) {
+        if self.inline_caching {
+            cache.set(Some(InlineCache { shape, entry }));
+        }
+    }
+
+    // lookup finds the property name of instance: the slot of a field, or else a method
+    fn lookup(&self, name: Value, instance: &Instance) -> Result<CacheEntry, String> {
+        let name_ref = name.as_object().unwrap();
+        if let Some(index) = self.shape(instance.shape).fields.get(&name_ref) {
+            return Ok(CacheEntry::Field(*index));
+        }
+        let method = match self.heap.get(instance.class) {
+            Object::Class(class) => class.methods.get(&name_ref),
+            object => panic!("{:?} is not a class", object),
+        };
+        match method {
+            Some(method) => Ok(CacheEntry::Method(*method)),
+            None => Err(self.undefined_property(name)),
+        }
+    }
+
+    // transition finds what setting the field name does to instances of shape
+    fn transition(&mut self, name: Value, shape: ObjRef) -> CacheEntry {
+        let name = name.as_object().unwrap();
+        let current = self.shape(shape);
+        if let Some(index) = current.fields.get(&name) {
+            return CacheEntry::Field(*index);
+        }
+        if let Some(next) = current.transitions.get(&name) {
+            return CacheEntry::Transition(*next);
+        }
+        let mut fields = current.fields.clone();
+        fields.insert(name, fields.len());
+        // the shape is reachable from the instance being set and the name is a constant of the
+        // running function, so neither is collected here
+        let next = self.alloc(Object::Shape(Shape {
+            fields,
+            transitions: HashMap::new(),
+        }));
+        if let Object::Shape(current) = self.heap.get_mut(shape) {
+            current.transitions.insert(name, next);
+        }
+        CacheEntry::Transition(next)
+    }
+
+    fn shape(&self, shape: ObjRef) -> &Shape {
+        match self.heap.get(shape) {
+            Object::Shape(shape) => shape,
+            object => panic!("{:?} is not a shape", object),
+        }
+    }
+
+    // subclass_method finds the method name that the Lox subclass making the instance of a native
+    // class receiver declares, for properties the Rust object does not have
+    fn subclass_method(&self, receiver: Value, name: Value) -> Result<ObjRef, String> {
+        let class = match receiver.as_object().map(|obj| self.heap.get(obj)) {
+            Some(Object::Userdata(userdata)) => userdata.class,
+            object => panic!("{:?} is not userdata", object),
+        };
+        match self.heap.get(class) {
+            Object::Class(class) => class.methods.get(&name.as_object().unwrap()).copied(),
+            object => panic!("{:?} is not a class", object),
+        }
+        .ok_or_else(|| self.undefined_property(name))
+    }
+
+    // super_method finds the method name of superclass
+    fn super_method(&self, superclass: Value, name: Value) -> Result<ObjRef, String> {
+        let method = match superclass.as_object().map(|obj| self.heap.get(obj)) {
+            Some(Object::Class(class)) => class.methods.get(&name.as_object().unwrap()),
+            _ => None,
+        };
+        method.copied().ok_or_else(|| self.undefined_property(name))
+    }
+
+    // bind makes a method whose this is receiver. Both must be rooted.
+    fn bind(&mut self, receiver: Value, method: ObjRef) -> Value {
+        Value::object(self.alloc(Object::BoundMethod(BoundMethod { receiver, method })))
+    }
+
+    fn undefined_property(&self, name: Value) -> String {
+        let name = self.heap.as_str(name).unwrap_or_default();
+        format!("Undefined property '{}'.", name)
+    }
+
+    // capture_upvalue returns the open upvalue for a stack slot, creating it if no closure has
+    // captured the slot yet
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = 0;
+        for (index, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match self.upvalue(*upvalue) {
+                Upvalue::Open(open) if *open == slot => return *upvalue,
+                Upvalue::Open(open) if *open < slot => {
+                    insert_at = index + 1;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let upvalue = self.alloc(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    // close_upvalues moves the variables in the stack slots from first up into their upvalues,
+    // as they are about to be popped
+    fn close_upvalues(&mut self, first: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match self.upvalue(*upvalue) {
+                Upvalue::Open(slot) if *slot >= first => *slot,
+                _ => break,
+            };
+            let value = self.stack[slot];
+            *self.heap.get_mut(*upvalue) = Object::Upvalue(Upvalue::Closed(value));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn upvalue(&self, upvalue: ObjRef) -> &Upvalue {
+        match self.heap.get(upvalue) {
+            Object::Upvalue(upvalue) => upvalue,
+            object => panic!("{:?} is not an upvalue", object),
+        }
+    }
+
+    fn global(&self, name: Value) -> Option<Value> {
+        self.globals.get(&name.as_object()?).copied()
+    }
+
+    fn undefined(&self, name: Value) -> String {
+        let name = self.heap.as_str(name).unwrap_or_default();
+        format!("Undefined variable '{}'.", name)
+    }
+
+    // the compiler only emits code that balances the stack, so popping never fails on valid chunks
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap_or_default()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        let index = self.stack.len().checked_sub(distance + 1);
+        index.map_or(Value::NIL, |index| self.stack[index])
+    }
+
+    // alloc puts an object on the heap, collecting garbage first if it is time to. Everything the
+    // new object refers to must be rooted.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        self.collect_if_due();
+        self.heap.alloc(object)
+    }
+
+    // alloc_string puts a string on the heap, collecting garbage first if it is time to
+    fn alloc_string(&mut self, val: &str) -> Value {
+        self.collect_if_due();
+        self.heap.alloc_string(val)
+    }
+
+    // collect_if_due collects garbage if it is time to. The roots are the stack, the closures
+    // being called, the open upvalues and the globals; everything else the program can reach is
+    // reachable from them.
+    fn collect_if_due(&mut self) {
+        if !self.heap.should_collect() {
+            return;
+        }
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        self.heap.mark_object(self.init_string);
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.collect();
+    }
+
+    // add adds two numbers or concatenates two strings. The operands must be rooted, as the result
+    // may be allocated.
+    fn add(&mut self, left: Value, right: Value) -> Result<Value, String> {
+        match (left.as_number(), right.as_number()) {
+            (Some(left), Some(right)) => Ok(Value::from(left + right)),
+            _ => match (self.heap.as_str(left), self.heap.as_str(right)) {
+                (Some(left), Some(right)) => {
+                    let val = format!("{}{}", left, right);
+                    Ok(self.alloc_string(&val))
+                }
+                _ => Err("Operands must be two numbers or two strings.".into()),
+            },
+        }
+    }
+
+    fn numbers(&mut self) -> Result<(f64, f64), &'static str> {
+        let right = self.pop();
+        let left = self.pop();
+        match (left.as_number(), right.as_number()) {
+            (Some(left), Some(right)) => Ok((left, right)),
+            _ => Err("Operands must be numbers."),
+        }
+    }
+
+    fn arithmetic(&mut self, op: fn(f64, f64) -> f64) -> Result<(), &'static str> {
+        let (left, right) = self.numbers()?;
+        self.stack.push(Value::from(op(left, right)));
+        Ok(())
+    }
+
+    fn comparison(&mut self, op: fn(f64, f64) -> bool) -> Result<(), &'static str> {
+        let (left, right) = self.numbers()?;
+        self.stack.push(Value::from(op(left, right)));
+        Ok(())
+    }
+}
+
+// Fault is why an instruction failed: an error of the program, or a limit it ran into. Calls that
+// nest too deeply are the one limit an instruction runs into by itself.
+#[derive(Debug)]
+enum Fault {
+    Message(String),
+    Limit(Limit),
+}
+
+impl Fault {
+    fn at_line(self, line: usize) -> RuntimeError {
+        match self {
+            Fault::Message(message) => RuntimeError::at_line(line, &message),
+            Fault::Limit(limit) => RuntimeError::exceeded(line, limit),
+        }
+    }
+}
+
+impl From<String> for Fault {
+    fn from(message: String) -> Self {
+        Fault::Message(message)
+    }
+}
+
+impl From<&str> for Fault {
+    fn from(message: &str) -> Self {
+        Fault::Message(message.into())
+    }
+}
+
+fn check_arity(arity: usize, count: usize) -> Result<(), String> {
+    if arity == count {
+        return Ok(());
+    }
+    Err(format!("Expected {} arguments but got {}.", arity, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn run(vm: &mut Vm, source: &str) -> Result<Value, RuntimeError> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens();
+        let statements = Parser::new(scanner.into_parts().0).parse().unwrap();
+        let script = Compiler::compile(&statements, &mut vm.heap, true).unwrap();
+        vm.interpret(script)
+    }
+
+    // global returns the value of a global the way print would show it
+    fn global(vm: &mut Vm, name: &str) -> String {
+        let name = vm.heap.alloc_string(name).as_object().unwrap();
+        vm.heap.display(vm.globals[&name]).to_string()
+    }
+
+    // evaluate runs a single expression under gc stress and returns its value the way print
+    // would show it
+    fn evaluate(source: &str) -> Result<String, RuntimeError> {
+        let mut vm = Vm::new();
+        vm.set_gc_stress(true);
+        run(&mut vm, &format!("var result = {};", source))?;
+        Ok(global(&mut vm, "result"))
+    }
+
+    #[test]
+    fn vm_evaluates_expressions() {
+        let cases = [
+            ("1 + 2 * 3", "7"),
+            ("(1 + 2) * 3", "9"),
+            ("-0x10 / 4", "-4"),
+            ("\"a\" + \"b\" + \"c\"", "abc"),
+            ("\"a\" == \"a\"", "true"),
+            ("!nil", "true"),
+            ("1 < 2 == true", "true"),
+            ("2 <= 2", "true"),
+            ("(0 / 0) >= 1", "false"),
+            ("\"1\" != 1", "true"),
+            ("nil or \"b\"", "b"),
+            ("1 and false", "false"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(evaluate(source), Ok(expected.to_string()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn vm_collects_garbage() {
+        let mut vm = Vm::new();
+        vm.set_gc_stress(true);
+        let source = "var a = \"a\" + \"b\";\na = \"c\" + \"d\";\na = \"e\" + \"f\";";
+        run(&mut vm, source).unwrap();
+        let stats = vm.gc_stats();
+        // the closure of the script and every concatenation collected
+        assert_eq!(stats.collections, 4);
+        // the first concatenation was garbage by the time of the third one
+        assert_eq!(stats.objects_freed, 1);
+        assert_eq!(global(&mut vm, "a"), "ef");
+    }
+
+    #[test]
+    fn vm_reports_errors_with_lines() {
+        let error = evaluate("1 +\n\"a\"").unwrap_err();
+        assert_eq!(
+            error.message,
+            "Operands must be two numbers or two strings."
+        );
+        assert_eq!(error.line, 1);
+
+        let mut vm = Vm::new();
+        let error = run(&mut vm, "1;\n-\"a\";").unwrap_err();
+        assert_eq!(error.message, "Operand must be a number.");
+        assert_eq!(error.line, 2);
+
+        let error = run(&mut vm, "fun f(a) {}\nf();").unwrap_err();
+        assert_eq!(error.message, "Expected 1 arguments but got 0.");
+        assert_eq!(error.line, 2);
+        let error = run(&mut vm, "print undefined;").unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'undefined'.");
+    }
+
+    #[test]
+    fn vm_calls_functions() {
+        let mut vm = Vm::new();
+        let source = "
+            fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            var result = fib(15);
+            var i = 0;
+            while (i < 10) i = i + 1;
+        ";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&mut vm, "result"), "610");
+        assert_eq!(global(&mut vm, "i"), "10");
+
+        let error = run(&mut vm, "fun f() { f(); }\nf();").unwrap_err();
+        assert_eq!(error.message, "Stack overflow.");
+        // the vm recovers from errors
+        run(&mut vm, "var ok = clock() > 0;").unwrap();
+        assert_eq!(global(&mut vm, "ok"), "true");
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let mut vm = Vm::new();
+        vm.set_gc_stress(true);
+        let source = "
+            var get; var set;
+            fun make() {
+                var x = 1;
+                fun g() { return x; }
+                fun s(value) { x = value; }
+                get = g; set = s;
+            }
+            make();
+            set(2);
+            var shared = get();
+
+            var first;
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = i;
+                fun f() { return j; }
+                if (first == nil) first = f;
+            }
+            var captured = first();
+        ";
+        run(&mut vm, source).unwrap();
+        assert_eq!(global(&mut vm, "shared"), "2");
+        assert_eq!(global(&mut vm, "captured"), "0");
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn vm_caches_properties_by_shape() {
+        let source = "
+            class P { init(x) { this.x = x; } get() { return this.x; } }
+            fun sum(p) { return p.get() + p.x; }
+            var a = P(1);
+            var b = P(2);
+            var result = sum(a) + sum(b);
+            b.y = 3;
+        ";
+        for inline_caching in [true, false] {
+            let mut vm = Vm::new();
+            vm.set_gc_stress(true);
+            vm.set_inline_caching(inline_caching);
+            run(&mut vm, source).unwrap();
+            assert_eq!(global(&mut vm, "result"), "6");
+
+            let value = |vm: &mut Vm, name: &str| {
+                let name = vm.heap.alloc_string(name).as_object().unwrap();
+                vm.globals[&name].as_object().unwrap()
+            };
+            let shape = |vm: &mut Vm, name: &str| {
+                let instance = value(vm, name);
+                match vm.heap.get(instance) {
+                    Object::Instance(instance) => instance.shape,
+                    object => panic!("{:?} is not an instance", object),
+                }
+            };
+            // b got another field than a, which moved it on to the next shape
+            let a = shape(&mut vm, "a");
+            let b = shape(&mut vm, "b");
+            match vm.heap.get(a) {
+                Object::Shape(shape) => {
+                    assert_eq!(shape.transitions.values().collect::<Vec<_>>(), [&b])
+                }
+                object => panic!("{:?} is not a shape", object),
+            }
+
+            let sum = value(&mut vm, "sum");
+            let sum = match vm.heap.get(sum) {
+                Object::Closure(closure) => closure.function,
+                object => panic!("{:?} is not a closure", object),
+            };
+            let caches = match vm.heap.get(sum) {
+                Object::Function(function) => Rc::clone(&function.caches),
+                object => panic!("{:?} is not a function", object),
+            };
+            let entries: Vec<_> = caches.iter().map(|cache| cache.get()).collect();
+            if inline_caching {
+                assert!(matches!(
+                    entries[..],
+                    [
+                        Some(InlineCache {
+                            entry: CacheEntry::Method(_),
+                            ..
+                        }),
+                        Some(InlineCache {
+                            entry: CacheEntry::Field(0),
+                            ..
+                        })
+                    ]
+                ));
+            } else {
+                assert_eq!(entries, [None, None]);
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Runs every Lox program in tests/lox through the interpreter binary, once for every backend, and
+// compares what it prints and its exit code against the expectations in the program's comments. The annotations are the
+// ones used by the Crafting Interpreters test suite, but this interpreter words its compile errors differently from
+// the book, so tests brought over from the suite need their error expectations rewritten:
+//
+//   print 1 + 2; // expect: 3
+//   -"a"; // expect runtime error: Operand must be a number.
+//   print #; // Error: unexpected character '#'
+//   // [line 4] Error: unexpected token ';'
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// the command line flags that select each backend. Both also run with the garbage collector
+// collecting on every allocation, to catch values it frees while they are still in use, and the
+// vm also runs without its optimizations.
+const BACKENDS: [(&str, &[&str]); 5] = [
+    ("tree-walker", &[]),
+    ("vm", &["--vm"]),
+    ("vm, unoptimized", &["--vm", "--no-optimize"]),
+    ("tree-walker, gc stress", &["--gc-stress"]),
+    ("vm, gc stress", &["--vm", "--gc-stress"]),
+];
+
+const EXPECT_OUTPUT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+// the exit codes of the interpreter, taken from sysexits.h like the book does
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<Vec<String>>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Expectations {
+        let mut expectations = Expectations::default();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            if let Some(start) = line.find(EXPECT_OUTPUT) {
+                let output = &line[start + EXPECT_OUTPUT.len()..];
+                expectations.output.push(output.to_string());
+            } else if let Some(start) = line.find(EXPECT_RUNTIME_ERROR) {
+                let message = &line[start + EXPECT_RUNTIME_ERROR.len()..];
+                expectations.runtime_error =
+                    Some(vec![message.to_string(), format!("[line {}]", line_number)]);
+            } else if let Some(start) = line.find("// [line ") {
+                expectations
+                    .compile_errors
+                    .push(line[start + 3..].to_string());
+            } else if let Some(start) = line.find("// Error") {
+                let error = format!("[line {}] {}", line_number, &line[start + 3..]);
+                expectations.compile_errors.push(error);
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            EXIT_COMPILE_ERROR
+        } else if self.runtime_error.is_some() {
+            EXIT_RUNTIME_ERROR
+        } else {
+            0
+        }
+    }
+}
+
+// compile errors are followed by a snippet of the offending source, like
+//   |
+// 1 | print #;
+//   |       ^
+// which is not part of what the tests compare
+fn is_snippet(line: &str) -> bool {
+    match line.split_once('|') {
+        Some((gutter, _)) => {
+            gutter.ends_with(' ') && gutter.trim().chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+fn lines(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|line| !is_snippet(line))
+        .map(str::to_string)
+        .collect()
+}
+
+// diff describes how the actual lines differ from the expected ones, if they do
+fn diff(what: &str, expected: &[String], actual: &[String]) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let mut report = format!("{} differs:\n", what);
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(expected), Some(actual)) if expected == actual => {
+                report.push_str(&format!("    {}\n", expected))
+            }
+            (expected, actual) => {
+                if let Some(expected) = expected {
+                    report.push_str(&format!("  - {}\n", expected));
+                }
+                if let Some(actual) = actual {
+                    report.push_str(&format!("  + {}\n", actual));
+                }
+            }
+        }
+    }
+    Some(report)
+}
+
+fn check(path: &Path, flags: &[&str]) -> Vec<String> {
+    let source = std::fs::read_to_string(path).unwrap();
+    let expectations = Expectations::parse(&source);
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .args(flags)
+        .arg(path)
+        .output()
+        .unwrap();
+
+    let mut failures = Vec::new();
+    failures.extend(diff("stdout", &expectations.output, &lines(&output.stdout)));
+
+    let mut errors = lines(&output.stderr);
+    let expected_errors = match expectations.runtime_error.clone() {
+        Some(runtime_error) => {
+            // like the book's test runner, only the innermost call of the stack trace that follows
+            // the message is checked, and only for its line
+            errors.truncate(2);
+            if let Some(line) = errors.get_mut(1) {
+                if let Some(end) = line.find(']') {
+                    line.truncate(end + 1);
+                }
+            }
+            runtime_error
+        }
+        None => {
+            // the scanner and parser report their errors separately, so only the set of
+            // errors matters
+            let mut compile_errors = expectations.compile_errors.clone();
+            compile_errors.sort();
+            errors.sort();
+            compile_errors
+        }
+    };
+    failures.extend(diff("stderr", &expected_errors, &errors));
+
+    let exit_code = output.status.code();
+    if exit_code != Some(expectations.exit_code()) {
+        failures.push(format!(
+            "expected exit code {} but got {:?}\n",
+            expectations.exit_code(),
+            exit_code
+        ));
+    }
+    failures
+}
+
+fn lox_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(lox_files(&path));
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn conformance() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let files = lox_files(&root);
+    assert!(!files.is_empty(), "no tests found in {}", root.display());
+
+    let mut failed = 0;
+    for path in &files {
+        for (backend, flags) in BACKENDS {
+            let failures = check(path, flags);
+            if !failures.is_empty() {
+                failed += 1;
+                let name = path.strip_prefix(&root).unwrap_or(path);
+                eprintln!(
+                    "FAIL {} ({})\n{}",
+                    name.display(),
+                    backend,
+                    failures.concat()
+                );
+            }
+        }
+    }
+    let runs = files.len() * BACKENDS.len();
+    assert_eq!(failed, 0, "{} of {} Lox test runs failed", failed, runs);
+}
@@ -0,0 +1,55 @@
+// Uses the library the way a crate embedding Lox would, through its public API only.
+
+use rust_lox::ast::{Expression, Statement};
+use rust_lox::{Backend, Error, Lox, Parser, Scanner, TokenType, Value};
+
+#[test]
+fn run_gives_values_and_errors() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut lox = Lox::with_backend(backend);
+        assert_eq!(lox.run("var a = 1;"), Ok(Value::NIL));
+        assert_eq!(lox.run("a + 2;"), Ok(Value::from(3.0)));
+
+        match lox.run("print;") {
+            Err(Error::Compile(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].line, 1);
+            }
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+        match lox.run("\nb;") {
+            Err(Error::Runtime(error)) => {
+                assert_eq!(error.message(), "Undefined variable 'b'.");
+                assert_eq!(error.line(), 2);
+            }
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+}
+
+#[test]
+fn scanner_and_parser_give_tokens_and_syntax_trees() {
+    let mut scanner = Scanner::new("print a * 2;");
+    scanner.scan_tokens();
+    let kinds: Vec<_> = scanner.tokens().iter().map(|token| token.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            TokenType::Print,
+            TokenType::Identifier,
+            TokenType::Star,
+            TokenType::Number,
+            TokenType::Semicolon,
+            TokenType::Eof
+        ]
+    );
+    assert_eq!(scanner.tokens()[1].lexeme(), "a");
+
+    let statements = Parser::new(scanner.tokens().to_vec()).parse().unwrap();
+    match &statements[..] {
+        [Statement::Print {
+            expr: Expression::Binary { operator, .. },
+        }] => assert_eq!(operator.kind(), TokenType::Star),
+        statements => panic!("unexpected syntax tree {:?}", statements),
+    }
+}